@@ -0,0 +1,476 @@
+//Hayden Coffey
+//AArch64 Linux backend. Mirrors x86_64_gen: ducks live in a zeroed array on the stack,
+//with the teacher in the slot after the last duck.
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use crate::backend::{self, Backend, LoopLabels};
+use crate::program::Program;
+
+//Callee saved, so they survive the syscalls
+static ARRAY_BASE_REG: &str = "x19";
+static DUCK_COUNT_REG: &str = "x20";
+static GOOSE_INDEX_REG: &str = "x21";
+
+//Linux syscall numbers
+const SYS_READ: usize = 63;
+const SYS_WRITE: usize = 64;
+const SYS_EXIT: usize = 93;
+const SYS_GETPID: usize = 172;
+const SYS_KILL: usize = 129;
+const SIGFPE: usize = 8;
+
+//Routine raising SIGFPE, AArch64 division by zero doesn't trap like x86-64 does
+static DIVIDE_BY_ZERO_LABEL: &str = "divide_by_zero";
+
+//Load a constant 16 bits at a time, mov only takes 16 bit immediates
+fn load_immediate<W: Write>(register: &str, value: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "  movz {}, #{}", register, value & 0xffff)?;
+    for shift in [16, 32, 48] {
+        let part = (value as u64 >> shift) & 0xffff;
+        if part != 0 {
+            writeln!(file, "  movk {}, #{}, lsl #{}", register, part, shift)?;
+        }
+    }
+
+    Ok(())
+}
+
+//Translate given duck index to array index and store in given register
+//Uses x9, x10, x11
+fn get_duck_index<W: Write>(register: &str, duck: usize, file: &mut W) -> std::io::Result<()> {
+    //Add duck to goose number
+    load_immediate("x9", duck, file)?;
+    writeln!(file, "  add x9, {}, x9", GOOSE_INDEX_REG)?;
+
+    //Mod by duck count + 1
+    writeln!(file, "  add x10, {}, #1", DUCK_COUNT_REG)?;
+    writeln!(file, "  udiv x11, x9, x10")?;
+    writeln!(file, "  msub {}, x11, x10, x9", register)?;
+
+    Ok(())
+}
+
+//Store teacher index in given register
+fn get_teacher_index<W: Write>(register: &str, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "  add {}, {}, #1", register, DUCK_COUNT_REG)
+}
+
+//Load the value of the slot whose index is held in index
+fn load_slot<W: Write>(register: &str, index: &str, file: &mut W) -> std::io::Result<()> {
+    writeln!(
+        file,
+        "  ldr {}, [{}, {}, lsl #3]",
+        register, ARRAY_BASE_REG, index
+    )
+}
+
+fn store_slot<W: Write>(register: &str, index: &str, file: &mut W) -> std::io::Result<()> {
+    writeln!(
+        file,
+        "  str {}, [{}, {}, lsl #3]",
+        register, ARRAY_BASE_REG, index
+    )
+}
+
+fn write_syscall<W: Write>(number: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "  mov x8, #{}", number)?;
+    writeln!(file, "  svc #0")
+}
+
+//Assembly header lines, allocate stack array and initialize registers
+fn write_header<W: Write>(duck_count: usize, file: &mut W) -> std::io::Result<()> {
+    //Mark the stack non-executable so the linker doesn't warn
+    writeln!(file, ".section .note.GNU-stack,\"\",%progbits")?;
+    write!(file, ".section .text\n.global main\nmain:\n")?;
+
+    //Add slots for goose and teacher and goose pointer, sp has to stay 16 byte aligned
+    let slots = duck_count + 3;
+    writeln!(file, "#Allocate ducks on stack")?;
+    load_immediate("x9", (slots * 8 + 15) & !15, file)?;
+    writeln!(file, "  sub sp, sp, x9")?;
+    writeln!(file, "  mov {}, sp", ARRAY_BASE_REG)?;
+
+    //Unlike push $0 the stack space isn't cleared for us
+    load_immediate("x10", slots, file)?;
+    writeln!(file, "  mov x9, #0")?;
+    writeln!(file, "1:")?;
+    writeln!(file, "  cmp x9, x10")?;
+    writeln!(file, "  b.hs 2f")?;
+    store_slot("xzr", "x9", file)?;
+    writeln!(file, "  add x9, x9, #1")?;
+    writeln!(file, "  b 1b")?;
+    writeln!(file, "2:")?;
+
+    load_immediate(DUCK_COUNT_REG, duck_count, file)?;
+    writeln!(file, "  mov {}, #0", GOOSE_INDEX_REG)?;
+
+    Ok(())
+}
+
+//Perform exit syscall
+fn write_exit<W: Write>(file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Exit==========")?;
+    writeln!(file, "  mov x0, #0")?;
+    write_syscall(SYS_EXIT, file)
+}
+
+//Kill ourselves with SIGFPE so a division by zero ends the same way on every target
+fn write_divide_by_zero<W: Write>(file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "{}:", DIVIDE_BY_ZERO_LABEL)?;
+    write_syscall(SYS_GETPID, file)?;
+    writeln!(file, "  mov x1, #{}", SIGFPE)?;
+    write_syscall(SYS_KILL, file)?;
+    //Not reached unless SIGFPE is ignored
+    writeln!(file, "  mov x0, #{}", 128 + SIGFPE)?;
+    write_syscall(SYS_EXIT, file)
+}
+
+//cbz only reaches 1MB, branch around an unconditional b instead
+fn write_branch_if_zero<W: Write>(
+    register: &str,
+    label: &str,
+    file: &mut W,
+) -> std::io::Result<()> {
+    writeln!(file, "  cbnz {}, 1f", register)?;
+    writeln!(file, "  b {}", label)?;
+    writeln!(file, "1:")
+}
+
+//Get n and y index into x14 and x15, and load their values into x12 and x13
+fn load_operands<W: Write>(name: &str, n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#{}==========", name)?;
+    writeln!(file, "#n: {}", n)?;
+    writeln!(file, "#y: {}", y)?;
+
+    get_duck_index("x14", n, file)?;
+    get_duck_index("x15", y, file)?;
+
+    load_slot("x12", "x14", file)?;
+    load_slot("x13", "x15", file)
+}
+
+//Move result -> Goose, then update goose index to n (held in x14)
+fn store_result<W: Write>(result: &str, file: &mut W) -> std::io::Result<()> {
+    store_slot(result, GOOSE_INDEX_REG, file)?;
+    writeln!(file, "  mov {}, x14", GOOSE_INDEX_REG)
+}
+
+//Lower given duck instruction to AArch64
+fn write_add<W: Write>(n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    load_operands("Add", n, y, file)?;
+    writeln!(file, "  add x12, x12, x13")?;
+    store_result("x12", file)
+}
+
+fn write_subtract<W: Write>(n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    load_operands("Subtract", n, y, file)?;
+    //n - y
+    writeln!(file, "  sub x12, x12, x13")?;
+    store_result("x12", file)
+}
+
+fn write_multiply<W: Write>(n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    load_operands("Multiply", n, y, file)?;
+    writeln!(file, "  mul x12, x12, x13")?;
+    store_result("x12", file)
+}
+
+fn write_divide<W: Write>(n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    load_operands("Divide", n, y, file)?;
+    //n/y, udiv would quietly give 0 for y == 0
+    write_branch_if_zero("x13", DIVIDE_BY_ZERO_LABEL, file)?;
+    writeln!(file, "  udiv x12, x12, x13")?;
+    store_result("x12", file)
+}
+
+//Reads a char from stdin
+fn write_input<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Input==========")?;
+    //Zeroed buffer on the stack, end of input reads as 0
+    writeln!(file, "  sub sp, sp, #16")?;
+    writeln!(file, "  str xzr, [sp]")?;
+
+    //read(stdin, sp, 1)
+    writeln!(file, "  mov x0, #0")?;
+    writeln!(file, "  mov x1, sp")?;
+    writeln!(file, "  mov x2, #1")?;
+    write_syscall(SYS_READ, file)?;
+
+    writeln!(file, "  ldr x12, [sp]")?;
+    writeln!(file, "  add sp, sp, #16")?;
+
+    get_duck_index("x14", n, file)?;
+    store_result("x12", file)
+}
+
+//Push value to teacher
+fn write_push<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Push==========")?;
+    get_teacher_index("x15", file)?;
+    get_duck_index("x14", n, file)?;
+    load_slot("x12", "x14", file)?;
+
+    //Move N -> Teacher, then N -> Goose and update goose index
+    store_slot("x12", "x15", file)?;
+    store_result("x12", file)
+}
+
+//Pop value from teacher to goose
+fn write_pop<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Pop==========")?;
+    //Load teacher
+    get_teacher_index("x15", file)?;
+    load_slot("x12", "x15", file)?;
+
+    //Set teacher to 0
+    store_slot("xzr", "x15", file)?;
+
+    //Move teacher -> Goose and update goose index
+    get_duck_index("x14", n, file)?;
+    store_result("x12", file)
+}
+
+//The source loop id is only kept as a comment
+fn write_loop_begin<W: Write>(
+    cond: usize,
+    id: usize,
+    labels: &LoopLabels,
+    file: &mut W,
+) -> std::io::Result<()> {
+    writeln!(file, "#LoopBegin==========")?;
+    writeln!(file, "#id: {}", id)?;
+    writeln!(file, "{}:", labels.start)?;
+
+    get_duck_index("x14", cond, file)?;
+    load_slot("x12", "x14", file)?;
+    write_branch_if_zero("x12", &labels.end, file)
+}
+
+fn write_loop_end<W: Write>(id: usize, labels: &LoopLabels, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#LoopEnd==========")?;
+    writeln!(file, "#id: {}", id)?;
+    writeln!(file, "  b {}", labels.start)?;
+    writeln!(file, "{}:", labels.end)?;
+
+    Ok(())
+}
+
+fn write_set<W: Write>(n: usize, value: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Set==========")?;
+    writeln!(file, "#n: {}", n)?;
+    writeln!(file, "#value: {}", value)?;
+
+    get_duck_index("x14", n, file)?;
+    load_immediate("x12", value, file)?;
+    store_result("x12", file)
+}
+
+//Goose to duck n, nothing stored
+fn write_move<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Move==========")?;
+    writeln!(file, "#n: {}", n)?;
+
+    get_duck_index("x14", n, file)?;
+    writeln!(file, "  mov {}, x14", GOOSE_INDEX_REG)
+}
+
+//write(stdout, sp, 1) with the low byte of register on the stack
+fn write_char<W: Write>(register: &str, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "  sub sp, sp, #16")?;
+    writeln!(file, "  str {}, [sp]", register)?;
+    writeln!(file, "  mov x0, #1")?;
+    writeln!(file, "  mov x1, sp")?;
+    writeln!(file, "  mov x2, #1")?;
+    write_syscall(SYS_WRITE, file)?;
+    writeln!(file, "  add sp, sp, #16")
+}
+
+fn write_print<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Print==========")?;
+
+    get_duck_index("x14", n, file)?;
+    load_slot("x12", "x14", file)?;
+    write_char("x12", file)
+}
+
+fn write_print_value<W: Write>(value: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#PrintValue==========")?;
+
+    load_immediate("x12", value, file)?;
+    write_char("x12", file)
+}
+
+pub struct Aarch64<W: Write> {
+    file: W,
+}
+
+impl<W: Write> Aarch64<W> {
+    pub fn new(file: W) -> Aarch64<W> {
+        Aarch64 { file }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.file
+    }
+}
+
+impl<W: Write> Backend for Aarch64<W> {
+    fn prologue(&mut self, program: &Program) -> std::io::Result<()> {
+        write_header(program.duck_count(), &mut self.file)
+    }
+
+    fn epilogue(&mut self) -> std::io::Result<()> {
+        write_exit(&mut self.file)?;
+        write_divide_by_zero(&mut self.file)
+    }
+
+    fn end(&mut self) -> std::io::Result<()> {
+        write_exit(&mut self.file)
+    }
+
+    fn print(&mut self, n: usize) -> std::io::Result<()> {
+        write_print(n, &mut self.file)
+    }
+
+    fn add(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_add(n, y, &mut self.file)
+    }
+
+    fn subtract(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_subtract(n, y, &mut self.file)
+    }
+
+    fn multiply(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_multiply(n, y, &mut self.file)
+    }
+
+    fn divide(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_divide(n, y, &mut self.file)
+    }
+
+    fn input(&mut self, n: usize) -> std::io::Result<()> {
+        write_input(n, &mut self.file)
+    }
+
+    fn push(&mut self, n: usize) -> std::io::Result<()> {
+        write_push(n, &mut self.file)
+    }
+
+    fn pop(&mut self, n: usize) -> std::io::Result<()> {
+        write_pop(n, &mut self.file)
+    }
+
+    fn loop_begin(&mut self, cond: usize, id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        write_loop_begin(cond, id, labels, &mut self.file)
+    }
+
+    fn loop_end(&mut self, id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        write_loop_end(id, labels, &mut self.file)
+    }
+
+    fn set(&mut self, n: usize, value: usize) -> std::io::Result<()> {
+        write_set(n, value, &mut self.file)
+    }
+
+    fn move_goose(&mut self, n: usize) -> std::io::Result<()> {
+        write_move(n, &mut self.file)
+    }
+
+    fn print_value(&mut self, value: usize) -> std::io::Result<()> {
+        write_print_value(value, &mut self.file)
+    }
+}
+
+//Write program as AArch64 assembly (GNU syntax) for Linux to out
+pub fn lower_to_writer(program: &Program, out: &mut impl Write) -> std::io::Result<()> {
+    backend::lower(program, &mut Aarch64::new(out))
+}
+
+//Program as AArch64 assembly (GNU syntax) for Linux in a string
+pub fn lower_to_string(program: &Program) -> std::io::Result<String> {
+    let mut out = Vec::new();
+    lower_to_writer(program, &mut out)?;
+
+    backend::into_text(out)
+}
+
+//Write program as AArch64 assembly (GNU syntax) for Linux
+pub fn lower_program(program: &Program, file_out_name: &str) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(file_out_name)?);
+    lower_to_writer(program, &mut file)?;
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction::*;
+    use crate::testing::{self, program};
+
+    #[test]
+    fn divide_checks_branch_around_a_long_jump() {
+        let asm = lower_to_string(&testing::divides_by_zero()).unwrap();
+
+        assert!(!asm.contains("cbz"), "{}", asm);
+        assert!(
+            asm.contains("  cbnz x13, 1f\n  b divide_by_zero\n1:\n  udiv x12, x12, x13\n"),
+            "{}",
+            asm
+        );
+        assert_eq!(asm.matches("\ndivide_by_zero:\n").count(), 1);
+    }
+
+    #[test]
+    fn loop_tests_branch_around_a_long_jump() {
+        let asm = lower_to_string(&testing::every_instruction()).unwrap();
+
+        assert!(!asm.contains("cbz"), "{}", asm);
+        assert_eq!(asm.matches("  cbnz x12, 1f\n  b ").count(), 2, "{}", asm);
+    }
+
+    #[test]
+    fn large_values_load_16_bits_at_a_time() {
+        let program = program(
+            1,
+            &[
+                Set {
+                    n: 1,
+                    value: 0x1_0000_0002_0003,
+                },
+                End,
+            ],
+        );
+        let asm = lower_to_string(&program).unwrap();
+
+        assert!(
+            asm.contains("  movz x12, #3\n  movk x12, #2, lsl #16\n  movk x12, #1, lsl #48\n"),
+            "{}",
+            asm
+        );
+    }
+
+    //The interpreter is the oracle, when there is a cross toolchain to check against
+    #[test]
+    fn programs_match_the_interpreter() {
+        let run = |test: &str, program: &Program, input: &[u8]| {
+            let asm = lower_to_string(program).unwrap();
+            testing::run_cross("aarch64-linux-gnu-gcc", "qemu-aarch64", test, &asm, input)
+        };
+
+        let (input, printed) = testing::EVERY_INSTRUCTION_IO;
+        let program = testing::every_instruction();
+        if let Some(output) = run("aarch64_every_instruction", &program, input) {
+            assert!(output.status.success());
+            assert_eq!(output.stdout, testing::interpret(&program, input));
+            assert_eq!(output.stdout, printed);
+        }
+
+        if let Some(output) = run("aarch64_divides_by_zero", &testing::divides_by_zero(), b"") {
+            assert!(!output.status.success());
+            assert_eq!(output.stdout, b"A");
+        }
+    }
+}
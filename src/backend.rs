@@ -0,0 +1,112 @@
+//Hayden Coffey
+//Code generation interface shared by the targets. lower walks the checked program and
+//calls one Backend method per instruction, so a target only describes its own code.
+use crate::check;
+use crate::instruction::{DuckInstruction, Instruction};
+use crate::program::Program;
+use crate::rotation;
+
+//Labels around one loop: start is jumped back to, end is where the loop exits
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LoopLabels {
+    pub start: String,
+    pub end: String,
+}
+
+pub trait Backend {
+    //Set up the duck array (ducks, goose and teacher zeroed) with the goose at 0
+    fn prologue(&mut self, program: &Program) -> std::io::Result<()>;
+
+    //Code after the last instruction, reached by falling off the end of the program
+    fn epilogue(&mut self) -> std::io::Result<()>;
+
+    //Names for the labels of the loop the check pass numbered label
+    fn loop_labels(&mut self, label: usize) -> LoopLabels {
+        LoopLabels {
+            start: format!("start_{}", label),
+            end: format!("end_{}", label),
+        }
+    }
+
+    //Ring index of the goose before the next instruction, if the rotation analysis knows
+    //it. Lets a target compute duck indices at compile time instead of at runtime.
+    fn known_goose(&mut self, _goose: Option<usize>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    //Called before each instruction, for targets that note the source in their output
+    fn comment(&mut self, _inst: &DuckInstruction) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn end(&mut self) -> std::io::Result<()>;
+    fn print(&mut self, n: usize) -> std::io::Result<()>;
+    fn add(&mut self, n: usize, y: usize) -> std::io::Result<()>;
+    fn subtract(&mut self, n: usize, y: usize) -> std::io::Result<()>;
+    fn multiply(&mut self, n: usize, y: usize) -> std::io::Result<()>;
+    fn divide(&mut self, n: usize, y: usize) -> std::io::Result<()>;
+    fn input(&mut self, n: usize) -> std::io::Result<()>;
+    fn push(&mut self, n: usize) -> std::io::Result<()>;
+    fn pop(&mut self, n: usize) -> std::io::Result<()>;
+    fn loop_begin(&mut self, cond: usize, id: usize, labels: &LoopLabels) -> std::io::Result<()>;
+    fn loop_end(&mut self, id: usize, labels: &LoopLabels) -> std::io::Result<()>;
+    fn set(&mut self, n: usize, value: usize) -> std::io::Result<()>;
+
+    //Goose to duck n without storing. Passes only leave a Move where nothing reads the
+    //goose slot before it is next written, so storing 0 there first is the same.
+    fn move_goose(&mut self, n: usize) -> std::io::Result<()> {
+        self.set(n, 0)
+    }
+
+    //Low byte of value to stdout. Has no default, since printing it with print would need
+    //a slot to hold it and any slot may still be read.
+    fn print_value(&mut self, value: usize) -> std::io::Result<()>;
+}
+
+//Lower program through the given backend
+//Fails with InvalidData if the program doesn't pass check::check
+pub fn lower<B: Backend>(program: &Program, backend: &mut B) -> std::io::Result<()> {
+    let loops = check::check(program).map_err(check::into_io_error)?;
+    let rotation = rotation::analyze(program, &loops);
+
+    backend.prologue(program)?;
+
+    //Labels of the loops enclosing the current instruction, innermost last
+    let mut open = Vec::new();
+    for (pos, inst) in program.iter().enumerate() {
+        backend.known_goose(rotation.goose(pos))?;
+        backend.comment(inst)?;
+        match inst.inst {
+            Instruction::End => backend.end()?,
+            Instruction::Print { n } => backend.print(n)?,
+            Instruction::Add { n, y } => backend.add(n, y)?,
+            Instruction::Subtract { n, y } => backend.subtract(n, y)?,
+            Instruction::Multiply { n, y } => backend.multiply(n, y)?,
+            Instruction::Divide { n, y } => backend.divide(n, y)?,
+            Instruction::Input { n } => backend.input(n)?,
+            Instruction::Push { n } => backend.push(n)?,
+            Instruction::Pop { n } => backend.pop(n)?,
+            Instruction::LoopBegin { cond, id } => {
+                let labels = backend.loop_labels(loops.label(pos).unwrap_or_default());
+                backend.loop_begin(cond, id, &labels)?;
+                open.push(labels);
+            }
+            Instruction::LoopEnd { id } => {
+                //The check pass pairs every LoopEnd with an earlier LoopBegin
+                let labels = open.pop().expect("LoopEnd without LoopBegin");
+                backend.loop_end(id, &labels)?;
+            }
+            Instruction::Set { n, value } => backend.set(n, value)?,
+            Instruction::Move { n } => backend.move_goose(n)?,
+            Instruction::PrintValue { value } => backend.print_value(value)?,
+        }
+    }
+
+    backend.epilogue()
+}
+
+//Text written by a backend to a byte buffer
+pub fn into_text(bytes: Vec<u8>) -> std::io::Result<String> {
+    String::from_utf8(bytes)
+        .map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))
+}
@@ -0,0 +1,321 @@
+//Hayden Coffey
+//Portable C99 backend. Emits a single self-contained file with the same layout as the
+//assembly backends: a duck array with the teacher after the last duck, and a goose index.
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use crate::backend::{self, Backend, LoopLabels};
+use crate::instruction::DuckInstruction;
+use crate::program::Program;
+
+//Runtime pieces every program shares
+static PRELUDE: &str = "#include <signal.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+#define RING_SIZE (DUCK_COUNT + 1)
+#define TEACHER RING_SIZE
+
+/* Index of the duck n places after the goose */
+#define DUCK(n) ((goose + (n)) % RING_SIZE)
+
+static uint64_t ducks[DUCK_COUNT + 3];
+static uint64_t goose;
+
+/* Division by zero raises SIGFPE like the x86-64 backend's divq. The signal kills the
+   process without flushing stdio, so anything printed so far goes out first. */
+static inline uint64_t divide(uint64_t n, uint64_t y)
+{
+    if (y == 0) {
+        fflush(stdout);
+        raise(SIGFPE);
+        abort();
+    }
+    return n / y;
+}
+
+/* One char from stdin, end of input reads as 0 */
+static inline uint64_t input(void)
+{
+    int c;
+
+    fflush(stdout);
+    c = getchar();
+    return c == EOF ? 0 : (uint64_t)(unsigned char)c;
+}
+";
+
+//Duck operands are unsigned 64 bit, spell large ones so any size fits
+fn literal(value: usize) -> String {
+    if value <= u32::MAX as usize {
+        value.to_string()
+    } else {
+        format!("UINT64_C({})", value)
+    }
+}
+
+fn duck(n: usize) -> String {
+    format!("DUCK({})", literal(n))
+}
+
+fn indent<W: Write>(depth: usize, file: &mut W) -> std::io::Result<()> {
+    write!(file, "{}", "    ".repeat(depth))
+}
+
+//Store value in the goose, then move the goose to target
+fn write_store_result<W: Write>(value: &str, depth: usize, file: &mut W) -> std::io::Result<()> {
+    indent(depth, file)?;
+    writeln!(file, "ducks[goose] = {};", value)?;
+    indent(depth, file)?;
+    writeln!(file, "goose = target;")
+}
+
+fn write_target<W: Write>(n: usize, depth: usize, file: &mut W) -> std::io::Result<()> {
+    indent(depth, file)?;
+    writeln!(file, "target = {};", duck(n))
+}
+
+//Statements are indented by how many while blocks they are in
+pub struct C<W: Write> {
+    file: W,
+    depth: usize,
+}
+
+impl<W: Write> C<W> {
+    pub fn new(file: W) -> C<W> {
+        C { file, depth: 1 }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.file
+    }
+
+    fn line(&mut self, text: &str) -> std::io::Result<()> {
+        indent(self.depth, &mut self.file)?;
+        writeln!(self.file, "{}", text)
+    }
+
+    fn store_result(&mut self, value: &str) -> std::io::Result<()> {
+        write_store_result(value, self.depth, &mut self.file)
+    }
+}
+
+impl<W: Write> Backend for C<W> {
+    fn prologue(&mut self, program: &Program) -> std::io::Result<()> {
+        if let Some(name) = program.source_name() {
+            writeln!(self.file, "/* Generated by gdd from {} */", name)?;
+        }
+        writeln!(
+            self.file,
+            "#define DUCK_COUNT {}",
+            literal(program.duck_count())
+        )?;
+        writeln!(self.file, "{}", PRELUDE)?;
+
+        writeln!(self.file, "int main(void)\n{{")?;
+        writeln!(self.file, "    uint64_t target;\n")
+    }
+
+    fn epilogue(&mut self) -> std::io::Result<()> {
+        writeln!(self.file, "    return 0;\n}}")
+    }
+
+    fn comment(&mut self, inst: &DuckInstruction) -> std::io::Result<()> {
+        self.line(&format!("/* {} */", inst.inst))
+    }
+
+    fn end(&mut self) -> std::io::Result<()> {
+        self.line("exit(0);")
+    }
+
+    fn print(&mut self, n: usize) -> std::io::Result<()> {
+        self.line(&format!("putchar((unsigned char)ducks[{}]);", duck(n)))
+    }
+
+    fn add(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_target(n, self.depth, &mut self.file)?;
+        self.store_result(&format!("ducks[target] + ducks[{}]", duck(y)))
+    }
+
+    fn subtract(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_target(n, self.depth, &mut self.file)?;
+        self.store_result(&format!("ducks[target] - ducks[{}]", duck(y)))
+    }
+
+    fn multiply(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_target(n, self.depth, &mut self.file)?;
+        self.store_result(&format!("ducks[target] * ducks[{}]", duck(y)))
+    }
+
+    fn divide(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_target(n, self.depth, &mut self.file)?;
+        self.store_result(&format!("divide(ducks[target], ducks[{}])", duck(y)))
+    }
+
+    fn input(&mut self, n: usize) -> std::io::Result<()> {
+        write_target(n, self.depth, &mut self.file)?;
+        self.store_result("input()")
+    }
+
+    fn push(&mut self, n: usize) -> std::io::Result<()> {
+        write_target(n, self.depth, &mut self.file)?;
+        self.line("ducks[TEACHER] = ducks[target];")?;
+        self.store_result("ducks[target]")
+    }
+
+    fn pop(&mut self, n: usize) -> std::io::Result<()> {
+        write_target(n, self.depth, &mut self.file)?;
+        self.line("ducks[goose] = ducks[TEACHER];")?;
+        self.line("ducks[TEACHER] = 0;")?;
+        self.line("goose = target;")
+    }
+
+    //Loops become while blocks, the labels aren't needed
+    fn loop_begin(&mut self, cond: usize, _id: usize, _labels: &LoopLabels) -> std::io::Result<()> {
+        self.line(&format!("while (ducks[{}] != 0) {{", duck(cond)))?;
+        self.depth += 1;
+
+        Ok(())
+    }
+
+    fn loop_end(&mut self, _id: usize, _labels: &LoopLabels) -> std::io::Result<()> {
+        self.depth -= 1;
+        self.line("}")
+    }
+
+    fn set(&mut self, n: usize, value: usize) -> std::io::Result<()> {
+        write_target(n, self.depth, &mut self.file)?;
+        self.store_result(&literal(value))
+    }
+
+    fn move_goose(&mut self, n: usize) -> std::io::Result<()> {
+        self.line(&format!("goose = {};", duck(n)))
+    }
+
+    fn print_value(&mut self, value: usize) -> std::io::Result<()> {
+        self.line(&format!("putchar((unsigned char){});", literal(value)))
+    }
+}
+
+//Write program as a C99 source file to out
+pub fn lower_to_writer(program: &Program, out: &mut impl Write) -> std::io::Result<()> {
+    backend::lower(program, &mut C::new(out))
+}
+
+//Program as a C99 source file in a string
+pub fn lower_to_string(program: &Program) -> std::io::Result<String> {
+    let mut out = Vec::new();
+    lower_to_writer(program, &mut out)?;
+
+    backend::into_text(out)
+}
+
+//Write program as a C99 source file
+pub fn lower_program(program: &Program, file_out_name: &str) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(file_out_name)?);
+    lower_to_writer(program, &mut file)?;
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction::*;
+    use crate::testing::{self, program};
+    use std::process::Command;
+
+    //Compile the C for program with cc and run it on input
+    fn run(test: &str, program: &Program, input: &[u8]) -> std::process::Output {
+        let c_path = testing::temp_path(&format!("{}.c", test));
+        let exe = testing::temp_path(test);
+        lower_program(program, &c_path.to_string_lossy()).unwrap();
+        let status = Command::new("cc")
+            .arg("-std=c99")
+            .arg(&c_path)
+            .arg("-o")
+            .arg(&exe)
+            .status()
+            .unwrap();
+        std::fs::remove_file(&c_path).unwrap();
+        assert!(status.success(), "cc failed on {}", test);
+
+        testing::run_executable(&exe, input)
+    }
+
+    #[test]
+    fn large_values_are_64_bit_literals() {
+        let program = program(
+            1,
+            &[
+                Set {
+                    n: 1,
+                    value: u32::MAX as usize,
+                },
+                Set {
+                    n: 1,
+                    value: u32::MAX as usize + 1,
+                },
+                End,
+            ],
+        );
+        let c = lower_to_string(&program).unwrap();
+
+        assert!(c.contains("    ducks[goose] = 4294967295;\n"), "{}", c);
+        assert!(
+            c.contains("    ducks[goose] = UINT64_C(4294967296);\n"),
+            "{}",
+            c
+        );
+    }
+
+    #[test]
+    fn loops_are_nested_while_blocks() {
+        let c = lower_to_string(&testing::every_instruction()).unwrap();
+
+        assert!(c.contains("\n    while (ducks[DUCK(2)] != 0) {\n        /* Set 1, $0 */\n"));
+        assert_eq!(c.matches("while (").count(), 2);
+        assert!(c.ends_with("    exit(0);\n    return 0;\n}\n"), "{}", c);
+    }
+
+    //The interpreter is the oracle
+    #[test]
+    fn programs_match_the_interpreter() {
+        if !testing::have_tool("cc", "programs_match_the_interpreter") {
+            return;
+        }
+
+        let (input, printed) = testing::EVERY_INSTRUCTION_IO;
+        let program = testing::every_instruction();
+        let output = run("c_every_instruction", &program, input);
+        assert!(output.status.success());
+        assert_eq!(output.stdout, testing::interpret(&program, input));
+        assert_eq!(output.stdout, printed);
+
+        let cat = crate::parse::parse_str(include_str!("../examples/cat.ddg")).unwrap();
+        let output = run("c_cat", &cat, b"quack");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"quack");
+    }
+
+    //stdout is a pipe here, so putchar output sits in stdio's buffer until the flush
+    #[test]
+    fn output_survives_dividing_by_zero() {
+        use std::os::unix::process::ExitStatusExt;
+
+        if !testing::have_tool("cc", "output_survives_dividing_by_zero") {
+            return;
+        }
+
+        let program = testing::divides_by_zero();
+        let mut expected = Vec::new();
+        assert!(crate::interp::run(&program, &b""[..], &mut expected).is_err());
+
+        let output = run("c_divides_by_zero", &program, b"");
+        assert_eq!(output.status.signal(), Some(8));
+        assert_eq!(output.stdout, expected);
+        assert_eq!(output.stdout, b"A");
+    }
+}
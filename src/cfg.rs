@@ -0,0 +1,623 @@
+//Hayden Coffey
+//Control-flow graph of a checked program. Blocks are runs of instructions with a single
+//entry: every LoopBegin is a block of its own (the loop test), and a block ends after a
+//LoopEnd (jump back to the test) or an End. An empty exit block follows the last
+//instruction, so falling off the program, End and leaving an outermost loop all meet there.
+use std::fmt::Write as _;
+
+use crate::check::Loops;
+use crate::instruction::Instruction;
+use crate::program::Program;
+
+//Instruction positions start..end, and the edges in and out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub start: usize,
+    pub end: usize,
+    //A loop test has two, the body first and the loop exit second
+    pub succs: Vec<usize>,
+    pub preds: Vec<usize>,
+}
+
+impl Block {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
+//Loop found from the back edges of the graph. Blocks that can't reach a back edge, such
+//as code after an End in the body, aren't part of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NaturalLoop {
+    pub header: usize,
+    //Blocks jumping back to the header
+    pub latches: Vec<usize>,
+    //Every block in the loop, nested loops included, in block order
+    pub blocks: Vec<usize>,
+    //Innermost enclosing loop, None for a root of the forest
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    //1 for outermost loops
+    pub depth: usize,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cfg {
+    blocks: Vec<Block>,
+    //Block holding each instruction, plus the exit block for the position after the last
+    block_of: Vec<usize>,
+    //Immediate dominator, None for the entry and for unreachable blocks
+    idom: Vec<Option<usize>>,
+    reachable: Vec<bool>,
+    //Ordered by header, so outer loops come before the loops nested in them
+    loops: Vec<NaturalLoop>,
+    //Innermost loop of each block
+    loop_of: Vec<Option<usize>>,
+}
+
+impl Cfg {
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn block(&self, block: usize) -> &Block {
+        &self.blocks[block]
+    }
+
+    pub fn entry(&self) -> usize {
+        0
+    }
+
+    pub fn exit(&self) -> usize {
+        self.blocks.len() - 1
+    }
+
+    //Block holding the instruction at pos
+    pub fn block_of(&self, pos: usize) -> usize {
+        self.block_of[pos]
+    }
+
+    pub fn is_reachable(&self, block: usize) -> bool {
+        self.reachable[block]
+    }
+
+    pub fn idom(&self, block: usize) -> Option<usize> {
+        self.idom[block]
+    }
+
+    //Whether every path from the entry to b goes through a. Blocks dominate themselves.
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        if !self.reachable[b] {
+            return false;
+        }
+
+        let mut block = b;
+        loop {
+            if block == a {
+                return true;
+            }
+            match self.idom[block] {
+                Some(idom) => block = idom,
+                None => return false,
+            }
+        }
+    }
+
+    //Children of block in the dominator tree
+    pub fn dominated(&self, block: usize) -> Vec<usize> {
+        (0..self.blocks.len())
+            .filter(|&child| self.idom[child] == Some(block))
+            .collect()
+    }
+
+    pub fn loops(&self) -> &[NaturalLoop] {
+        &self.loops
+    }
+
+    //Outermost loops, the roots of the loop nesting forest
+    pub fn root_loops(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.loops.len()).filter(|&l| self.loops[l].parent.is_none())
+    }
+
+    //Innermost loop containing block
+    pub fn loop_of(&self, block: usize) -> Option<usize> {
+        self.loop_of[block]
+    }
+
+    //Number of loops containing block
+    pub fn loop_depth(&self, block: usize) -> usize {
+        self.loop_of[block].map_or(0, |l| self.loops[l].depth)
+    }
+
+    //Whether the edge from -> to jumps back to a loop header
+    pub fn is_back_edge(&self, from: usize, to: usize) -> bool {
+        self.blocks[from].succs.contains(&to) && self.dominates(to, from)
+    }
+
+    //Graphviz rendering: loops as nested clusters, back edges dashed, and the dominator
+    //tree as grey dotted edges that don't affect the layout
+    pub fn to_dot(&self, program: &Program) -> String {
+        let mut out = String::new();
+        let name = program.source_name().unwrap_or("<memory>");
+        let _ = writeln!(out, "digraph cfg {{");
+        let _ = writeln!(out, "    label=\"{}\";", escape(name));
+        let _ = writeln!(out, "    node [shape=box, fontname=\"monospace\"];");
+
+        for block in 0..self.blocks.len() {
+            if self.loop_of[block].is_none() {
+                self.write_node(&mut out, program, block, 1);
+            }
+        }
+        for l in self.root_loops() {
+            self.write_cluster(&mut out, program, l, 1);
+        }
+
+        for (from, block) in self.blocks.iter().enumerate() {
+            for (i, &to) in block.succs.iter().enumerate() {
+                let mut attrs = Vec::new();
+                if block.succs.len() == 2 {
+                    attrs.push(if i == 0 {
+                        "label=\"nonzero\""
+                    } else {
+                        "label=\"zero\""
+                    });
+                }
+                if self.is_back_edge(from, to) {
+                    attrs.push("style=dashed");
+                }
+
+                if attrs.is_empty() {
+                    let _ = writeln!(out, "    b{} -> b{};", from, to);
+                } else {
+                    let _ = writeln!(out, "    b{} -> b{} [{}];", from, to, attrs.join(", "));
+                }
+            }
+        }
+
+        for block in 0..self.blocks.len() {
+            if let Some(idom) = self.idom[block] {
+                let _ = writeln!(
+                    out,
+                    "    b{} -> b{} [style=dotted, color=grey, arrowhead=empty, constraint=false];",
+                    idom, block
+                );
+            }
+        }
+
+        out.push_str("}\n");
+        out
+    }
+
+    fn write_node(&self, out: &mut String, program: &Program, block: usize, indent: usize) {
+        let pad = "    ".repeat(indent);
+        let mut label = format!("b{}", block);
+        if block == self.entry() {
+            label.push_str(" entry");
+        }
+        if block == self.exit() {
+            label.push_str(" exit");
+        }
+        label.push_str("\\l");
+        for inst in &program.instructions()[self.blocks[block].start..self.blocks[block].end] {
+            let line = format!("{}  {}", inst.span, inst.inst);
+            label.push_str(&escape(&line));
+            label.push_str("\\l");
+        }
+
+        let style = if self.reachable[block] {
+            ""
+        } else {
+            ", style=dotted"
+        };
+        let _ = writeln!(out, "{}b{} [label=\"{}\"{}];", pad, block, label, style);
+    }
+
+    fn write_cluster(&self, out: &mut String, program: &Program, l: usize, indent: usize) {
+        let pad = "    ".repeat(indent);
+        let _ = writeln!(out, "{}subgraph cluster_loop{} {{", pad, l);
+        let _ = writeln!(
+            out,
+            "{}    label=\"loop {}, depth {}\";",
+            pad, l, self.loops[l].depth
+        );
+        let _ = writeln!(out, "{}    style=dashed;", pad);
+
+        for &block in &self.loops[l].blocks {
+            if self.loop_of[block] == Some(l) {
+                self.write_node(out, program, block, indent + 1);
+            }
+        }
+        for &child in &self.loops[l].children {
+            self.write_cluster(out, program, child, indent + 1);
+        }
+
+        let _ = writeln!(out, "{}}}", pad);
+    }
+}
+
+//Quote text for a Graphviz label
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+//Split the program into blocks and connect them
+fn blocks(program: &Program, loops: &Loops) -> (Vec<Block>, Vec<usize>) {
+    let len = program.len();
+    let mut leader = vec![false; len + 1];
+    leader[0] = true;
+    leader[len] = true;
+    for (pos, inst) in program.iter().enumerate() {
+        match inst.inst {
+            Instruction::LoopBegin { .. } => {
+                leader[pos] = true;
+                leader[pos + 1] = true;
+            }
+            Instruction::LoopEnd { .. } | Instruction::End => leader[pos + 1] = true,
+            _ => (),
+        }
+    }
+
+    let starts: Vec<usize> = (0..=len).filter(|&pos| leader[pos]).collect();
+    let mut block_of = vec![0; len + 1];
+    let mut blocks = Vec::with_capacity(starts.len());
+    for (block, &start) in starts.iter().enumerate() {
+        let end = starts.get(block + 1).copied().unwrap_or(len);
+        block_of[start..end.max(start + 1)].fill(block);
+        blocks.push(Block {
+            start,
+            end,
+            succs: Vec::new(),
+            preds: Vec::new(),
+        });
+    }
+
+    let exit = blocks.len() - 1;
+    for (block, info) in blocks.iter_mut().enumerate().take(exit) {
+        let last = info.end - 1;
+        let partner = loops.partner(last).unwrap_or(last);
+        info.succs = match program.instructions()[last].inst {
+            Instruction::LoopBegin { .. } => vec![block_of[last + 1], block_of[partner + 1]],
+            Instruction::LoopEnd { .. } => vec![block_of[partner]],
+            Instruction::End => vec![exit],
+            _ => vec![block + 1],
+        };
+    }
+    for block in 0..blocks.len() {
+        for succ in blocks[block].succs.clone() {
+            blocks[succ].preds.push(block);
+        }
+    }
+
+    (blocks, block_of)
+}
+
+//Blocks reachable from the entry in reverse postorder
+fn reverse_postorder(blocks: &[Block]) -> Vec<usize> {
+    let mut visited = vec![false; blocks.len()];
+    let mut order = Vec::with_capacity(blocks.len());
+
+    //Blocks being visited, with the next successor to look at
+    let mut stack = vec![(0, 0)];
+    visited[0] = true;
+    while let Some((block, next)) = stack.pop() {
+        match blocks[block].succs.get(next) {
+            Some(&succ) => {
+                stack.push((block, next + 1));
+                if !visited[succ] {
+                    visited[succ] = true;
+                    stack.push((succ, 0));
+                }
+            }
+            None => order.push(block),
+        }
+    }
+
+    order.reverse();
+    order
+}
+
+//Immediate dominators by the iterative algorithm of Cooper, Harvey and Kennedy
+fn dominators(blocks: &[Block], rpo: &[usize]) -> Vec<Option<usize>> {
+    let mut rank = vec![usize::MAX; blocks.len()];
+    for (i, &block) in rpo.iter().enumerate() {
+        rank[block] = i;
+    }
+
+    let mut idom = vec![None; blocks.len()];
+    idom[0] = Some(0);
+    let intersect = |idom: &[Option<usize>], mut a: usize, mut b: usize| {
+        while a != b {
+            while rank[a] > rank[b] {
+                a = idom[a].unwrap_or(0);
+            }
+            while rank[b] > rank[a] {
+                b = idom[b].unwrap_or(0);
+            }
+        }
+        a
+    };
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for &block in &rpo[1..] {
+            let mut new = None;
+            for &pred in &blocks[block].preds {
+                if idom[pred].is_none() {
+                    continue;
+                }
+                new = Some(match new {
+                    None => pred,
+                    Some(new) => intersect(&idom, pred, new),
+                });
+            }
+
+            if new.is_some() && idom[block] != new {
+                idom[block] = new;
+                changed = true;
+            }
+        }
+    }
+
+    idom[0] = None;
+    idom
+}
+
+//Build the graph of a checked program
+pub fn build(program: &Program, loops: &Loops) -> Cfg {
+    let (blocks, block_of) = blocks(program, loops);
+    let rpo = reverse_postorder(&blocks);
+    let idom = dominators(&blocks, &rpo);
+    let mut reachable = vec![false; blocks.len()];
+    for &block in &rpo {
+        reachable[block] = true;
+    }
+
+    let mut cfg = Cfg {
+        loop_of: vec![None; blocks.len()],
+        blocks,
+        block_of,
+        idom,
+        reachable,
+        loops: Vec::new(),
+    };
+
+    //Natural loop of every header: the blocks that reach a latch without passing the header
+    for header in 0..cfg.blocks.len() {
+        let latches: Vec<usize> = cfg.blocks[header]
+            .preds
+            .iter()
+            .copied()
+            .filter(|&pred| cfg.dominates(header, pred))
+            .collect();
+        if latches.is_empty() {
+            continue;
+        }
+
+        let mut member = vec![false; cfg.blocks.len()];
+        member[header] = true;
+        let mut work = latches.clone();
+        while let Some(block) = work.pop() {
+            if member[block] || !cfg.reachable[block] {
+                continue;
+            }
+            member[block] = true;
+            work.extend(cfg.blocks[block].preds.iter().copied());
+        }
+
+        cfg.loops.push(NaturalLoop {
+            header,
+            latches,
+            blocks: (0..cfg.blocks.len()).filter(|&b| member[b]).collect(),
+            parent: None,
+            children: Vec::new(),
+            depth: 1,
+        });
+    }
+
+    //Loops nest, so the smallest other loop holding a header is its parent. Parents have
+    //earlier headers, so their depth is final by the time their children are reached.
+    for l in 0..cfg.loops.len() {
+        let header = cfg.loops[l].header;
+        let parent = (0..l)
+            .filter(|&outer| cfg.loops[outer].blocks.contains(&header))
+            .min_by_key(|&outer| cfg.loops[outer].blocks.len());
+        if let Some(parent) = parent {
+            cfg.loops[l].parent = Some(parent);
+            cfg.loops[l].depth = cfg.loops[parent].depth + 1;
+            cfg.loops[parent].children.push(l);
+        }
+
+        for b in cfg.loops[l].blocks.clone() {
+            cfg.loop_of[b] = Some(l);
+        }
+    }
+
+    cfg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check;
+    use crate::instruction::Instruction::*;
+    use crate::testing::program;
+
+    fn graph(insts: &[Instruction]) -> Cfg {
+        let program = program(2, insts);
+        let loops = check::check(&program).unwrap();
+        build(&program, &loops)
+    }
+
+    fn block(start: usize, end: usize, succs: &[usize], preds: &[usize]) -> Block {
+        Block {
+            start,
+            end,
+            succs: succs.to_vec(),
+            preds: preds.to_vec(),
+        }
+    }
+
+    //Two loops one inside the other, the outer test being the entry
+    fn nested() -> Cfg {
+        graph(&[
+            LoopBegin { cond: 0, id: 0 },
+            LoopBegin { cond: 1, id: 1 },
+            Print { n: 1 },
+            LoopEnd { id: 1 },
+            LoopEnd { id: 0 },
+            End,
+        ])
+    }
+
+    #[test]
+    fn blocks_split_around_loops_and_ends() {
+        let cfg = graph(&[
+            Set { n: 1, value: 1 },
+            LoopBegin { cond: 1, id: 0 },
+            Print { n: 1 },
+            LoopEnd { id: 0 },
+            Print { n: 2 },
+            End,
+            Set { n: 2, value: 0 },
+        ]);
+
+        assert_eq!(
+            cfg.blocks(),
+            [
+                block(0, 1, &[1], &[]),
+                block(1, 2, &[2, 3], &[0, 2]),
+                block(2, 4, &[1], &[1]),
+                block(4, 6, &[5], &[1]),
+                block(6, 7, &[5], &[]),
+                block(7, 7, &[], &[3, 4]),
+            ]
+        );
+        assert_eq!(cfg.exit(), 5);
+        assert_eq!(cfg.block_of(3), 2);
+        assert_eq!(cfg.block_of(7), 5);
+        assert!(!cfg.is_reachable(4));
+        assert_eq!(cfg.idom(4), None);
+        assert_eq!(cfg.idom(5), Some(3));
+        assert!(cfg.is_back_edge(2, 1));
+        assert!(!cfg.is_back_edge(1, 2));
+    }
+
+    #[test]
+    fn dominators_of_nested_loops() {
+        let cfg = nested();
+
+        let idoms: Vec<Option<usize>> = (0..cfg.blocks().len()).map(|b| cfg.idom(b)).collect();
+        assert_eq!(idoms, [None, Some(0), Some(1), Some(1), Some(0), Some(4)]);
+        assert!(cfg.dominates(0, 5));
+        assert!(cfg.dominates(1, 3));
+        assert!(cfg.dominates(2, 2));
+        assert!(!cfg.dominates(2, 3));
+        assert!(!cfg.dominates(3, 4));
+        assert_eq!(cfg.dominated(1), [2, 3]);
+        assert!(cfg.is_back_edge(3, 0));
+        assert!(cfg.is_back_edge(2, 1));
+    }
+
+    #[test]
+    fn nested_loops_hold_their_inner_loops() {
+        let cfg = nested();
+
+        assert_eq!(
+            cfg.loops(),
+            [
+                NaturalLoop {
+                    header: 0,
+                    latches: vec![3],
+                    blocks: vec![0, 1, 2, 3],
+                    parent: None,
+                    children: vec![1],
+                    depth: 1,
+                },
+                NaturalLoop {
+                    header: 1,
+                    latches: vec![2],
+                    blocks: vec![1, 2],
+                    parent: Some(0),
+                    children: Vec::new(),
+                    depth: 2,
+                },
+            ]
+        );
+        assert_eq!(cfg.loop_of(3), Some(0));
+        assert_eq!(cfg.loop_depth(2), 2);
+        assert_eq!(cfg.loop_depth(4), 0);
+    }
+
+    #[test]
+    fn ends_in_a_loop_body_leave_the_loop() {
+        let cfg = graph(&[
+            LoopBegin { cond: 1, id: 0 },
+            LoopBegin { cond: 2, id: 1 },
+            End,
+            //Never runs, so the inner loop never jumps back
+            Print { n: 1 },
+            LoopEnd { id: 1 },
+            Set { n: 2, value: 0 },
+            LoopEnd { id: 0 },
+            End,
+        ]);
+
+        assert_eq!(cfg.block_of(2), 2);
+        assert_eq!(cfg.block(2).succs, [cfg.exit()]);
+        assert!(!cfg.is_reachable(3));
+        assert_eq!(cfg.loops().len(), 1);
+        assert_eq!(cfg.loops()[0].header, 0);
+        assert_eq!(cfg.loops()[0].latches, [4]);
+        assert_eq!(cfg.loops()[0].blocks, [0, 1, 4]);
+        assert_eq!(cfg.loop_of(2), None);
+        assert_eq!(cfg.loop_of(3), None);
+    }
+
+    #[test]
+    fn sibling_loops_share_a_parent() {
+        let cfg = graph(&[
+            LoopBegin { cond: 1, id: 0 },
+            LoopBegin { cond: 2, id: 1 },
+            Set { n: 2, value: 0 },
+            LoopEnd { id: 1 },
+            LoopBegin { cond: 1, id: 2 },
+            Set { n: 1, value: 0 },
+            LoopEnd { id: 2 },
+            LoopEnd { id: 0 },
+            LoopBegin { cond: 2, id: 3 },
+            Set { n: 2, value: 0 },
+            LoopEnd { id: 3 },
+            End,
+        ]);
+
+        let headers: Vec<usize> = cfg.loops().iter().map(|l| l.header).collect();
+        assert_eq!(headers, [0, 1, 3, 6]);
+        let parents: Vec<Option<usize>> = cfg.loops().iter().map(|l| l.parent).collect();
+        assert_eq!(parents, [None, Some(0), Some(0), None]);
+        let depths: Vec<usize> = cfg.loops().iter().map(|l| l.depth).collect();
+        assert_eq!(depths, [1, 2, 2, 1]);
+        assert_eq!(cfg.loops()[0].children, [1, 2]);
+        assert_eq!(cfg.loops()[0].blocks, [0, 1, 2, 3, 4, 5]);
+        assert_eq!(cfg.root_loops().collect::<Vec<_>>(), [0, 3]);
+        assert_eq!(cfg.loop_of(4), Some(2));
+        assert_eq!(cfg.loop_of(8), None);
+    }
+
+    #[test]
+    fn empty_program_is_one_block() {
+        let cfg = graph(&[]);
+
+        assert_eq!(cfg.blocks(), [block(0, 0, &[], &[])]);
+        assert_eq!(cfg.entry(), cfg.exit());
+        assert_eq!(cfg.block_of(0), 0);
+        assert!(cfg.is_reachable(0));
+        assert_eq!(cfg.idom(0), None);
+        assert!(cfg.dominates(0, 0));
+        assert!(cfg.loops().is_empty());
+    }
+}
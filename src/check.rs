@@ -0,0 +1,355 @@
+//Hayden Coffey
+//Semantic checks run after parsing. Loops are matched structurally: a LoopEnd closes
+//the innermost open LoopBegin, and each matched pair gets a label independent of the
+//ids written in the source.
+use std::fmt;
+
+use crate::instruction::{Instruction, Span};
+use crate::parse::Snippet;
+use crate::program::Program;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CheckError {
+    //LoopEnd with no open LoopBegin
+    UnmatchedEnd {
+        id: usize,
+        span: Span,
+    },
+    //LoopBegin that is never closed
+    UnclosedLoop {
+        id: usize,
+        span: Span,
+    },
+    //LoopEnd whose id doesn't match the innermost open loop
+    MismatchedEnd {
+        id: usize,
+        span: Span,
+        open_id: usize,
+        open_span: Span,
+    },
+    //LoopBegin reusing the id of a loop it is nested in
+    DuplicateId {
+        id: usize,
+        span: Span,
+        outer_span: Span,
+    },
+}
+
+impl CheckError {
+    pub fn message(&self) -> String {
+        match self {
+            CheckError::UnmatchedEnd { id, .. } => format!("LoopEnd {} has no open loop!", id),
+            CheckError::UnclosedLoop { id, .. } => format!("Loop {} is never closed!", id),
+            CheckError::MismatchedEnd { id, open_id, .. } => {
+                format!("LoopEnd {} closes loop {}!", id, open_id)
+            }
+            CheckError::DuplicateId { id, .. } => {
+                format!("Loop id {} is already used by an enclosing loop!", id)
+            }
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            CheckError::UnmatchedEnd { span, .. }
+            | CheckError::UnclosedLoop { span, .. }
+            | CheckError::MismatchedEnd { span, .. }
+            | CheckError::DuplicateId { span, .. } => *span,
+        }
+    }
+
+    //Related location worth pointing at, such as the loop being closed
+    fn note(&self) -> Option<(&'static str, Span)> {
+        match self {
+            CheckError::MismatchedEnd { open_span, .. } => Some(("loop opened at", *open_span)),
+            CheckError::DuplicateId { outer_span, .. } => {
+                Some(("enclosing loop opened at", *outer_span))
+            }
+            CheckError::UnmatchedEnd { .. } | CheckError::UnclosedLoop { .. } => None,
+        }
+    }
+
+    //Render a rustc style diagnostic, source is the full program text
+    pub fn render(&self, file_name: &str, source: &str) -> String {
+        let at = Snippet::from_source(source, self.span());
+        let mut out = format!("error: {}\n{}", self.message(), at.render(file_name));
+
+        if let Some((note, span)) = self.note() {
+            let pad = " ".repeat(self.span().line.to_string().len());
+            out.push_str(&format!("{} = note: {} {}\n", pad, note, span));
+        }
+
+        out
+    }
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.span().line, self.message())
+    }
+}
+
+impl std::error::Error for CheckError {}
+
+//Loop structure of a checked program, indexed by instruction position
+#[derive(Clone, Debug, Default)]
+pub struct Loops {
+    partner: Vec<Option<usize>>,
+    label: Vec<Option<usize>>,
+    count: usize,
+}
+
+impl Loops {
+    //Position of the LoopEnd matching a LoopBegin, or the other way around
+    pub fn partner(&self, pos: usize) -> Option<usize> {
+        self.partner.get(pos).copied().flatten()
+    }
+
+    //Internal label shared by a matched LoopBegin/LoopEnd pair
+    pub fn label(&self, pos: usize) -> Option<usize> {
+        self.label.get(pos).copied().flatten()
+    }
+
+    //Number of matched loops
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+//For callers that report through std::io, such as the code generators
+pub fn into_io_error(errors: Vec<CheckError>) -> std::io::Error {
+    let messages: Vec<String> = errors.iter().map(CheckError::to_string).collect();
+    std::io::Error::new(std::io::ErrorKind::InvalidData, messages.join("\n"))
+}
+
+//Match loops, reporting every structural problem found
+pub fn check(program: &Program) -> Result<Loops, Vec<CheckError>> {
+    let mut loops = Loops {
+        partner: vec![None; program.len()],
+        label: vec![None; program.len()],
+        count: 0,
+    };
+    let mut errors = Vec::new();
+
+    //Open loops as (position, id, span)
+    let mut open: Vec<(usize, usize, Span)> = Vec::new();
+
+    for (pos, inst) in program.iter().enumerate() {
+        match inst.inst {
+            Instruction::LoopBegin { id, .. } => {
+                if let Some(outer) = open.iter().find(|outer| outer.1 == id) {
+                    errors.push(CheckError::DuplicateId {
+                        id,
+                        span: inst.span,
+                        outer_span: outer.2,
+                    });
+                }
+                open.push((pos, id, inst.span));
+            }
+            Instruction::LoopEnd { id } => {
+                let (begin, open_id, open_span) = match open.pop() {
+                    None => {
+                        errors.push(CheckError::UnmatchedEnd {
+                            id,
+                            span: inst.span,
+                        });
+                        continue;
+                    }
+                    Some(begin) => begin,
+                };
+
+                if open_id != id {
+                    errors.push(CheckError::MismatchedEnd {
+                        id,
+                        span: inst.span,
+                        open_id,
+                        open_span,
+                    });
+                }
+
+                loops.partner[begin] = Some(pos);
+                loops.partner[pos] = Some(begin);
+            }
+            _ => (),
+        }
+    }
+
+    for (_, id, span) in open {
+        errors.push(CheckError::UnclosedLoop { id, span });
+    }
+
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+
+    //Number loops in program order so labels are unique and stable
+    for pos in 0..program.len() {
+        if let (Some(end), Instruction::LoopBegin { .. }) =
+            (loops.partner[pos], program.instructions()[pos].inst)
+        {
+            loops.label[pos] = Some(loops.count);
+            loops.label[end] = Some(loops.count);
+            loops.count += 1;
+        }
+    }
+
+    Ok(loops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::InstructionEnum;
+    use crate::parse;
+
+    //Source for a program given as (instruction, operands), one line per duck count
+    fn source(duck_count: usize, insts: &[(InstructionEnum, &[usize])]) -> String {
+        let ducks = |n: usize| vec!["duck"; n].join(" ");
+        let mut lines = vec![format!("{} goose", ducks(duck_count))];
+        for &(inst, operands) in insts {
+            lines.extend(operands.iter().map(|&n| ducks(n)));
+            lines.push(format!("{} goose", ducks(inst as usize)).trim().to_string());
+        }
+
+        lines.join("\n") + "\n"
+    }
+
+    fn errors(source: &str) -> Vec<CheckError> {
+        let program = parse::parse_str(source).unwrap();
+        check(&program).unwrap_err()
+    }
+
+    #[test]
+    fn unmatched_end() {
+        let source = source(
+            1,
+            &[
+                (InstructionEnum::LoopEnd, &[4]),
+                (InstructionEnum::End, &[]),
+            ],
+        );
+        let errors = errors(&source);
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], CheckError::UnmatchedEnd { id: 4, .. }));
+        assert_eq!(errors[0].span().line, 3);
+        let rendered = errors[0].render("t.ddg", &source);
+        assert!(rendered.starts_with("error: LoopEnd 4 has no open loop!\n"));
+        assert!(rendered.contains("--> t.ddg:3:1"), "{}", rendered);
+    }
+
+    #[test]
+    fn unclosed_loop() {
+        let source = source(
+            1,
+            &[
+                (InstructionEnum::LoopBegin, &[1, 2]),
+                (InstructionEnum::End, &[]),
+            ],
+        );
+        let errors = errors(&source);
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], CheckError::UnclosedLoop { id: 2, .. }));
+        assert_eq!(errors[0].span().line, 4);
+        let rendered = errors[0].render("t.ddg", &source);
+        assert!(rendered.contains("Loop 2 is never closed!"));
+        assert!(rendered.contains("--> t.ddg:4:1"), "{}", rendered);
+    }
+
+    #[test]
+    fn mismatched_end() {
+        let source = source(
+            1,
+            &[
+                (InstructionEnum::LoopBegin, &[1, 2]),
+                (InstructionEnum::LoopEnd, &[3]),
+                (InstructionEnum::End, &[]),
+            ],
+        );
+        let errors = errors(&source);
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            CheckError::MismatchedEnd {
+                id: 3,
+                open_id: 2,
+                ..
+            }
+        ));
+        assert_eq!(errors[0].span().line, 6);
+        let rendered = errors[0].render("t.ddg", &source);
+        assert!(rendered.contains("LoopEnd 3 closes loop 2!"));
+        assert!(rendered.contains("--> t.ddg:6:1"), "{}", rendered);
+        assert!(
+            rendered.contains("= note: loop opened at 4:1"),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn duplicate_id() {
+        let source = source(
+            1,
+            &[
+                (InstructionEnum::LoopBegin, &[1, 2]),
+                (InstructionEnum::LoopBegin, &[1, 2]),
+                (InstructionEnum::LoopEnd, &[2]),
+                (InstructionEnum::LoopEnd, &[2]),
+                (InstructionEnum::End, &[]),
+            ],
+        );
+        let errors = errors(&source);
+
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], CheckError::DuplicateId { id: 2, .. }));
+        assert_eq!(errors[0].span().line, 7);
+        let rendered = errors[0].render("t.ddg", &source);
+        assert!(rendered.contains("Loop id 2 is already used by an enclosing loop!"));
+        assert!(rendered.contains("--> t.ddg:7:1"), "{}", rendered);
+        assert!(
+            rendered.contains("= note: enclosing loop opened at 4:1"),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let source = source(
+            1,
+            &[
+                (InstructionEnum::LoopEnd, &[1]),
+                (InstructionEnum::LoopBegin, &[1, 2]),
+                (InstructionEnum::End, &[]),
+            ],
+        );
+
+        assert_eq!(errors(&source).len(), 2);
+    }
+
+    #[test]
+    fn sibling_loops_may_share_an_id() {
+        let source = source(
+            1,
+            &[
+                (InstructionEnum::LoopBegin, &[1, 2]),
+                (InstructionEnum::LoopEnd, &[2]),
+                (InstructionEnum::LoopBegin, &[1, 2]),
+                (InstructionEnum::LoopEnd, &[2]),
+                (InstructionEnum::End, &[]),
+            ],
+        );
+        let program = parse::parse_str(&source).unwrap();
+        let loops = check(&program).unwrap();
+
+        assert_eq!(loops.count(), 2);
+        assert_eq!(loops.partner(0), Some(1));
+        assert_eq!(loops.partner(3), Some(2));
+        assert_eq!(loops.label(0), Some(0));
+        assert_eq!(loops.label(2), Some(1));
+        assert_eq!(loops.label(4), None);
+    }
+}
@@ -0,0 +1,590 @@
+//Hayden Coffey
+//Command line parsing for the gdd driver
+use std::path::{Path, PathBuf};
+
+use gdd::passes::{self, OptLevel};
+use gdd::toolchain::Tool;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Emit {
+    Tokens,
+    Ir,
+    //Mid-level IR from mir::lower
+    Mir,
+    //Graphviz drawing of the control-flow graph
+    Cfg,
+    Asm,
+    //Textual LLVM IR module
+    Llvm,
+    Obj,
+    Exe,
+}
+
+impl Emit {
+    fn from_name(name: &str) -> Option<Emit> {
+        match name {
+            "tokens" => Some(Emit::Tokens),
+            "ir" => Some(Emit::Ir),
+            "mir" => Some(Emit::Mir),
+            "cfg" => Some(Emit::Cfg),
+            "asm" => Some(Emit::Asm),
+            "llvm" => Some(Emit::Llvm),
+            "obj" => Some(Emit::Obj),
+            "exe" => Some(Emit::Exe),
+            _ => None,
+        }
+    }
+
+    //Extension of the default output file. Textual dumps go to stdout unless -o is given,
+    //so theirs only name the file the dump would be.
+    fn extension(self, target: Target) -> &'static str {
+        match (self, target) {
+            (Emit::Tokens, _) => "tokens",
+            (Emit::Ir, _) => "ir",
+            (Emit::Mir, _) => "mir",
+            (Emit::Cfg, _) => "dot",
+            (Emit::Asm, _) => target.asm_extension(),
+            (Emit::Llvm, _) => "ll",
+            (Emit::Exe, Target::Wasm32) => "wasm",
+            (Emit::Obj, _) => "o",
+            (Emit::Exe, _) => "",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Target {
+    X86_64,
+    Aarch64,
+    Riscv64,
+    //WebAssembly module for WASI runtimes
+    Wasm32,
+    //Portable C99 source, built with the system C compiler
+    C,
+}
+
+impl Target {
+    fn from_name(name: &str) -> Option<Target> {
+        match name {
+            "x86_64" | "x86-64" => Some(Target::X86_64),
+            "aarch64" | "arm64" => Some(Target::Aarch64),
+            "riscv64" | "riscv64gc" => Some(Target::Riscv64),
+            "wasm32" | "wasm32-wasi" | "wasm" => Some(Target::Wasm32),
+            "c" => Some(Target::C),
+            _ => None,
+        }
+    }
+
+    //Extension for generated assembly listings
+    pub fn asm_extension(self) -> &'static str {
+        match self {
+            Target::Wasm32 => "wat",
+            Target::C => "c",
+            _ => "s",
+        }
+    }
+
+    //Machine gdd itself was built for
+    fn host() -> Target {
+        if cfg!(target_arch = "aarch64") {
+            Target::Aarch64
+        } else if cfg!(target_arch = "riscv64") {
+            Target::Riscv64
+        } else {
+            Target::X86_64
+        }
+    }
+}
+
+//Who turns generated code into an executable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Assembler {
+    //Encode machine code and write the ELF file ourselves
+    Builtin,
+    External(Tool),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Input {
+    //Name used in diagnostics
+    pub fn display_name(&self) -> String {
+        match self {
+            Input::Stdin => String::from("<stdin>"),
+            Input::File(path) => path.display().to_string(),
+        }
+    }
+
+    //Base name for outputs written to the current directory
+    fn stem(&self) -> String {
+        match self {
+            Input::Stdin => String::from("a"),
+            Input::File(path) => path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_else(|| String::from("a")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Mode {
+    Build,
+    //Compile and execute, passing the given arguments along
+    Run(Vec<String>),
+    //Compile to memory and call into it from this process
+    Jit,
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub mode: Mode,
+    pub input: Input,
+    pub output: Option<PathBuf>,
+    pub emit: Emit,
+    pub target: Target,
+    pub keep_temps: bool,
+    pub assembler: Assembler,
+    pub linker: Tool,
+    //Keep ducks in registers where the goose position is known (x86_64 only)
+    pub duck_registers: bool,
+    pub opt_level: OptLevel,
+    //Passes to print the program after
+    pub print_after: Vec<String>,
+    pub time_passes: bool,
+}
+
+impl Options {
+    //Where a textual dump goes, None meaning stdout
+    pub fn dump_path(&self) -> Option<&Path> {
+        self.output.as_deref()
+    }
+
+    //Where the requested output file goes, named after the input unless -o was given
+    pub fn output_path(&self) -> PathBuf {
+        if let Some(output) = &self.output {
+            return output.clone();
+        }
+
+        let mut path = PathBuf::from(self.input.stem());
+        path.set_extension(self.emit.extension(self.target));
+
+        //Never overwrite the source with an extensionless executable
+        if let Input::File(input) = &self.input {
+            if same_file(input, &path) {
+                path.set_extension("out");
+            }
+        }
+
+        path
+    }
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+pub fn usage(program_name: &str) -> String {
+    format!(
+        "Usage: {0} [options] file.ddg
+       {0} run [options] file.ddg [-- args...]
+       {0} jit [options] file.ddg
+
+Use - as the file to read from stdin.
+
+Options:
+  -o <path>          Write output to <path>, also -o=<path> or -o<path>
+  --emit=<kind>      One of tokens, ir, mir, cfg, asm, llvm, obj, exe (default exe)
+  -S                 Stop after generating assembly, same as --emit=asm
+  --target=<arch>    Generate code for x86_64, aarch64, riscv64, wasm32 or c
+                     (default: this machine)
+  --keep-temps       Keep intermediate files next to the output
+  --duck-registers   Keep ducks in registers instead of the stack array
+                     while the goose position is known (x86_64 only)
+  -O<level>          Optimization level 0 (default), 1 or 2. -O2 implies
+                     --duck-registers on x86_64
+  --print-after=<pass>
+                     Print the program after the named pass runs
+  --time-passes      Print how long each pass took
+  --assembler=<tool> Assemble with builtin (default for x86_64 and wasm32), gcc,
+                     cc, clang or as
+  --linker=<tool>    Link with gcc (default), cc, clang or ld when the
+                     assembler is external
+  -h, --help         Print this message
+",
+        program_name
+    )
+}
+
+//Value of an option given either as --name=value or as --name value. Short options like
+//-o also take the value straight after the name, as in -opath.
+fn option_value<'a, I: Iterator<Item = &'a String>>(
+    arg: &'a str,
+    name: &str,
+    rest: &mut I,
+) -> Result<Option<&'a str>, String> {
+    if arg == name {
+        return match rest.next() {
+            None => Err(format!("Missing value for {}", name)),
+            Some(value) => Ok(Some(value)),
+        };
+    }
+
+    let value = match arg.strip_prefix(name) {
+        None => return Ok(None),
+        Some(value) => value,
+    };
+    match value.strip_prefix('=') {
+        Some(value) => Ok(Some(value)),
+        None if !name.starts_with("--") => Ok(Some(value)),
+        None => Ok(None),
+    }
+}
+
+//Driver name or the binutils program expected for this step
+fn parse_tool(name: &str, binutils: &str) -> Result<Tool, String> {
+    match Tool::from_name(name) {
+        Some(Tool::Binutils) if name != binutils => Err(format!("Unknown tool {}", name)),
+        Some(tool) => Ok(tool),
+        None => Err(format!("Unknown tool {}", name)),
+    }
+}
+
+//Parse arguments, excluding the program name. Ok(None) means help was requested.
+pub fn parse_args(args: &[String]) -> Result<Option<Options>, String> {
+    let mut args = args.iter();
+    let mut mode = Mode::Build;
+    let mut input = None;
+    let mut output = None;
+    let mut emit = Emit::Exe;
+    let mut keep_temps = false;
+    let mut duck_registers = false;
+    let mut opt_level = OptLevel::O0;
+    let mut print_after = Vec::new();
+    let mut time_passes = false;
+    let mut target = Target::host();
+    let mut assembler = None;
+    let mut linker = Tool::Gcc;
+
+    let mut first = true;
+    while let Some(arg) = args.next() {
+        if first && arg == "run" {
+            mode = Mode::Run(Vec::new());
+            first = false;
+            continue;
+        }
+        if first && arg == "jit" {
+            mode = Mode::Jit;
+            first = false;
+            continue;
+        }
+        first = false;
+
+        if arg == "--" {
+            match &mut mode {
+                Mode::Run(program_args) => program_args.extend(args.by_ref().cloned()),
+                Mode::Build | Mode::Jit => return Err(String::from("Arguments after -- need run")),
+            }
+            break;
+        }
+
+        if arg == "-h" || arg == "--help" {
+            return Ok(None);
+        } else if arg == "-S" {
+            emit = Emit::Asm;
+        } else if arg == "--keep-temps" {
+            keep_temps = true;
+        } else if arg == "--duck-registers" {
+            duck_registers = true;
+        } else if arg == "--time-passes" {
+            time_passes = true;
+        } else if let Some(level) = arg.strip_prefix("-O") {
+            opt_level =
+                OptLevel::from_name(level).ok_or(format!("Unknown optimization level {}", arg))?;
+        } else if let Some(value) = option_value(arg, "--print-after", &mut args)? {
+            if !passes::is_known(value) {
+                return Err(format!("Unknown pass {}", value));
+            }
+            print_after.push(String::from(value));
+        } else if let Some(value) = option_value(arg, "-o", &mut args)? {
+            output = Some(PathBuf::from(value));
+        } else if let Some(value) = option_value(arg, "--emit", &mut args)? {
+            emit = Emit::from_name(value).ok_or(format!("Unknown emit kind {}", value))?;
+        } else if let Some(value) = option_value(arg, "--assembler", &mut args)? {
+            assembler = match value {
+                "builtin" => Some(Assembler::Builtin),
+                value => Some(Assembler::External(parse_tool(value, "as")?)),
+            };
+        } else if let Some(value) = option_value(arg, "--target", &mut args)? {
+            target = Target::from_name(value).ok_or(format!("Unknown target {}", value))?;
+        } else if let Some(value) = option_value(arg, "--linker", &mut args)? {
+            linker = parse_tool(value, "ld")?;
+        } else if arg != "-" && arg.starts_with('-') {
+            return Err(format!("Unknown option {}", arg));
+        } else if input.is_some() {
+            return Err(format!("Unexpected argument {}", arg));
+        } else if arg == "-" {
+            input = Some(Input::Stdin);
+        } else {
+            input = Some(Input::File(PathBuf::from(arg)));
+        }
+    }
+
+    let input = input.ok_or("Missing target file.")?;
+    if let Mode::Run(_) = mode {
+        emit = Emit::Exe;
+    }
+    if matches!(mode, Mode::Jit) && target != Target::X86_64 {
+        return Err(String::from("The jit only generates x86_64 code"));
+    }
+    if duck_registers && target != Target::X86_64 {
+        return Err(String::from(
+            "--duck-registers is only supported for x86_64",
+        ));
+    }
+    let duck_registers = duck_registers || (opt_level >= OptLevel::O2 && target == Target::X86_64);
+    //The builtin assembler knows x86-64 and wasm, other targets go through gcc unless told
+    //otherwise
+    let assembler = match (assembler, target) {
+        (Some(Assembler::External(_)), Target::Wasm32) => {
+            return Err(String::from(
+                "WebAssembly modules are always written by the builtin assembler",
+            ))
+        }
+        (_, Target::Wasm32) if emit == Emit::Obj => {
+            return Err(String::from(
+                "There are no object files for wasm32, use --emit=exe",
+            ))
+        }
+        (Some(Assembler::Builtin | Assembler::External(Tool::Binutils)), Target::C) => {
+            return Err(String::from(
+                "C sources need a compiler, pick gcc, cc or clang as --assembler",
+            ))
+        }
+        (Some(Assembler::Builtin), Target::Aarch64 | Target::Riscv64) => {
+            return Err(String::from(
+                "The builtin assembler only supports x86_64 and wasm32, pick an external \
+                 --assembler",
+            ))
+        }
+        (Some(assembler), _) => assembler,
+        (None, Target::X86_64 | Target::Wasm32) => Assembler::Builtin,
+        (None, Target::C) => Assembler::External(Tool::Cc),
+        (None, _) => Assembler::External(Tool::Gcc),
+    };
+    //The generated C uses stdio, which ld alone doesn't bring in
+    if target == Target::C && linker == Tool::Binutils {
+        return Err(String::from(
+            "C programs need libc, pick gcc, cc or clang as --linker",
+        ));
+    }
+    if emit == Emit::Obj && assembler == Assembler::Builtin {
+        return Err(String::from(
+            "The builtin assembler only writes executables, pick an external --assembler \
+             for obj",
+        ));
+    }
+
+    Ok(Some(Options {
+        mode,
+        input,
+        output,
+        emit,
+        target,
+        keep_temps,
+        assembler,
+        linker,
+        duck_registers,
+        opt_level,
+        print_after,
+        time_passes,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Option<Options>, String> {
+        let args: Vec<String> = args.iter().map(|&arg| String::from(arg)).collect();
+        parse_args(&args)
+    }
+
+    fn accepted(args: &[&str]) -> Options {
+        parse(args).unwrap().unwrap()
+    }
+
+    fn rejected(args: &[&str]) -> String {
+        match parse(args) {
+            Err(why) => why,
+            Ok(options) => panic!("{:?} accepted as {:?}", args, options),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let options = accepted(&["prog.ddg"]);
+
+        assert!(matches!(options.mode, Mode::Build));
+        assert_eq!(options.input, Input::File(PathBuf::from("prog.ddg")));
+        assert_eq!(options.emit, Emit::Exe);
+        assert_eq!(options.target, Target::host());
+        assert_eq!(options.opt_level, OptLevel::O0);
+        assert_eq!(options.dump_path(), None);
+        assert_eq!(options.output_path(), PathBuf::from("prog"));
+        assert!(!options.keep_temps);
+    }
+
+    #[test]
+    fn output_path_spellings() {
+        for args in [["-o", "out.s"].as_slice(), &["-o=out.s"], &["-oout.s"]] {
+            let options = accepted(&[args, &["prog.ddg"]].concat());
+            assert_eq!(options.output_path(), PathBuf::from("out.s"), "{:?}", args);
+            assert_eq!(options.dump_path(), Some(Path::new("out.s")), "{:?}", args);
+        }
+
+        assert_eq!(rejected(&["prog.ddg", "-o"]), "Missing value for -o");
+    }
+
+    #[test]
+    fn default_outputs_follow_emit_and_target() {
+        let cases = [
+            (&["-S", "--target=aarch64"][..], Emit::Asm, "prog.s"),
+            (
+                &["--emit", "asm", "--target", "wasm32"],
+                Emit::Asm,
+                "prog.wat",
+            ),
+            (&["--emit=asm", "--target=c"], Emit::Asm, "prog.c"),
+            (&["--emit=llvm"], Emit::Llvm, "prog.ll"),
+            (&["--emit=obj", "--assembler=gcc"], Emit::Obj, "prog.o"),
+            (&["--target=wasm"], Emit::Exe, "prog.wasm"),
+        ];
+        for (args, emit, path) in cases {
+            let options = accepted(&[args, &["dir/prog.ddg"]].concat());
+            assert_eq!(options.emit, emit, "{:?}", args);
+            assert_eq!(options.output_path(), PathBuf::from(path), "{:?}", args);
+        }
+
+        for kind in ["tokens", "ir", "mir", "cfg"] {
+            let options = accepted(&[&format!("--emit={}", kind), "prog.ddg"]);
+            assert_eq!(options.dump_path(), None, "{}", kind);
+        }
+    }
+
+    #[test]
+    fn opt_levels() {
+        let options = accepted(&["-O1", "--target=x86_64", "prog.ddg"]);
+        assert_eq!(options.opt_level, OptLevel::O1);
+        assert!(!options.duck_registers);
+
+        //-O2 keeps ducks in registers only where that is supported
+        assert!(accepted(&["-O2", "--target=x86_64", "prog.ddg"]).duck_registers);
+        assert!(!accepted(&["-O2", "--target=riscv64", "prog.ddg"]).duck_registers);
+
+        let options = accepted(&["-O0", "--print-after=fold", "--time-passes", "prog.ddg"]);
+        assert_eq!(options.print_after, ["fold"]);
+        assert!(options.time_passes);
+    }
+
+    #[test]
+    fn assemblers_per_target() {
+        let cases = [
+            (&["--target=x86_64"][..], Assembler::Builtin),
+            (&["--target=wasm32"], Assembler::Builtin),
+            (&["--target=riscv64"], Assembler::External(Tool::Gcc)),
+            (&["--target=c"], Assembler::External(Tool::Cc)),
+            (
+                &["--target=aarch64", "--assembler=clang"],
+                Assembler::External(Tool::Clang),
+            ),
+            (
+                &["--target=x86_64", "--assembler", "as", "--linker=ld"],
+                Assembler::External(Tool::Binutils),
+            ),
+        ];
+        for (args, assembler) in cases {
+            let options = accepted(&[args, &["prog.ddg"]].concat());
+            assert_eq!(options.assembler, assembler, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn subcommands() {
+        let options = accepted(&["run", "-S", "prog.ddg", "--", "a", "-b"]);
+        match options.mode {
+            Mode::Run(args) => assert_eq!(args, ["a", "-b"]),
+            mode => panic!("{:?}", mode),
+        }
+        //run always builds an executable
+        assert_eq!(options.emit, Emit::Exe);
+
+        let options = accepted(&["jit", "--target=x86_64", "-"]);
+        assert!(matches!(options.mode, Mode::Jit));
+        assert_eq!(options.input, Input::Stdin);
+
+        //Only the first argument names a subcommand
+        assert_eq!(rejected(&["prog.ddg", "run"]), "Unexpected argument run");
+        assert!(parse(&["--help", "prog.ddg"]).unwrap().is_none());
+        assert!(parse(&["run", "-h"]).unwrap().is_none());
+    }
+
+    #[test]
+    fn rejected_inputs() {
+        let cases = [
+            (&[][..], "Missing target file."),
+            (&["a.ddg", "b.ddg"], "Unexpected argument b.ddg"),
+            (&["--bogus", "prog.ddg"], "Unknown option --bogus"),
+            (&["--emit=elf", "prog.ddg"], "Unknown emit kind elf"),
+            (&["--target=mips", "prog.ddg"], "Unknown target mips"),
+            (&["-O3", "prog.ddg"], "Unknown optimization level -O3"),
+            (&["--print-after=inline", "prog.ddg"], "Unknown pass inline"),
+            (&["--linker=as", "prog.ddg"], "Unknown tool as"),
+            (&["--assembler=ld", "prog.ddg"], "Unknown tool ld"),
+            (&["prog.ddg", "--", "a"], "Arguments after -- need run"),
+            (
+                &["jit", "--target=aarch64", "prog.ddg"],
+                "The jit only generates x86_64 code",
+            ),
+            (
+                &["--duck-registers", "--target=riscv64", "prog.ddg"],
+                "--duck-registers is only supported for x86_64",
+            ),
+            (
+                &["--target=wasm32", "--assembler=gcc", "prog.ddg"],
+                "WebAssembly modules are always written by the builtin assembler",
+            ),
+            (
+                &["--target=wasm32", "--emit=obj", "prog.ddg"],
+                "There are no object files for wasm32, use --emit=exe",
+            ),
+            (
+                &["--target=c", "--assembler=builtin", "prog.ddg"],
+                "C sources need a compiler, pick gcc, cc or clang as --assembler",
+            ),
+            (
+                &["--target=c", "--linker=ld", "prog.ddg"],
+                "C programs need libc, pick gcc, cc or clang as --linker",
+            ),
+            (
+                &["--target=riscv64", "--assembler=builtin", "prog.ddg"],
+                "The builtin assembler only supports x86_64 and wasm32",
+            ),
+            (
+                &["--target=x86_64", "--emit=obj", "prog.ddg"],
+                "The builtin assembler only writes executables",
+            ),
+        ];
+        for (args, why) in cases {
+            let rejected = rejected(args);
+            assert!(rejected.starts_with(why), "{:?}: {}", args, rejected);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//Hayden Coffey
+//Minimal ELF64 executable writer: a single loadable segment holding the code,
+//no sections, no dynamic linking
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+pub const EM_X86_64: u16 = 62;
+
+//Conventional load address for static x86-64 executables
+const BASE_ADDRESS: u64 = 0x400000;
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+const PHDR_COUNT: u64 = 2;
+
+const PT_LOAD: u32 = 1;
+const PT_GNU_STACK: u32 = 0x6474e551;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+
+struct ProgramHeader {
+    kind: u32,
+    flags: u32,
+    offset: u64,
+    size: u64,
+    align: u64,
+}
+
+impl ProgramHeader {
+    fn write(&self, out: &mut Vec<u8>) {
+        let address = if self.kind == PT_LOAD {
+            BASE_ADDRESS + self.offset
+        } else {
+            0
+        };
+
+        out.extend_from_slice(&self.kind.to_le_bytes());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        out.extend_from_slice(&self.offset.to_le_bytes());
+        //Virtual and physical address
+        out.extend_from_slice(&address.to_le_bytes());
+        out.extend_from_slice(&address.to_le_bytes());
+        //Size in the file and in memory
+        out.extend_from_slice(&self.size.to_le_bytes());
+        out.extend_from_slice(&self.size.to_le_bytes());
+        out.extend_from_slice(&self.align.to_le_bytes());
+    }
+}
+
+//Build an executable image that starts running at code[entry]
+pub fn executable(machine: u16, code: &[u8], entry: usize) -> Vec<u8> {
+    let code_offset = EHDR_SIZE + PHDR_SIZE * PHDR_COUNT;
+    let file_size = code_offset + code.len() as u64;
+
+    let mut out = Vec::with_capacity(file_size as usize);
+
+    //e_ident: magic, 64 bit, little endian, version 1, System V ABI
+    out.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    out.extend_from_slice(&[0; 8]);
+    //e_type (executable), e_machine, e_version
+    out.extend_from_slice(&2u16.to_le_bytes());
+    out.extend_from_slice(&machine.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    //e_entry, e_phoff, e_shoff
+    out.extend_from_slice(&(BASE_ADDRESS + code_offset + entry as u64).to_le_bytes());
+    out.extend_from_slice(&EHDR_SIZE.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes());
+    //e_flags, e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+    out.extend_from_slice(&0u32.to_le_bytes());
+    out.extend_from_slice(&(EHDR_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PHDR_SIZE as u16).to_le_bytes());
+    out.extend_from_slice(&(PHDR_COUNT as u16).to_le_bytes());
+    out.extend_from_slice(&[0; 6]);
+
+    //Map the whole file, headers included, as read + execute
+    ProgramHeader {
+        kind: PT_LOAD,
+        flags: PF_R | PF_X,
+        offset: 0,
+        size: file_size,
+        align: 0x1000,
+    }
+    .write(&mut out);
+
+    //Non-executable stack
+    ProgramHeader {
+        kind: PT_GNU_STACK,
+        flags: PF_R | PF_W,
+        offset: 0,
+        size: 0,
+        align: 16,
+    }
+    .write(&mut out);
+
+    out.extend_from_slice(code);
+    out
+}
+
+pub fn write_executable(
+    path: &Path,
+    machine: u16,
+    code: &[u8],
+    entry: usize,
+) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(&executable(machine, code, entry))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(image: &[u8], pos: usize) -> u16 {
+        u16::from_le_bytes(image[pos..pos + 2].try_into().unwrap())
+    }
+
+    fn u32_at(image: &[u8], pos: usize) -> u32 {
+        u32::from_le_bytes(image[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn u64_at(image: &[u8], pos: usize) -> u64 {
+        u64::from_le_bytes(image[pos..pos + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn header_and_segments() {
+        let code = [0x90, 0x90, 0xc3];
+        let image = executable(EM_X86_64, &code, 2);
+        let code_offset = (EHDR_SIZE + PHDR_SIZE * PHDR_COUNT) as usize;
+
+        assert_eq!(image.len(), code_offset + code.len());
+        assert_eq!(image[..7], [0x7f, b'E', b'L', b'F', 2, 1, 1]);
+        assert_eq!(u16_at(&image, 16), 2);
+        assert_eq!(u16_at(&image, 18), EM_X86_64);
+        assert_eq!(u64_at(&image, 24), BASE_ADDRESS + code_offset as u64 + 2);
+        assert_eq!(u64_at(&image, 32), EHDR_SIZE);
+        assert_eq!(u16_at(&image, 52), EHDR_SIZE as u16);
+        assert_eq!(u16_at(&image, 54), PHDR_SIZE as u16);
+        assert_eq!(u16_at(&image, 56), PHDR_COUNT as u16);
+
+        //PT_LOAD maps the whole file at the base address
+        let load = EHDR_SIZE as usize;
+        assert_eq!(u32_at(&image, load), PT_LOAD);
+        assert_eq!(u32_at(&image, load + 4), PF_R | PF_X);
+        assert_eq!(u64_at(&image, load + 8), 0);
+        assert_eq!(u64_at(&image, load + 16), BASE_ADDRESS);
+        assert_eq!(u64_at(&image, load + 24), BASE_ADDRESS);
+        assert_eq!(u64_at(&image, load + 32), image.len() as u64);
+        assert_eq!(u64_at(&image, load + 40), image.len() as u64);
+        assert_eq!(u64_at(&image, load + 48), 0x1000);
+
+        let stack = load + PHDR_SIZE as usize;
+        assert_eq!(u32_at(&image, stack), PT_GNU_STACK);
+        assert_eq!(u32_at(&image, stack + 4), PF_R | PF_W);
+
+        assert_eq!(image[code_offset..], code);
+    }
+}
@@ -1,4 +1,8 @@
 //Hayden Coffey
+use std::fmt;
+
+//Instruction codes, the number of ducks before the goose on an instruction line
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum InstructionEnum {
     End,
     Print,
@@ -14,11 +18,211 @@ pub enum InstructionEnum {
     Set,
 }
 
-#[derive(Clone, Copy)]
+impl InstructionEnum {
+    pub fn from_code(op_code: usize) -> Option<InstructionEnum> {
+        let op = match op_code {
+            0 => InstructionEnum::End,
+            1 => InstructionEnum::Print,
+            2 => InstructionEnum::Add,
+            3 => InstructionEnum::Subtract,
+            4 => InstructionEnum::Multiply,
+            5 => InstructionEnum::Divide,
+            6 => InstructionEnum::Input,
+            7 => InstructionEnum::Push,
+            8 => InstructionEnum::Pop,
+            9 => InstructionEnum::LoopBegin,
+            10 => InstructionEnum::LoopEnd,
+            11 => InstructionEnum::Set,
+            _ => return None,
+        };
+
+        Some(op)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            InstructionEnum::End => "End",
+            InstructionEnum::Print => "Print",
+            InstructionEnum::Add => "Add",
+            InstructionEnum::Subtract => "Subtract",
+            InstructionEnum::Multiply => "Multiply",
+            InstructionEnum::Divide => "Divide",
+            InstructionEnum::Input => "Input",
+            InstructionEnum::Push => "Push",
+            InstructionEnum::Pop => "Pop",
+            InstructionEnum::LoopBegin => "LoopBegin",
+            InstructionEnum::LoopEnd => "LoopEnd",
+            InstructionEnum::Set => "Set",
+        }
+    }
+}
+
+//A decoded instruction. Duck operands (n, y, cond) are positions relative to the goose.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    End,
+    Print { n: usize },
+    Add { n: usize, y: usize },
+    Subtract { n: usize, y: usize },
+    Multiply { n: usize, y: usize },
+    Divide { n: usize, y: usize },
+    Input { n: usize },
+    Push { n: usize },
+    Pop { n: usize },
+    LoopBegin { cond: usize, id: usize },
+    LoopEnd { id: usize },
+    Set { n: usize, value: usize },
+    //Goose moves to duck n without storing anything, what is left of a Set whose value
+    //is never read
+    Move { n: usize },
+    //Low byte of value to stdout, a Print of a slot known at compile time
+    PrintValue { value: usize },
+}
+
+impl Instruction {
+    //None for the instructions only optimization passes produce, which no source reads as
+    pub fn op_code(&self) -> Option<InstructionEnum> {
+        let op = match self {
+            Instruction::End => InstructionEnum::End,
+            Instruction::Print { .. } => InstructionEnum::Print,
+            Instruction::Add { .. } => InstructionEnum::Add,
+            Instruction::Subtract { .. } => InstructionEnum::Subtract,
+            Instruction::Multiply { .. } => InstructionEnum::Multiply,
+            Instruction::Divide { .. } => InstructionEnum::Divide,
+            Instruction::Input { .. } => InstructionEnum::Input,
+            Instruction::Push { .. } => InstructionEnum::Push,
+            Instruction::Pop { .. } => InstructionEnum::Pop,
+            Instruction::LoopBegin { .. } => InstructionEnum::LoopBegin,
+            Instruction::LoopEnd { .. } => InstructionEnum::LoopEnd,
+            Instruction::Set { .. } => InstructionEnum::Set,
+            Instruction::Move { .. } | Instruction::PrintValue { .. } => return None,
+        };
+
+        Some(op)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Instruction::Move { .. } => "Move",
+            Instruction::PrintValue { .. } => "PrintValue",
+            _ => self.op_code().map_or("", InstructionEnum::name),
+        }
+    }
+
+    //Duck the goose moves to after this instruction, if it moves at all
+    pub fn goose_target(&self) -> Option<usize> {
+        match *self {
+            Instruction::Add { n, .. }
+            | Instruction::Subtract { n, .. }
+            | Instruction::Multiply { n, .. }
+            | Instruction::Divide { n, .. }
+            | Instruction::Input { n }
+            | Instruction::Push { n }
+            | Instruction::Pop { n }
+            | Instruction::Set { n, .. }
+            | Instruction::Move { n } => Some(n),
+            Instruction::End
+            | Instruction::Print { .. }
+            | Instruction::PrintValue { .. }
+            | Instruction::LoopBegin { .. }
+            | Instruction::LoopEnd { .. } => None,
+        }
+    }
+
+    //Apply f to every duck operand, leaving immediates and loop ids alone
+    pub fn map_ducks<F: Fn(usize) -> usize>(self, f: F) -> Instruction {
+        match self {
+            Instruction::End => Instruction::End,
+            Instruction::Print { n } => Instruction::Print { n: f(n) },
+            Instruction::Add { n, y } => Instruction::Add { n: f(n), y: f(y) },
+            Instruction::Subtract { n, y } => Instruction::Subtract { n: f(n), y: f(y) },
+            Instruction::Multiply { n, y } => Instruction::Multiply { n: f(n), y: f(y) },
+            Instruction::Divide { n, y } => Instruction::Divide { n: f(n), y: f(y) },
+            Instruction::Input { n } => Instruction::Input { n: f(n) },
+            Instruction::Push { n } => Instruction::Push { n: f(n) },
+            Instruction::Pop { n } => Instruction::Pop { n: f(n) },
+            Instruction::LoopBegin { cond, id } => Instruction::LoopBegin { cond: f(cond), id },
+            Instruction::LoopEnd { id } => Instruction::LoopEnd { id },
+            Instruction::Set { n, value } => Instruction::Set { n: f(n), value },
+            Instruction::Move { n } => Instruction::Move { n: f(n) },
+            Instruction::PrintValue { value } => Instruction::PrintValue { value },
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::End => write!(f, "End"),
+            Instruction::Print { n }
+            | Instruction::Input { n }
+            | Instruction::Push { n }
+            | Instruction::Pop { n }
+            | Instruction::Move { n } => write!(f, "{} {}", self.name(), n),
+            Instruction::Add { n, y }
+            | Instruction::Subtract { n, y }
+            | Instruction::Multiply { n, y }
+            | Instruction::Divide { n, y } => write!(f, "{} {}, {}", self.name(), n, y),
+            Instruction::LoopBegin { cond, id } => write!(f, "LoopBegin {}, id {}", cond, id),
+            Instruction::LoopEnd { id } => write!(f, "LoopEnd id {}", id),
+            Instruction::Set { n, value } => write!(f, "Set {}, ${}", n, value),
+            Instruction::PrintValue { value } => write!(f, "PrintValue ${}", value),
+        }
+    }
+}
+
+//Location in a .ddg file: 1-based line number and the byte range covered on that line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, start: usize, end: usize) -> Span {
+        Span { line, start, end }
+    }
+
+    //1-based column of the first byte
+    pub fn column(&self) -> usize {
+        self.start + 1
+    }
+
+    //Span covering both self and other, which must be on the same line
+    pub fn to(self, other: Span) -> Span {
+        Span::new(
+            self.line,
+            self.start.min(other.start),
+            self.end.max(other.end),
+        )
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column())
+    }
+}
+
+//Parsed instruction along with where it came from
+#[derive(Clone, Copy, Debug)]
 pub struct DuckInstruction {
-    pub op_code: usize,
-    pub n: usize,
-    pub y: usize,
-    pub arg_c: usize,
-    pub goose: usize,
+    pub inst: Instruction,
+    //Instruction line (ducks and goose), followed by its operand lines
+    pub span: Span,
+    pub n_span: Option<Span>,
+    pub y_span: Option<Span>,
+}
+
+//Instruction with no source location, for programs built in code
+impl From<Instruction> for DuckInstruction {
+    fn from(inst: Instruction) -> DuckInstruction {
+        DuckInstruction {
+            inst,
+            span: Span::default(),
+            n_span: None,
+            y_span: None,
+        }
+    }
 }
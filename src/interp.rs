@@ -0,0 +1,406 @@
+//Hayden Coffey
+//Reference interpreter, mirrors the layout used by the generated code:
+//ducks and goose share a ring of duck_count + 1 slots, the teacher sits right after it
+use std::fmt;
+use std::io::{ErrorKind, Read, Write};
+
+use crate::check::{self, CheckError};
+use crate::instruction::{Instruction, Span};
+use crate::program::Program;
+
+#[derive(Debug)]
+pub enum InterpError {
+    Io(std::io::Error),
+    DivideByZero { span: Span },
+    Check(Vec<CheckError>),
+    StepLimit { steps: u64 },
+}
+
+impl fmt::Display for InterpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InterpError::Io(why) => write!(f, "{}", why),
+            InterpError::DivideByZero { span } => {
+                write!(f, "line {}: Division by zero!", span.line)
+            }
+            InterpError::Check(errors) => {
+                let messages: Vec<String> = errors.iter().map(CheckError::to_string).collect();
+                write!(f, "{}", messages.join("\n"))
+            }
+            InterpError::StepLimit { steps } => write!(f, "Step limit of {} reached!", steps),
+        }
+    }
+}
+
+impl std::error::Error for InterpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            InterpError::Io(why) => Some(why),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for InterpError {
+    fn from(why: std::io::Error) -> Self {
+        InterpError::Io(why)
+    }
+}
+
+pub struct Interpreter<'a, R: Read, W: Write> {
+    program: &'a Program,
+    //Ring slots followed by the teacher
+    ducks: Vec<u64>,
+    goose: usize,
+    pc: usize,
+    //Jump target of every loop instruction, indexed like the instruction list
+    jumps: Vec<usize>,
+    steps: u64,
+    max_steps: Option<u64>,
+    input: R,
+    output: W,
+}
+
+//Jump target of every loop instruction, from the matched loop structure
+fn loop_jumps(program: &Program) -> Result<Vec<usize>, InterpError> {
+    let loops = check::check(program).map_err(InterpError::Check)?;
+
+    let mut jumps = vec![0; program.len()];
+    for (pos, inst) in program.iter().enumerate() {
+        jumps[pos] = match (inst.inst, loops.partner(pos)) {
+            //Skip past the matching end when the condition duck is zero
+            (Instruction::LoopBegin { .. }, Some(end)) => end + 1,
+            (Instruction::LoopEnd { .. }, Some(begin)) => begin,
+            _ => continue,
+        };
+    }
+
+    Ok(jumps)
+}
+
+impl<'a, R: Read, W: Write> Interpreter<'a, R, W> {
+    pub fn new(program: &'a Program, input: R, output: W) -> Result<Self, InterpError> {
+        Ok(Interpreter {
+            program,
+            ducks: vec![0; program.ring_size() + 1],
+            goose: 0,
+            pc: 0,
+            jumps: loop_jumps(program)?,
+            steps: 0,
+            max_steps: None,
+            input,
+            output,
+        })
+    }
+
+    //Stop with an error after executing this many instructions
+    pub fn with_step_limit(mut self, max_steps: u64) -> Self {
+        self.max_steps = Some(max_steps);
+        self
+    }
+
+    //Values of the ring slots, by absolute position
+    pub fn ducks(&self) -> &[u64] {
+        &self.ducks[..self.program.ring_size()]
+    }
+
+    //Absolute position of the goose in the ring
+    pub fn goose(&self) -> usize {
+        self.goose
+    }
+
+    pub fn teacher(&self) -> u64 {
+        self.ducks[self.program.teacher_slot()]
+    }
+
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    //Absolute slot of the duck n positions after the goose
+    fn duck_index(&self, duck: usize) -> usize {
+        (self.goose + duck) % self.program.ring_size()
+    }
+
+    fn duck(&self, duck: usize) -> u64 {
+        self.ducks[self.duck_index(duck)]
+    }
+
+    //Store result in the goose slot, then move the goose to duck n
+    fn store_and_move(&mut self, value: u64, n: usize) {
+        let next = self.duck_index(n);
+        self.ducks[self.goose] = value;
+        self.goose = next;
+    }
+
+    //A read at end of input yields 0
+    fn read_byte(&mut self) -> std::io::Result<u64> {
+        self.output.flush()?;
+
+        let mut buf = [0u8; 1];
+        loop {
+            match self.input.read(&mut buf) {
+                Ok(0) => return Ok(0),
+                Ok(_) => return Ok(buf[0] as u64),
+                Err(why) if why.kind() == ErrorKind::Interrupted => continue,
+                Err(why) => return Err(why),
+            }
+        }
+    }
+
+    //Execute one instruction, returns false once the program has ended
+    pub fn step(&mut self) -> Result<bool, InterpError> {
+        let inst = match self.program.instructions().get(self.pc) {
+            None => return Ok(false),
+            Some(inst) => *inst,
+        };
+
+        if let Some(max_steps) = self.max_steps {
+            if self.steps >= max_steps {
+                return Err(InterpError::StepLimit { steps: max_steps });
+            }
+        }
+        self.steps += 1;
+        self.pc += 1;
+
+        match inst.inst {
+            Instruction::End => {
+                self.output.flush()?;
+                self.pc = self.program.len();
+                return Ok(false);
+            }
+            Instruction::Print { n } => {
+                let c = self.duck(n) as u8;
+                self.output.write_all(&[c])?;
+            }
+            Instruction::Add { n, y } => {
+                let value = self.duck(n).wrapping_add(self.duck(y));
+                self.store_and_move(value, n);
+            }
+            Instruction::Subtract { n, y } => {
+                let value = self.duck(n).wrapping_sub(self.duck(y));
+                self.store_and_move(value, n);
+            }
+            Instruction::Multiply { n, y } => {
+                let value = self.duck(n).wrapping_mul(self.duck(y));
+                self.store_and_move(value, n);
+            }
+            Instruction::Divide { n, y } => {
+                let value = match self.duck(n).checked_div(self.duck(y)) {
+                    None => return Err(InterpError::DivideByZero { span: inst.span }),
+                    Some(value) => value,
+                };
+                self.store_and_move(value, n);
+            }
+            Instruction::Input { n } => {
+                let value = self.read_byte()?;
+                self.store_and_move(value, n);
+            }
+            Instruction::Push { n } => {
+                let value = self.duck(n);
+                let teacher = self.program.teacher_slot();
+                self.ducks[teacher] = value;
+                self.store_and_move(value, n);
+            }
+            Instruction::Pop { n } => {
+                let teacher = self.program.teacher_slot();
+                let value = self.ducks[teacher];
+                self.ducks[teacher] = 0;
+                self.store_and_move(value, n);
+            }
+            Instruction::LoopBegin { cond, .. } => {
+                if self.duck(cond) == 0 {
+                    self.pc = self.jumps[self.pc - 1];
+                }
+            }
+            Instruction::LoopEnd { .. } => self.pc = self.jumps[self.pc - 1],
+            Instruction::Set { n, value } => self.store_and_move(value as u64, n),
+            Instruction::Move { n } => self.goose = self.duck_index(n),
+            Instruction::PrintValue { value } => self.output.write_all(&[value as u8])?,
+        }
+
+        Ok(true)
+    }
+
+    pub fn run(&mut self) -> Result<(), InterpError> {
+        while self.step()? {}
+        self.output.flush()?;
+
+        Ok(())
+    }
+}
+
+//Run a program to completion with the given input and output streams
+pub fn run<R: Read, W: Write>(program: &Program, input: R, output: W) -> Result<(), InterpError> {
+    Interpreter::new(program, input, output)?.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{interpret, program};
+    use std::io::Cursor;
+
+    #[test]
+    fn goose_wraps_around_the_ring() {
+        let program = program(
+            2,
+            &[
+                Instruction::Set { n: 2, value: 7 },
+                Instruction::Set { n: 2, value: 9 },
+                Instruction::Print { n: 2 },
+                Instruction::Print { n: 1 },
+            ],
+        );
+        let mut out = Vec::new();
+        let mut interp = Interpreter::new(&program, Cursor::new(""), &mut out).unwrap();
+
+        interp.step().unwrap();
+        assert_eq!(interp.goose(), 2);
+        interp.step().unwrap();
+        assert_eq!(interp.goose(), 1);
+        assert_eq!(interp.ducks(), &[7, 0, 9]);
+
+        interp.run().unwrap();
+        assert_eq!(out, [7, 9]);
+    }
+
+    #[test]
+    fn push_and_pop_go_through_the_teacher() {
+        let program = program(
+            2,
+            &[
+                Instruction::Set { n: 1, value: 65 },
+                Instruction::Push { n: 2 },
+                Instruction::Set { n: 1, value: 66 },
+                Instruction::Pop { n: 0 },
+                Instruction::Print { n: 0 },
+                Instruction::Print { n: 2 },
+            ],
+        );
+        let mut out = Vec::new();
+        let mut interp = Interpreter::new(&program, Cursor::new(""), &mut out).unwrap();
+
+        interp.step().unwrap();
+        interp.step().unwrap();
+        assert_eq!(interp.teacher(), 65);
+        assert_eq!(interp.goose(), 0);
+        assert_eq!(interp.ducks(), &[65, 65, 0]);
+
+        interp.run().unwrap();
+        assert_eq!(interp.teacher(), 0);
+        assert_eq!(out, b"AB");
+    }
+
+    #[test]
+    fn loops_test_cond_on_every_iteration() {
+        let program = program(
+            2,
+            &[
+                Instruction::Set { n: 1, value: 5 },
+                Instruction::Set { n: 2, value: 1 },
+                Instruction::LoopBegin { cond: 0, id: 0 },
+                Instruction::Print { n: 0 },
+                Instruction::Subtract { n: 0, y: 1 },
+                Instruction::LoopEnd { id: 0 },
+                Instruction::End,
+            ],
+        );
+        let mut out = Vec::new();
+        let mut interp = Interpreter::new(&program, Cursor::new(""), &mut out).unwrap();
+        interp.run().unwrap();
+
+        //Two Sets, five passes through the body, the failing test and End
+        assert_eq!(interp.steps(), 2 + 5 * 4 + 1 + 1);
+        assert_eq!(out, [5, 4, 3, 2, 1]);
+    }
+
+    #[test]
+    fn end_of_input_reads_as_zero() {
+        //cat.ddg
+        let program = program(
+            1,
+            &[
+                Instruction::Input { n: 1 },
+                Instruction::LoopBegin { cond: 1, id: 0 },
+                Instruction::Print { n: 1 },
+                Instruction::Input { n: 1 },
+                Instruction::LoopEnd { id: 0 },
+                Instruction::End,
+            ],
+        );
+
+        assert_eq!(interpret(&program, b"quack"), b"quack");
+        assert_eq!(interpret(&program, b""), b"");
+    }
+
+    #[test]
+    fn dividing_by_zero_is_an_error() {
+        let program = program(
+            1,
+            &[
+                Instruction::Set { n: 0, value: 4 },
+                Instruction::Divide { n: 0, y: 1 },
+            ],
+        );
+        let result = run(&program, Cursor::new(""), Vec::new());
+
+        assert!(matches!(result, Err(InterpError::DivideByZero { .. })));
+    }
+
+    #[test]
+    fn step_limit_stops_endless_loops() {
+        let program = program(
+            1,
+            &[
+                Instruction::Set { n: 0, value: 1 },
+                Instruction::LoopBegin { cond: 0, id: 0 },
+                Instruction::LoopEnd { id: 0 },
+            ],
+        );
+        let mut interp = Interpreter::new(&program, Cursor::new(""), Vec::new())
+            .unwrap()
+            .with_step_limit(10);
+
+        assert!(matches!(
+            interp.run(),
+            Err(InterpError::StepLimit { steps: 10 })
+        ));
+        assert_eq!(interp.steps(), 10);
+    }
+
+    #[test]
+    fn unmatched_loops_are_rejected() {
+        let program = program(1, &[Instruction::LoopEnd { id: 0 }]);
+
+        assert!(matches!(
+            Interpreter::new(&program, Cursor::new(""), Vec::new()),
+            Err(InterpError::Check(_))
+        ));
+    }
+
+    //The interpreter is the oracle: compiled examples must print what it prints
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn examples_match_the_compiled_binary() {
+        let examples = [
+            ("helloworld", ""),
+            ("cat", "Duck Duck Goose\n"),
+            ("pushpop", ""),
+            ("truth_machine", "0"),
+        ];
+        for (name, input) in examples {
+            let path = format!("{}/examples/{}.ddg", env!("CARGO_MANIFEST_DIR"), name);
+            let source = std::fs::read_to_string(&path).unwrap();
+            let program = crate::parse::parse_str(&source).unwrap();
+            let expected = interpret(&program, input.as_bytes());
+
+            let exe = crate::testing::temp_path(name);
+            crate::x86_64_enc::write_executable(&program, Default::default(), &exe).unwrap();
+            let result = crate::testing::run_executable(&exe, input.as_bytes());
+
+            assert!(result.status.success(), "{}", name);
+            assert_eq!(result.stdout, expected, "{}", name);
+        }
+    }
+}
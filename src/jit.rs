@@ -0,0 +1,228 @@
+//Hayden Coffey
+//Run programs in process: lower with x86_64_gen, encode with x86_64_enc and call the
+//result from an executable mapping, no files or child processes involved
+use std::ffi::c_void;
+use std::io::{Error, ErrorKind};
+
+use crate::program::Program;
+use crate::x86_64_enc;
+use crate::x86_64_gen::{self, Options};
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, off: i64)
+        -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+//Machine code copied into its own mapping, writable while filled and executable after
+struct ExecutableBuffer {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl ExecutableBuffer {
+    fn new(code: &[u8]) -> std::io::Result<ExecutableBuffer> {
+        //mmap rejects empty mappings
+        let len = code.len().max(1);
+
+        //Fresh anonymous mapping, nothing else refers to it
+        let ptr = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        //MAP_FAILED is (void *)-1
+        if ptr as isize == -1 {
+            return Err(Error::last_os_error());
+        }
+        let buffer = ExecutableBuffer { ptr, len };
+
+        //The mapping is at least code.len() bytes and doesn't overlap code
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), ptr as *mut u8, code.len());
+            if mprotect(ptr, len, PROT_READ | PROT_EXEC) != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+
+        Ok(buffer)
+    }
+
+    //Call into the buffer at the given offset
+    //The code there must follow the C ABI and take no arguments
+    unsafe fn call(&self, offset: usize) {
+        assert!(offset < self.len, "Entry point outside the buffer");
+        let entry: extern "C" fn() = std::mem::transmute((self.ptr as *const u8).add(offset));
+        entry();
+    }
+}
+
+impl Drop for ExecutableBuffer {
+    fn drop(&mut self) {
+        //Only this buffer owns the mapping
+        unsafe {
+            munmap(self.ptr, self.len);
+        }
+    }
+}
+
+//Compile and run the program in this process. Input and output go straight to fds 0
+//and 1, End returns here instead of exiting.
+pub fn run(program: &Program, options: Options) -> std::io::Result<()> {
+    let ops = x86_64_gen::lower_to_function(program, options)?;
+    let code = x86_64_enc::encode(&ops).map_err(|why| Error::new(ErrorKind::InvalidData, why))?;
+    let entry = code
+        .label(x86_64_gen::ENTRY_LABEL)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Missing entry point"))?;
+
+    let buffer = ExecutableBuffer::new(&code.bytes)?;
+
+    //Generated by lower_to_function, which saves what the ABI requires and returns
+    unsafe { buffer.call(entry) };
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction::{self, *};
+    use std::arch::asm;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::fd::FromRawFd;
+
+    const O_CLOEXEC: i32 = 0o2000000;
+
+    extern "C" {
+        fn pipe2(fds: *mut i32, flags: i32) -> i32;
+        fn fork() -> i32;
+        fn dup2(old: i32, new: i32) -> i32;
+        fn close(fd: i32) -> i32;
+        fn waitpid(pid: i32, status: *mut i32, options: i32) -> i32;
+        fn _exit(status: i32) -> !;
+    }
+
+    //Put in rbx, rbp and r12-r15 before the call, and expected back after it
+    const SENTINELS: [u64; 6] = [
+        0x0b0b_0b0b_0b0b_0b0b,
+        0x0505_0505_0505_0505,
+        0x1212_1212_1212_1212,
+        0x1313_1313_1313_1313,
+        0x1414_1414_1414_1414,
+        0x1515_1515_1515_1515,
+    ];
+
+    //Call the code at entry, returning whether the callee-saved registers survived
+    unsafe fn call_checked(entry: *const u8) -> bool {
+        let (rbx, rbp): (u64, u64);
+        let [_, _, mut r12, mut r13, mut r14, mut r15] = SENTINELS;
+        //rbx and rbp can't be operands, so they are set and read inside
+        asm!(
+            "push rbx",
+            "push rbp",
+            "mov rbx, {rbx_in}",
+            "mov rbp, {rbp_in}",
+            "call {entry}",
+            "mov rsi, rbx",
+            "mov rdi, rbp",
+            "pop rbp",
+            "pop rbx",
+            entry = in(reg) entry,
+            rbx_in = in(reg) SENTINELS[0],
+            rbp_in = in(reg) SENTINELS[1],
+            out("rsi") rbx,
+            out("rdi") rbp,
+            inout("r12") r12,
+            inout("r13") r13,
+            inout("r14") r14,
+            inout("r15") r15,
+            clobber_abi("C"),
+        );
+
+        [rbx, rbp, r12, r13, r14, r15] == SENTINELS
+    }
+
+    //Run program in a child process with stdout on a pipe, returning what it printed and
+    //whether the callee-saved registers survived
+    fn run_in_child(program: &Program, options: Options) -> (Vec<u8>, bool) {
+        let ops = x86_64_gen::lower_to_function(program, options).unwrap();
+        let code = x86_64_enc::encode(&ops).unwrap();
+        let entry = code.label(x86_64_gen::ENTRY_LABEL).unwrap();
+        let buffer = ExecutableBuffer::new(&code.bytes).unwrap();
+
+        let mut fds = [0; 2];
+        unsafe {
+            assert_eq!(pipe2(fds.as_mut_ptr(), O_CLOEXEC), 0);
+            let pid = fork();
+            assert!(pid >= 0);
+            if pid == 0 {
+                //Other test threads may hold locks, so nothing that allocates from here
+                dup2(fds[1], 1);
+                let preserved = call_checked((buffer.ptr as *const u8).add(entry));
+                _exit(if preserved { 0 } else { 1 });
+            }
+
+            close(fds[1]);
+            let mut printed = Vec::new();
+            File::from_raw_fd(fds[0]).read_to_end(&mut printed).unwrap();
+            let mut status = 0;
+            assert_eq!(waitpid(pid, &mut status, 0), pid);
+
+            (printed, status == 0)
+        }
+    }
+
+    #[test]
+    fn duck_registers_survive_losing_the_goose() {
+        //Ring of 4: print the ducks from a loop that turns the goose, then the teacher
+        let insts: Vec<Instruction> = vec![
+            Set {
+                n: 1,
+                value: b'T' as usize,
+            },
+            Set {
+                n: 1,
+                value: b'\n' as usize,
+            },
+            Set {
+                n: 1,
+                value: b'J' as usize,
+            },
+            Set {
+                n: 2,
+                value: b'I' as usize,
+            },
+            Push { n: 0 },
+            Set { n: 0, value: 0 },
+            LoopBegin { cond: 1, id: 0 },
+            Print { n: 1 },
+            Set { n: 1, value: 0 },
+            LoopEnd { id: 0 },
+            Pop { n: 0 },
+            Print { n: 0 },
+            End,
+        ];
+        let program = crate::testing::program(3, &insts);
+        let expected = crate::testing::interpret(&program, b"");
+        assert_eq!(expected, b"JIT\n");
+
+        for duck_registers in [false, true] {
+            let (printed, preserved) = run_in_child(&program, Options { duck_registers });
+            assert!(preserved, "{}", duck_registers);
+            assert_eq!(printed, expected, "{}", duck_registers);
+        }
+    }
+}
@@ -1,5 +1,27 @@
 //Hayden Coffey
+pub mod aarch64_gen;
+pub mod backend;
+pub mod c_gen;
+pub mod cfg;
+pub mod check;
+pub mod elf;
+pub mod instruction;
+pub mod interp;
+//Maps and calls x86-64 code, so only on hosts that can run it
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+pub mod jit;
+pub mod liveness;
+pub mod llvm_gen;
+pub mod mir;
 pub mod parse;
+pub mod passes;
+pub mod program;
+pub mod riscv64_gen;
+pub mod rotation;
+#[cfg(test)]
+mod testing;
+pub mod toolchain;
+pub mod wasm_enc;
+pub mod wasm_gen;
+pub mod x86_64_enc;
 pub mod x86_64_gen;
-
-mod instruction;
@@ -0,0 +1,319 @@
+//Hayden Coffey
+//Slot liveness over the known-goose prefix: the instructions before the first one whose
+//goose the rotation analysis can't place. Every slot touched there gets one range of
+//positions, from where it first holds a value that is still needed to where it is last
+//needed, so slots that are never live at the same time can share a register. Stores
+//to slots that are dead right after them can be dropped.
+use crate::check::Loops;
+use crate::instruction::Instruction;
+use crate::program::Program;
+use crate::rotation::{Access, Rotation};
+
+//Positions start..=end, in program order, over which slot needs a place of its own
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LiveRange {
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+    //Whether the slot is stored to within the range, leaving the array copy stale
+    pub written: bool,
+}
+
+//Number of instructions before the first one with an unknown goose. Once the goose is
+//lost it stays lost, so everything from there on is outside the prefix.
+pub fn known_prefix(program: &Program, rotation: &Rotation) -> usize {
+    (0..program.len())
+        .find(|&pos| rotation.goose(pos).is_none())
+        .unwrap_or(program.len())
+}
+
+//Instructions control may go to after the one at pos
+fn successors(program: &Program, loops: &Loops, pos: usize) -> Vec<usize> {
+    match program.instructions()[pos].inst {
+        Instruction::End => Vec::new(),
+        //Into the body, or past the LoopEnd when the test reads 0
+        Instruction::LoopBegin { .. } => match loops.partner(pos) {
+            Some(end) => vec![pos + 1, end + 1],
+            None => vec![pos + 1],
+        },
+        //Back to the test
+        Instruction::LoopEnd { .. } => loops.partner(pos).into_iter().collect(),
+        _ => vec![pos + 1],
+    }
+}
+
+//Position of the LoopEnd closing the outermost loop around each instruction, or the
+//instruction itself outside loops. Every path through the program passes that point.
+fn outermost_ends(program: &Program, loops: &Loops) -> Vec<usize> {
+    let mut ends = Vec::with_capacity(program.len());
+    let mut depth = 0usize;
+    let mut end = 0;
+    for (pos, inst) in program.iter().enumerate() {
+        if let Instruction::LoopBegin { .. } = inst.inst {
+            if depth == 0 {
+                end = loops.partner(pos).unwrap_or(pos);
+            }
+            depth += 1;
+        }
+        ends.push(if depth == 0 { pos } else { end });
+        if let Instruction::LoopEnd { .. } = inst.inst {
+            depth = depth.saturating_sub(1);
+        }
+    }
+
+    ends
+}
+
+//Slot liveness over the known prefix. Sets are over the slots the prefix touches, numbered
+//in order of first access.
+struct Liveness {
+    //Code follows the prefix and may read any slot
+    tail: bool,
+    accesses: Vec<Access>,
+    slots: Vec<usize>,
+    dense: Vec<Option<usize>>,
+    //Slots live on entry to each instruction
+    live_in: Vec<Vec<bool>>,
+}
+
+impl Liveness {
+    fn solve(program: &Program, loops: &Loops, rotation: &Rotation) -> Liveness {
+        let prefix = known_prefix(program, rotation);
+        let accesses: Vec<Access> = (0..prefix)
+            .map(|pos| rotation.access(program, pos).unwrap_or_default())
+            .collect();
+
+        let mut slots = Vec::new();
+        let mut dense = vec![None; program.ring_size() + 1];
+        for access in &accesses {
+            for &slot in access.reads.iter().chain(&access.writes) {
+                if dense[slot].is_none() {
+                    dense[slot] = Some(slots.len());
+                    slots.push(slot);
+                }
+            }
+        }
+
+        let mut liveness = Liveness {
+            tail: prefix < program.len(),
+            live_in: vec![vec![false; slots.len()]; prefix],
+            accesses,
+            slots,
+            dense,
+        };
+
+        //A LoopEnd jumps back, so sweep backwards until nothing changes
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for pos in (0..prefix).rev() {
+                let mut live = liveness.live_out(program, loops, pos);
+                for &slot in &liveness.accesses[pos].writes {
+                    live[liveness.dense(slot)] = false;
+                }
+                for &slot in &liveness.accesses[pos].reads {
+                    live[liveness.dense(slot)] = true;
+                }
+
+                if live != liveness.live_in[pos] {
+                    liveness.live_in[pos] = live;
+                    changed = true;
+                }
+            }
+        }
+
+        liveness
+    }
+
+    fn dense(&self, slot: usize) -> usize {
+        self.dense[slot].unwrap_or_default()
+    }
+
+    //Slots live after the instruction at pos
+    fn live_out(&self, program: &Program, loops: &Loops, pos: usize) -> Vec<bool> {
+        let mut live = vec![false; self.slots.len()];
+        for next in successors(program, loops, pos) {
+            match self.live_in.get(next) {
+                Some(next_live) => {
+                    for (slot, next_live) in live.iter_mut().zip(next_live) {
+                        *slot |= *next_live;
+                    }
+                }
+                None if self.tail => live.fill(true),
+                None => (),
+            }
+        }
+
+        live
+    }
+}
+
+//Whether each instruction only stores values that are never read, overwritten or left
+//behind by End on every path. False outside the known prefix.
+pub fn dead_stores(program: &Program, loops: &Loops, rotation: &Rotation) -> Vec<bool> {
+    let liveness = Liveness::solve(program, loops, rotation);
+
+    let mut dead = vec![false; program.len()];
+    for (pos, access) in liveness.accesses.iter().enumerate() {
+        let live = liveness.live_out(program, loops, pos);
+        dead[pos] = !access.writes.is_empty()
+            && access
+                .writes
+                .iter()
+                .all(|&slot| !live[liveness.dense(slot)]);
+    }
+
+    dead
+}
+
+//Live range of every slot accessed in the known prefix, ordered by start. When code
+//follows the prefix it reads every slot from the array, so a range then lasts until
+//the instruction after its last access that every path goes through, where the register
+//can be stored back, even if a loop that accessed the slot never ran.
+pub fn live_ranges(program: &Program, loops: &Loops, rotation: &Rotation) -> Vec<LiveRange> {
+    let Liveness {
+        tail,
+        accesses,
+        slots,
+        dense,
+        live_in,
+    } = Liveness::solve(program, loops, rotation);
+    let dense = |slot: usize| dense[slot].unwrap_or_default();
+
+    let mut ranges: Vec<Option<LiveRange>> = vec![None; slots.len()];
+    let mut extend = |index: usize, pos: usize, written: bool| {
+        let range = ranges[index].get_or_insert(LiveRange {
+            slot: slots[index],
+            start: pos,
+            end: pos,
+            written: false,
+        });
+        range.end = pos;
+        range.written |= written;
+    };
+    for (pos, access) in accesses.iter().enumerate() {
+        for (index, live) in live_in[pos].iter().enumerate() {
+            if *live {
+                extend(index, pos, false);
+            }
+        }
+        for &slot in &access.reads {
+            extend(dense(slot), pos, false);
+        }
+        for &slot in &access.writes {
+            extend(dense(slot), pos, true);
+        }
+    }
+
+    let mut ranges: Vec<LiveRange> = ranges.into_iter().flatten().collect();
+    if tail {
+        //Live until the tail, but only in the array once no longer accessed
+        let mut last_access = vec![0; slots.len()];
+        for (pos, access) in accesses.iter().enumerate() {
+            for &slot in access.reads.iter().chain(&access.writes) {
+                last_access[dense(slot)] = pos;
+            }
+        }
+        let ends = outermost_ends(program, loops);
+        for range in &mut ranges {
+            range.end = ends[last_access[dense(range.slot)]];
+        }
+    }
+    ranges.sort_by_key(|range| (range.start, range.slot));
+
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check;
+    use crate::instruction::Instruction::*;
+    use crate::rotation;
+    use crate::testing::program;
+
+    //Two ducks, so a ring of 3 with the teacher at 3
+    fn ranges(insts: &[Instruction]) -> Vec<LiveRange> {
+        let program = program(2, insts);
+        let loops = check::check(&program).unwrap();
+        let rotation = rotation::analyze(&program, &loops);
+        live_ranges(&program, &loops, &rotation)
+    }
+
+    fn range(slot: usize, start: usize, end: usize, written: bool) -> LiveRange {
+        LiveRange {
+            slot,
+            start,
+            end,
+            written,
+        }
+    }
+
+    #[test]
+    fn ranges_end_at_the_last_read() {
+        let ranges = ranges(&[
+            Set { n: 1, value: 5 },
+            Print { n: 2 },
+            Set { n: 1, value: 7 },
+            Print { n: 2 },
+            End,
+        ]);
+
+        //Slot 0 is dead before slot 1 is first written
+        assert_eq!(ranges, [range(0, 0, 1, true), range(1, 2, 3, true)]);
+    }
+
+    #[test]
+    fn slots_read_before_written_are_live_from_the_start() {
+        let ranges = ranges(&[Pop { n: 0 }, Print { n: 0 }, End]);
+
+        assert_eq!(ranges, [range(0, 0, 1, true), range(3, 0, 0, true)]);
+    }
+
+    #[test]
+    fn values_carried_around_a_loop_cover_all_of_it() {
+        let ranges = ranges(&[
+            Set { n: 1, value: 3 },
+            Set { n: 2, value: 1 },
+            LoopBegin { cond: 0, id: 0 },
+            Subtract { n: 0, y: 1 },
+            LoopEnd { id: 0 },
+            End,
+        ]);
+
+        assert_eq!(ranges, [range(0, 0, 4, true), range(1, 1, 4, true)]);
+    }
+
+    #[test]
+    fn ranges_last_past_loops_when_the_goose_is_lost_later() {
+        let ranges = ranges(&[
+            Set { n: 1, value: 1 },
+            LoopBegin { cond: 2, id: 0 },
+            Set { n: 2, value: 4 },
+            Set { n: 1, value: 0 },
+            LoopEnd { id: 0 },
+            //Turns the goose one place each time round
+            LoopBegin { cond: 0, id: 1 },
+            Set { n: 1, value: 0 },
+            LoopEnd { id: 1 },
+            End,
+        ]);
+
+        //Slot 1 is only written in a loop that may never run, so it has to hold the
+        //initial 0 from the start for the code after the first loop to find
+        assert_eq!(ranges, [range(0, 0, 4, true), range(1, 0, 4, true)]);
+    }
+
+    #[test]
+    fn nothing_is_live_where_the_goose_is_unknown() {
+        let ranges = ranges(&[
+            LoopBegin { cond: 0, id: 0 },
+            Set { n: 1, value: 0 },
+            LoopEnd { id: 0 },
+            Print { n: 0 },
+            End,
+        ]);
+
+        assert!(ranges.is_empty());
+    }
+}
@@ -0,0 +1,464 @@
+//Hayden Coffey
+//LLVM IR backend. Writes a textual .ll module for opt/llc: the duck array is an alloca'd
+//array, the goose a local index, and Print/Input call write/read. Duck indices are
+//computed without a division so LLVM can fold them once the goose is known.
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use crate::backend::{self, Backend, LoopLabels};
+use crate::instruction::DuckInstruction;
+use crate::program::Program;
+
+const SIGFPE: usize = 8;
+
+static DIVIDE_BY_ZERO_LABEL: &str = "divide_by_zero";
+
+static DECLARATIONS: &str = "declare i64 @read(i32, ptr, i64)
+declare i64 @write(i32, ptr, i64)
+declare i32 @raise(i32)
+declare void @exit(i32) noreturn
+declare void @abort() noreturn
+declare void @llvm.memset.p0.i64(ptr, i8, i64, i1)
+";
+
+//Lowering state for main, SSA values and blocks need unique names
+pub struct Llvm<W: Write> {
+    file: W,
+    ring_size: usize,
+    //Type of the duck array, teacher included
+    array_type: String,
+    next_value: usize,
+    next_block: usize,
+}
+
+impl<W: Write> Llvm<W> {
+    pub fn new(file: W) -> Llvm<W> {
+        Llvm {
+            file,
+            ring_size: 0,
+            array_type: String::new(),
+            next_value: 0,
+            next_block: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.file
+    }
+
+    fn value(&mut self) -> String {
+        self.next_value += 1;
+        format!("%v{}", self.next_value)
+    }
+
+    fn block(&mut self, name: &str) -> String {
+        self.next_block += 1;
+        format!("{}{}", name, self.next_block)
+    }
+
+    fn line(&mut self, text: &str) -> std::io::Result<()> {
+        writeln!(self.file, "  {}", text)
+    }
+
+    fn label(&mut self, name: &str) -> std::io::Result<()> {
+        writeln!(self.file, "{}:", name)
+    }
+
+    //Array index of the duck n places after the goose. The offset is reduced at compile
+    //time, leaving an add and a conditional subtract instead of urem.
+    fn duck_index(&mut self, n: usize) -> std::io::Result<String> {
+        let goose = self.value();
+        self.line(&format!("{} = load i64, ptr %goose", goose))?;
+
+        let offset = n % self.ring_size;
+        if offset == 0 {
+            return Ok(goose);
+        }
+
+        let sum = self.value();
+        let wraps = self.value();
+        let wrapped = self.value();
+        let index = self.value();
+        self.line(&format!("{} = add nuw i64 {}, {}", sum, goose, offset))?;
+        self.line(&format!(
+            "{} = icmp uge i64 {}, {}",
+            wraps, sum, self.ring_size
+        ))?;
+        self.line(&format!(
+            "{} = sub i64 {}, {}",
+            wrapped, sum, self.ring_size
+        ))?;
+        self.line(&format!(
+            "{} = select i1 {}, i64 {}, i64 {}",
+            index, wraps, wrapped, sum
+        ))?;
+
+        Ok(index)
+    }
+
+    //Pointer to the array slot at the given index (a value or a constant)
+    fn slot(&mut self, index: &str) -> std::io::Result<String> {
+        let ptr = self.value();
+        self.line(&format!(
+            "{} = getelementptr inbounds {}, ptr %ducks, i64 0, i64 {}",
+            ptr, self.array_type, index
+        ))?;
+
+        Ok(ptr)
+    }
+
+    fn load_slot(&mut self, index: &str) -> std::io::Result<String> {
+        let ptr = self.slot(index)?;
+        let value = self.value();
+        self.line(&format!("{} = load i64, ptr {}", value, ptr))?;
+
+        Ok(value)
+    }
+
+    fn store_slot(&mut self, value: &str, index: &str) -> std::io::Result<()> {
+        let ptr = self.slot(index)?;
+        self.line(&format!("store i64 {}, ptr {}", value, ptr))
+    }
+
+    fn teacher(&self) -> String {
+        self.ring_size.to_string()
+    }
+
+    //Store value in the goose, then move the goose to target
+    fn store_result(&mut self, value: &str, target: &str) -> std::io::Result<()> {
+        let goose = self.value();
+        self.line(&format!("{} = load i64, ptr %goose", goose))?;
+        self.store_slot(value, &goose)?;
+        self.line(&format!("store i64 {}, ptr %goose", target))
+    }
+
+    fn write_header(&mut self, slots: usize) -> std::io::Result<()> {
+        writeln!(self.file, "define i32 @main() {{")?;
+        self.label("entry")?;
+        self.line(&format!("%ducks = alloca {}", self.array_type))?;
+        self.line("%goose = alloca i64")?;
+        self.line("%buffer = alloca i8")?;
+        self.line(&format!(
+            "call void @llvm.memset.p0.i64(ptr %ducks, i8 0, i64 {}, i1 false)",
+            slots * 8
+        ))?;
+        self.line("store i64 0, ptr %goose")
+    }
+
+    //Ends the current block, code after End goes in a block of its own
+    fn write_exit(&mut self) -> std::io::Result<()> {
+        self.line("call void @exit(i32 0)")?;
+        self.line("unreachable")?;
+        let after = self.block("after_exit");
+        self.label(&after)
+    }
+
+    fn write_binary(&mut self, op: &str, n: usize, y: usize) -> std::io::Result<()> {
+        let target = self.duck_index(n)?;
+        let y_index = self.duck_index(y)?;
+        let lhs = self.load_slot(&target)?;
+        let rhs = self.load_slot(&y_index)?;
+
+        //udiv by zero is undefined, check first and raise SIGFPE like divq
+        if op == "udiv" {
+            let ok = self.block("divide_ok");
+            let is_zero = self.value();
+            self.line(&format!("{} = icmp eq i64 {}, 0", is_zero, rhs))?;
+            self.line(&format!(
+                "br i1 {}, label %{}, label %{}",
+                is_zero, DIVIDE_BY_ZERO_LABEL, ok
+            ))?;
+            self.label(&ok)?;
+        }
+
+        let result = self.value();
+        self.line(&format!("{} = {} i64 {}, {}", result, op, lhs, rhs))?;
+        self.store_result(&result, &target)
+    }
+
+    fn write_input(&mut self, n: usize) -> std::io::Result<()> {
+        //End of input reads as 0
+        self.line("store i8 0, ptr %buffer")?;
+        let read = self.value();
+        self.line(&format!(
+            "{} = call i64 @read(i32 0, ptr %buffer, i64 1)",
+            read
+        ))?;
+
+        let byte = self.value();
+        let value = self.value();
+        self.line(&format!("{} = load i8, ptr %buffer", byte))?;
+        self.line(&format!("{} = zext i8 {} to i64", value, byte))?;
+
+        let target = self.duck_index(n)?;
+        self.store_result(&value, &target)
+    }
+
+    fn write_print(&mut self, n: usize) -> std::io::Result<()> {
+        let index = self.duck_index(n)?;
+        let value = self.load_slot(&index)?;
+        self.write_char(&value)
+    }
+
+    //Low byte of value (a value or a constant) to stdout
+    fn write_char(&mut self, value: &str) -> std::io::Result<()> {
+        let byte = self.value();
+        self.line(&format!("{} = trunc i64 {} to i8", byte, value))?;
+        self.line(&format!("store i8 {}, ptr %buffer", byte))?;
+
+        let written = self.value();
+        self.line(&format!(
+            "{} = call i64 @write(i32 1, ptr %buffer, i64 1)",
+            written
+        ))
+    }
+
+    fn write_push(&mut self, n: usize) -> std::io::Result<()> {
+        let target = self.duck_index(n)?;
+        let value = self.load_slot(&target)?;
+
+        let teacher = self.teacher();
+        self.store_slot(&value, &teacher)?;
+        self.store_result(&value, &target)
+    }
+
+    fn write_pop(&mut self, n: usize) -> std::io::Result<()> {
+        let teacher = self.teacher();
+        let value = self.load_slot(&teacher)?;
+        self.store_slot("0", &teacher)?;
+
+        let target = self.duck_index(n)?;
+        self.store_result(&value, &target)
+    }
+
+    fn write_set(&mut self, n: usize, value: usize) -> std::io::Result<()> {
+        let target = self.duck_index(n)?;
+        self.store_result(&value.to_string(), &target)
+    }
+
+    fn write_move(&mut self, n: usize) -> std::io::Result<()> {
+        let target = self.duck_index(n)?;
+        self.line(&format!("store i64 {}, ptr %goose", target))
+    }
+}
+
+impl<W: Write> Backend for Llvm<W> {
+    fn prologue(&mut self, program: &Program) -> std::io::Result<()> {
+        if let Some(name) = program.source_name() {
+            writeln!(self.file, "; ModuleID = '{}'", name)?;
+        }
+        writeln!(self.file, "{}", DECLARATIONS)?;
+
+        //Ducks, goose and teacher like the other backends
+        let slots = program.duck_count() + 3;
+        self.ring_size = program.ring_size();
+        self.array_type = format!("[{} x i64]", slots);
+        self.write_header(slots)
+    }
+
+    fn epilogue(&mut self) -> std::io::Result<()> {
+        self.line("ret i32 0")?;
+
+        //Shared by every Divide
+        self.label(DIVIDE_BY_ZERO_LABEL)?;
+        let raised = self.value();
+        self.line(&format!("{} = call i32 @raise(i32 {})", raised, SIGFPE))?;
+        self.line("call void @abort()")?;
+        self.line("unreachable")?;
+
+        writeln!(self.file, "}}")
+    }
+
+    fn comment(&mut self, inst: &DuckInstruction) -> std::io::Result<()> {
+        writeln!(self.file, "  ; {}", inst.inst)
+    }
+
+    fn end(&mut self) -> std::io::Result<()> {
+        self.write_exit()
+    }
+
+    fn print(&mut self, n: usize) -> std::io::Result<()> {
+        self.write_print(n)
+    }
+
+    fn add(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        self.write_binary("add", n, y)
+    }
+
+    fn subtract(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        self.write_binary("sub", n, y)
+    }
+
+    fn multiply(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        self.write_binary("mul", n, y)
+    }
+
+    fn divide(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        self.write_binary("udiv", n, y)
+    }
+
+    fn input(&mut self, n: usize) -> std::io::Result<()> {
+        self.write_input(n)
+    }
+
+    fn push(&mut self, n: usize) -> std::io::Result<()> {
+        self.write_push(n)
+    }
+
+    fn pop(&mut self, n: usize) -> std::io::Result<()> {
+        self.write_pop(n)
+    }
+
+    fn loop_begin(&mut self, cond: usize, _id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        self.line(&format!("br label %{}", labels.start))?;
+        self.label(&labels.start)?;
+
+        let index = self.duck_index(cond)?;
+        let value = self.load_slot(&index)?;
+        let taken = self.value();
+        let body = self.block("loop_body");
+        self.line(&format!("{} = icmp ne i64 {}, 0", taken, value))?;
+        self.line(&format!(
+            "br i1 {}, label %{}, label %{}",
+            taken, body, labels.end
+        ))?;
+        self.label(&body)
+    }
+
+    fn loop_end(&mut self, _id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        self.line(&format!("br label %{}", labels.start))?;
+        self.label(&labels.end)
+    }
+
+    fn set(&mut self, n: usize, value: usize) -> std::io::Result<()> {
+        self.write_set(n, value)
+    }
+
+    fn move_goose(&mut self, n: usize) -> std::io::Result<()> {
+        self.write_move(n)
+    }
+
+    fn print_value(&mut self, value: usize) -> std::io::Result<()> {
+        self.write_char(&value.to_string())
+    }
+}
+
+//Write program as a textual LLVM IR module to out
+pub fn lower_to_writer(program: &Program, out: &mut impl Write) -> std::io::Result<()> {
+    backend::lower(program, &mut Llvm::new(out))
+}
+
+//Program as a textual LLVM IR module in a string
+pub fn lower_to_string(program: &Program) -> std::io::Result<String> {
+    let mut out = Vec::new();
+    lower_to_writer(program, &mut out)?;
+
+    backend::into_text(out)
+}
+
+//Write program as a textual LLVM IR module
+pub fn lower_program(program: &Program, file_out_name: &str) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(file_out_name)?);
+    lower_to_writer(program, &mut file)?;
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+    use std::process::Command;
+
+    //LLVM 14 reads the ptr type only when asked, later versions always do
+    fn llc_flags() -> Vec<&'static str> {
+        let output = Command::new("llc").arg("--version").output().unwrap();
+        let version = String::from_utf8_lossy(&output.stdout);
+        let major = version
+            .split("LLVM version ")
+            .nth(1)
+            .and_then(|rest| rest.split('.').next())
+            .and_then(|major| major.parse::<u32>().ok())
+            .unwrap_or(u32::MAX);
+
+        let mut flags = vec!["-relocation-model=pic"];
+        if major <= 14 {
+            flags.push("-opaque-pointers");
+        }
+        flags
+    }
+
+    //Compile the module for program with llc, link it with cc and run it on input
+    fn run(test: &str, program: &Program, input: &[u8]) -> std::process::Output {
+        let ll_path = testing::temp_path(&format!("{}.ll", test));
+        let asm_path = testing::temp_path(&format!("{}.s", test));
+        let exe = testing::temp_path(test);
+        lower_program(program, &ll_path.to_string_lossy()).unwrap();
+
+        let status = Command::new("llc")
+            .args(llc_flags())
+            .arg(&ll_path)
+            .arg("-o")
+            .arg(&asm_path)
+            .status()
+            .unwrap();
+        std::fs::remove_file(&ll_path).unwrap();
+        assert!(status.success(), "llc failed on {}", test);
+        let status = Command::new("cc")
+            .arg(&asm_path)
+            .arg("-o")
+            .arg(&exe)
+            .status()
+            .unwrap();
+        std::fs::remove_file(&asm_path).unwrap();
+        assert!(status.success(), "cc failed on {}", test);
+
+        testing::run_executable(&exe, input)
+    }
+
+    #[test]
+    fn duck_indices_avoid_urem() {
+        let ll = lower_to_string(&testing::every_instruction()).unwrap();
+
+        assert!(!ll.contains("urem"), "{}", ll);
+        assert!(ll.contains("define i32 @main() {\n"), "{}", ll);
+        //Ring of 3, then the teacher and a spare slot
+        assert!(ll.contains("%ducks = alloca [5 x i64]"), "{}", ll);
+    }
+
+    #[test]
+    fn divides_share_one_trap_block() {
+        let ll = lower_to_string(&testing::divides_by_zero()).unwrap();
+
+        assert_eq!(ll.matches("\ndivide_by_zero:\n").count(), 1, "{}", ll);
+        assert!(ll.contains("call i32 @raise(i32 8)"), "{}", ll);
+        assert!(ll.contains(", label %divide_by_zero, label %"), "{}", ll);
+    }
+
+    //The interpreter is the oracle, when llc is there to build the module
+    #[test]
+    fn modules_match_the_interpreter() {
+        if !testing::have_tool("llc", "modules_match_the_interpreter")
+            || !testing::have_tool("cc", "modules_match_the_interpreter")
+        {
+            return;
+        }
+
+        let (input, printed) = testing::EVERY_INSTRUCTION_IO;
+        let program = testing::every_instruction();
+        let output = run("llvm_every_instruction", &program, input);
+        assert!(output.status.success());
+        assert_eq!(output.stdout, testing::interpret(&program, input));
+        assert_eq!(output.stdout, printed);
+
+        let cat = crate::parse::parse_str(include_str!("../examples/cat.ddg")).unwrap();
+        let output = run("llvm_cat", &cat, b"quack");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"quack");
+
+        let output = run("llvm_divides_by_zero", &testing::divides_by_zero(), b"");
+        assert!(!output.status.success());
+        assert_eq!(output.stdout, b"A");
+    }
+}
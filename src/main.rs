@@ -1,54 +1,274 @@
 //Hayden Coffey
+mod cli;
+
 use std::env;
-use std::fs::File;
-use std::io::BufReader;
-use std::io::{Error, ErrorKind};
-use std::path::Path;
-use std::process::Command;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Error, Read, Write};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use gdd::{parse, x86_64_gen};
+use cli::{Assembler, Emit, Input, Mode, Options, Target};
+use gdd::program::Program;
+use gdd::toolchain::Tool;
+use gdd::{
+    aarch64_gen, c_gen, cfg, check, llvm_gen, mir, parse, passes, riscv64_gen, toolchain, wasm_enc,
+    wasm_gen, x86_64_enc, x86_64_gen,
+};
 
-fn get_file_name(path: &str) -> &str {
-    let pos_end = path.find(".ddg").unwrap();
-    let pos_begin = path.rfind("/").unwrap() + 1;
+fn read_source(input: &Input) -> Result<String, Error> {
+    let mut source = String::new();
+    match input {
+        Input::Stdin => io::stdin().read_to_string(&mut source)?,
+        Input::File(path) => File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut source))
+            .map_err(|why| Error::new(why.kind(), format!("{}: {}", path.display(), why)))?,
+    };
 
-    &path[pos_begin..pos_end]
+    Ok(source)
 }
 
-fn main() -> Result<(), Error> {
-    //Get input file path
-    let args: Vec<String> = env::args().collect();
-    if args.len() == 1 {
-        println!("Usage: {} file.duck", args[0]);
+//Parse and check the source, printing diagnostics and exiting on failure
+fn load_program(options: &Options, source: &str) -> Program {
+    let name = options.input.display_name();
+    match parse::parse_str(source) {
+        Err(why) => {
+            eprint!("{}", why.render(&name));
+            process::exit(1);
+        }
+        Ok(program) => {
+            let program = program.with_source_name(&name);
+            if let Err(errors) = check::check(&program) {
+                for why in errors {
+                    eprint!("{}", why.render(&name, source));
+                }
+                process::exit(1);
+            }
+            program
+        }
+    }
+}
+
+//Run the pipeline for the -O level, dumping and timing passes to stderr as asked
+fn optimize(options: &Options, mut program: Program) -> Result<Program, Error> {
+    let mut passes = options.opt_level.pipeline();
+    for name in &options.print_after {
+        passes.print_after(name);
+    }
+
+    let timings = passes.run(&mut program, &mut io::stderr())?;
+    if options.time_passes {
+        eprint!("{}", passes::timing_report(&timings));
+    }
+
+    Ok(program)
+}
+
+//x86-64 code generation choices from the command line
+fn x86_64_options(options: &Options) -> x86_64_gen::Options {
+    x86_64_gen::Options {
+        duck_registers: options.duck_registers,
+    }
+}
+
+//Lower the program to assembly for the target at asm_path
+fn write_asm(program: &Program, options: &Options, asm_path: &Path) -> Result<(), Error> {
+    let asm_name = asm_path.to_string_lossy();
+    match options.target {
+        Target::X86_64 => File::create(asm_path).and_then(|file| {
+            let mut file = BufWriter::new(file);
+            x86_64_gen::lower_to_writer_with(program, x86_64_options(options), &mut file)?;
+            file.flush()
+        }),
+        Target::Aarch64 => aarch64_gen::lower_program(program, &asm_name),
+        Target::Riscv64 => riscv64_gen::lower_program(program, &asm_name),
+        Target::Wasm32 => wasm_gen::lower_program(program, &asm_name),
+        Target::C => c_gen::lower_program(program, &asm_name),
+    }
+    .map_err(|why| Error::new(why.kind(), format!("{}: {}", asm_path.display(), why)))
+}
+
+//Write a textual dump to the output path, or stdout if there is none
+fn write_text(path: Option<&Path>, text: &str) -> Result<(), Error> {
+    match path {
+        None => io::stdout().write_all(text.as_bytes()),
+        Some(path) => fs::write(path, text),
+    }
+}
+
+//Assemble (and for executables, link) the assembly at asm_path with an external tool
+fn assemble(options: &Options, tool: Tool, asm_path: &Path, out_path: &Path) -> Result<(), Error> {
+    if options.emit == Emit::Obj {
+        return toolchain::assemble(tool, asm_path, out_path).map_err(Error::other);
+    }
+
+    //Object file lives next to the assembly, so it is kept or cleaned up along with it
+    let obj_path = asm_path.with_extension("o");
+    toolchain::assemble(tool, asm_path, &obj_path).map_err(Error::other)?;
+    toolchain::link(options.linker, &obj_path, out_path).map_err(Error::other)
+}
+
+//Write the executable directly, no external tools involved
+fn write_executable(program: &Program, options: &Options, out_path: &Path) -> Result<(), Error> {
+    match options.target {
+        Target::Wasm32 => wasm_enc::write_module(program, out_path),
+        _ => x86_64_enc::write_executable(program, x86_64_options(options), out_path),
+    }
+    .map_err(|why| Error::new(why.kind(), format!("{}: {}", out_path.display(), why)))
+}
+
+//Fresh directory under the system temp dir for intermediate files
+fn make_temp_dir() -> Result<PathBuf, Error> {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    let dir = env::temp_dir().join(format!("gdd-{}-{}", process::id(), nanos));
+    fs::create_dir_all(&dir)?;
+
+    Ok(dir)
+}
 
-        return Err(Error::new(ErrorKind::InvalidInput, "Missing target file."));
+//Lower to assembly and assemble into out_path, keeping the .s next to it if asked
+fn compile(program: &Program, options: &Options, out_path: &Path) -> Result<(), Error> {
+    let tool = match options.assembler {
+        Assembler::External(tool) => tool,
+        Assembler::Builtin => {
+            //Nothing intermediate is needed, but keep the listing for reference if asked
+            if options.keep_temps {
+                let asm_path = out_path.with_extension(options.target.asm_extension());
+                write_asm(program, options, &asm_path)?;
+            }
+            return write_executable(program, options, out_path);
+        }
+    };
+
+    if options.keep_temps {
+        let asm_path = out_path.with_extension(options.target.asm_extension());
+        write_asm(program, options, &asm_path)?;
+        return assemble(options, tool, &asm_path, out_path);
     }
 
-    //Open file and init reader
-    let path = Path::new(&args[1]);
+    let dir = make_temp_dir()?;
+    let asm_path = dir
+        .join("out")
+        .with_extension(options.target.asm_extension());
+    let result = write_asm(program, options, &asm_path)
+        .and_then(|_| assemble(options, tool, &asm_path, out_path));
+    fs::remove_dir_all(&dir)?;
+
+    result
+}
+
+fn build(options: &Options) -> Result<(), Error> {
+    let source = read_source(&options.input)?;
+    //Tokens are dumped without checking the program
+    let program = || optimize(options, load_program(options, &source));
+
+    match options.emit {
+        Emit::Tokens => {
+            let tokens = parse::tokenize(&mut source.as_bytes())
+                .map_err(|why| Error::new(io::ErrorKind::InvalidData, why.to_string()))?;
+            let text: String = tokens.iter().map(|t| format!("{}\n", t)).collect();
+            write_text(options.dump_path(), &text)
+        }
+        Emit::Ir => write_text(options.dump_path(), &program()?.to_string()),
+        Emit::Mir => write_text(options.dump_path(), &mir::lower(&program()?)?.to_string()),
+        Emit::Cfg => {
+            let program = program()?;
+            let loops = check::check(&program).map_err(check::into_io_error)?;
+            write_text(
+                options.dump_path(),
+                &cfg::build(&program, &loops).to_dot(&program),
+            )
+        }
+        Emit::Asm => write_asm(&program()?, options, &options.output_path()),
+        Emit::Llvm => {
+            let out_path = options.output_path();
+            llvm_gen::lower_program(&program()?, &out_path.to_string_lossy())
+                .map_err(|why| Error::new(why.kind(), format!("{}: {}", out_path.display(), why)))
+        }
+        Emit::Obj | Emit::Exe => compile(&program()?, options, &options.output_path()),
+    }
+}
+
+//Compile into a temp dir, execute with inherited stdio and exit with the program's status
+fn run(options: &Options, args: &[String]) -> Result<(), Error> {
+    let source = read_source(&options.input)?;
+    let program = optimize(options, load_program(options, &source))?;
 
-    let file_name_base = get_file_name(&args[1]);
+    let dir = make_temp_dir()?;
+    let exe_path = match (&options.output, options.target) {
+        (None, Target::Wasm32) => dir.join("a.wasm"),
+        (None, _) => dir.join("a.out"),
+        (Some(path), _) => path.clone(),
+    };
+    let result = compile(&program, options, &exe_path).and_then(|_| match options.target {
+        //Modules need a runtime to execute them
+        Target::Wasm32 => Command::new("wasmtime")
+            .arg(&exe_path)
+            .args(args)
+            .status()
+            .map_err(|why| Error::new(why.kind(), format!("wasmtime: {}", why))),
+        _ => Command::new(&exe_path).args(args).status(),
+    });
+
+    if options.keep_temps {
+        eprintln!("gdd: kept temporary files in {}", dir.display());
+    } else {
+        fs::remove_dir_all(&dir)?;
+    }
 
-    let file = match File::open(&path) {
-        Err(why) => panic!("Couldn't open {}: {}", path.display(), why),
-        Ok(file) => file,
+    let status = result?;
+    //Mirror the shell convention for programs killed by a signal
+    let code = match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
     };
-    let mut reader = BufReader::new(file);
+    process::exit(code);
+}
 
-    //Parse input file and create IR
-    let parse_results = parse::parse_file(&mut reader);
+//Compile into an executable mapping and call it, End returns here
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn jit(options: &Options) -> Result<(), Error> {
+    let source = read_source(&options.input)?;
+    let program = optimize(options, load_program(options, &source))?;
 
-    //Transform IR to x86_64
-    let file_name_asm = format!("{}.s", file_name_base);
-    x86_64_gen::lower_program(&parse_results, &file_name_asm)?;
+    gdd::jit::run(&program, x86_64_options(options))
+}
 
-    //Kinda cheating and using gcc to assemble for now
-    //Also, this is probably a security vulnerability
-    let output_arg = format!("-o{}", file_name_base);
-    Command::new("gcc")
-        .args([file_name_asm, String::from("-g"), output_arg])
-        .spawn()
-        .expect("Failed to assemble program.");
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+fn jit(_options: &Options) -> Result<(), Error> {
+    Err(Error::other("The jit needs an x86_64 Linux host"))
+}
 
-    Ok(())
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let program_name = args.first().map(String::as_str).unwrap_or("gdd");
+
+    let options = match cli::parse_args(&args[1..]) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            print!("{}", cli::usage(program_name));
+            return;
+        }
+        Err(why) => {
+            eprintln!("{}: {}", program_name, why);
+            eprint!("{}", cli::usage(program_name));
+            process::exit(2);
+        }
+    };
+
+    let result = match &options.mode {
+        Mode::Build => build(&options),
+        Mode::Run(args) => run(&options, args),
+        Mode::Jit => jit(&options),
+    };
+
+    if let Err(why) = result {
+        eprintln!("{}: {}", program_name, why);
+        process::exit(1);
+    }
 }
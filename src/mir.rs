@@ -0,0 +1,580 @@
+//Hayden Coffey
+//Mid-level IR. Every instruction is spelled out as the primitive steps the backends
+//perform: work out a slot index, load and store slots, compute, move the goose and make
+//syscalls. Values are numbered temporaries, each assigned once and used only within the
+//instruction that computes them. The x86-64 backend generates its stack array code from
+//these ops, and --emit=mir prints them.
+use std::fmt;
+
+use crate::backend::{self, Backend, LoopLabels};
+use crate::program::Program;
+
+//Temporary holding a 64 bit value
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Value(pub usize);
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    //Unsigned, dividing by zero ends the program with SIGFPE
+    Div,
+}
+
+impl BinOp {
+    pub fn name(self) -> &'static str {
+        match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Const {
+        dst: Value,
+        value: u64,
+    },
+    //Array index of the duck n places after the goose, (goose + n) % ring size
+    DuckIndex {
+        dst: Value,
+        n: usize,
+    },
+    //Array index of the goose
+    GooseIndex {
+        dst: Value,
+    },
+    Load {
+        dst: Value,
+        index: Value,
+    },
+    Store {
+        index: Value,
+        value: Value,
+    },
+    Binary {
+        op: BinOp,
+        dst: Value,
+        lhs: Value,
+        rhs: Value,
+    },
+    //Move the goose to the given array index
+    SetGoose {
+        index: Value,
+    },
+    //Next byte of stdin, 0 at end of input
+    Read {
+        dst: Value,
+    },
+    //Low byte of value to stdout
+    Write {
+        value: Value,
+    },
+    Exit,
+    Label(String),
+    Jump(String),
+    JumpIfZero {
+        value: Value,
+        label: String,
+    },
+}
+
+impl Op {
+    //Value this op assigns, if any
+    pub fn dst(&self) -> Option<Value> {
+        match *self {
+            Op::Const { dst, .. }
+            | Op::DuckIndex { dst, .. }
+            | Op::GooseIndex { dst }
+            | Op::Load { dst, .. }
+            | Op::Binary { dst, .. }
+            | Op::Read { dst } => Some(dst),
+            _ => None,
+        }
+    }
+
+    //Values this op reads
+    pub fn uses(&self) -> Vec<Value> {
+        match *self {
+            Op::Load { index, .. } | Op::SetGoose { index } => vec![index],
+            Op::Store { index, value } => vec![index, value],
+            Op::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Op::Write { value } | Op::JumpIfZero { value, .. } => vec![value],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Op::Const { dst, value } => write!(f, "  {} = const {}", dst, value),
+            Op::DuckIndex { dst, n } => write!(f, "  {} = duck_index {}", dst, n),
+            Op::GooseIndex { dst } => write!(f, "  {} = goose_index", dst),
+            Op::Load { dst, index } => write!(f, "  {} = load [{}]", dst, index),
+            Op::Store { index, value } => write!(f, "  store [{}], {}", index, value),
+            Op::Binary { op, dst, lhs, rhs } => {
+                write!(f, "  {} = {} {}, {}", dst, op.name(), lhs, rhs)
+            }
+            Op::SetGoose { index } => write!(f, "  set_goose {}", index),
+            Op::Read { dst } => write!(f, "  {} = read", dst),
+            Op::Write { value } => write!(f, "  write {}", value),
+            Op::Exit => write!(f, "  exit"),
+            Op::Label(name) => write!(f, "{}:", name),
+            Op::Jump(label) => write!(f, "  jump {}", label),
+            Op::JumpIfZero { value, label } => write!(f, "  jump_if_zero {}, {}", value, label),
+        }
+    }
+}
+
+//Lowered program. The array holds the ring slots, then the teacher, all starting at 0
+//with the goose at index 0.
+#[derive(Clone, Debug)]
+pub struct Function {
+    pub duck_count: usize,
+    pub ops: Vec<Op>,
+    value_count: usize,
+}
+
+impl Function {
+    pub fn ring_size(&self) -> usize {
+        self.duck_count + 1
+    }
+
+    //Array index of the teacher
+    pub fn teacher(&self) -> usize {
+        self.ring_size()
+    }
+
+    //Number of values assigned, every Value is below this
+    pub fn value_count(&self) -> usize {
+        self.value_count
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "#{} duck(s), teacher at {}",
+            self.duck_count,
+            self.teacher()
+        )?;
+        for op in &self.ops {
+            writeln!(f, "{}", op)?;
+        }
+
+        Ok(())
+    }
+}
+
+//Builds a Function through the shared lowering driver. Where the rotation analysis knows
+//the goose, indices are emitted as constants instead of DuckIndex/GooseIndex.
+pub struct Builder {
+    function: Function,
+    goose: Option<usize>,
+}
+
+impl Builder {
+    pub fn new() -> Builder {
+        Builder {
+            function: Function {
+                duck_count: 0,
+                ops: Vec::new(),
+                value_count: 0,
+            },
+            goose: None,
+        }
+    }
+
+    pub fn into_function(self) -> Function {
+        self.function
+    }
+
+    //Ops built so far, leaving the function empty. Value numbers keep counting up.
+    pub fn take_ops(&mut self) -> Vec<Op> {
+        std::mem::take(&mut self.function.ops)
+    }
+
+    fn value(&mut self) -> Value {
+        self.function.value_count += 1;
+        Value(self.function.value_count - 1)
+    }
+
+    fn op(&mut self, op: Op) {
+        self.function.ops.push(op);
+    }
+
+    fn constant(&mut self, value: u64) -> Value {
+        let dst = self.value();
+        self.op(Op::Const { dst, value });
+        dst
+    }
+
+    fn duck_index(&mut self, n: usize) -> Value {
+        let ring_size = self.function.ring_size();
+        match self.goose {
+            Some(goose) => self.constant(((goose + n % ring_size) % ring_size) as u64),
+            None => {
+                let dst = self.value();
+                self.op(Op::DuckIndex { dst, n });
+                dst
+            }
+        }
+    }
+
+    fn load(&mut self, index: Value) -> Value {
+        let dst = self.value();
+        self.op(Op::Load { dst, index });
+        dst
+    }
+
+    //Store value in the goose, then move the goose to target
+    fn store_result(&mut self, value: Value, target: Value) {
+        let goose = match self.goose {
+            Some(goose) => self.constant(goose as u64),
+            None => {
+                let dst = self.value();
+                self.op(Op::GooseIndex { dst });
+                dst
+            }
+        };
+        self.op(Op::Store {
+            index: goose,
+            value,
+        });
+        self.op(Op::SetGoose { index: target });
+    }
+
+    fn binary(&mut self, op: BinOp, n: usize, y: usize) {
+        let target = self.duck_index(n);
+        let y = self.duck_index(y);
+        let lhs = self.load(target);
+        let rhs = self.load(y);
+
+        let dst = self.value();
+        self.op(Op::Binary { op, dst, lhs, rhs });
+        self.store_result(dst, target);
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+impl Backend for Builder {
+    fn prologue(&mut self, program: &Program) -> std::io::Result<()> {
+        self.function.duck_count = program.duck_count();
+        Ok(())
+    }
+
+    fn epilogue(&mut self) -> std::io::Result<()> {
+        self.op(Op::Exit);
+        Ok(())
+    }
+
+    fn known_goose(&mut self, goose: Option<usize>) -> std::io::Result<()> {
+        self.goose = goose;
+        Ok(())
+    }
+
+    fn end(&mut self) -> std::io::Result<()> {
+        self.op(Op::Exit);
+        Ok(())
+    }
+
+    fn print(&mut self, n: usize) -> std::io::Result<()> {
+        let index = self.duck_index(n);
+        let value = self.load(index);
+        self.op(Op::Write { value });
+        Ok(())
+    }
+
+    fn add(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        self.binary(BinOp::Add, n, y);
+        Ok(())
+    }
+
+    fn subtract(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        self.binary(BinOp::Sub, n, y);
+        Ok(())
+    }
+
+    fn multiply(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        self.binary(BinOp::Mul, n, y);
+        Ok(())
+    }
+
+    fn divide(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        self.binary(BinOp::Div, n, y);
+        Ok(())
+    }
+
+    fn input(&mut self, n: usize) -> std::io::Result<()> {
+        let dst = self.value();
+        self.op(Op::Read { dst });
+        let target = self.duck_index(n);
+        self.store_result(dst, target);
+        Ok(())
+    }
+
+    //The duck's value goes to both the teacher and the goose
+    fn push(&mut self, n: usize) -> std::io::Result<()> {
+        let target = self.duck_index(n);
+        let value = self.load(target);
+        let teacher = self.constant(self.function.teacher() as u64);
+        self.op(Op::Store {
+            index: teacher,
+            value,
+        });
+        self.store_result(value, target);
+        Ok(())
+    }
+
+    //The teacher's value goes to the goose and the teacher is cleared
+    fn pop(&mut self, n: usize) -> std::io::Result<()> {
+        let teacher = self.constant(self.function.teacher() as u64);
+        let value = self.load(teacher);
+        let zero = self.constant(0);
+        self.op(Op::Store {
+            index: teacher,
+            value: zero,
+        });
+        let target = self.duck_index(n);
+        self.store_result(value, target);
+        Ok(())
+    }
+
+    fn loop_begin(&mut self, cond: usize, _id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        self.op(Op::Label(labels.start.clone()));
+        let index = self.duck_index(cond);
+        let value = self.load(index);
+        self.op(Op::JumpIfZero {
+            value,
+            label: labels.end.clone(),
+        });
+        Ok(())
+    }
+
+    fn loop_end(&mut self, _id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        self.op(Op::Jump(labels.start.clone()));
+        self.op(Op::Label(labels.end.clone()));
+        Ok(())
+    }
+
+    fn set(&mut self, n: usize, value: usize) -> std::io::Result<()> {
+        let target = self.duck_index(n);
+        let value = self.constant(value as u64);
+        self.store_result(value, target);
+        Ok(())
+    }
+
+    fn move_goose(&mut self, n: usize) -> std::io::Result<()> {
+        let target = self.duck_index(n);
+        self.op(Op::SetGoose { index: target });
+        Ok(())
+    }
+
+    fn print_value(&mut self, value: usize) -> std::io::Result<()> {
+        let value = self.constant(value as u64);
+        self.op(Op::Write { value });
+        Ok(())
+    }
+}
+
+//Lower program to the mid-level IR
+//Fails with InvalidData if the program doesn't pass check::check
+pub fn lower(program: &Program) -> std::io::Result<Function> {
+    let mut builder = Builder::new();
+    backend::lower(program, &mut builder)?;
+
+    Ok(builder.into_function())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction::*;
+    use crate::testing::{self, program};
+
+    #[test]
+    fn known_goose_indices_are_constants() {
+        //Ring of 3: Set stores at 0 and moves the goose to 1, Add then reads 0 and 1
+        let function = lower(&program(
+            2,
+            &[Set { n: 1, value: 7 }, Add { n: 2, y: 0 }, End],
+        ))
+        .unwrap();
+        let v = Value;
+
+        assert_eq!(
+            function.ops,
+            [
+                Op::Const {
+                    dst: v(0),
+                    value: 1
+                },
+                Op::Const {
+                    dst: v(1),
+                    value: 7
+                },
+                Op::Const {
+                    dst: v(2),
+                    value: 0
+                },
+                Op::Store {
+                    index: v(2),
+                    value: v(1)
+                },
+                Op::SetGoose { index: v(0) },
+                Op::Const {
+                    dst: v(3),
+                    value: 0
+                },
+                Op::Const {
+                    dst: v(4),
+                    value: 1
+                },
+                Op::Load {
+                    dst: v(5),
+                    index: v(3)
+                },
+                Op::Load {
+                    dst: v(6),
+                    index: v(4)
+                },
+                Op::Binary {
+                    op: BinOp::Add,
+                    dst: v(7),
+                    lhs: v(5),
+                    rhs: v(6)
+                },
+                Op::Const {
+                    dst: v(8),
+                    value: 1
+                },
+                Op::Store {
+                    index: v(8),
+                    value: v(7)
+                },
+                Op::SetGoose { index: v(3) },
+                //End, then the epilogue
+                Op::Exit,
+                Op::Exit,
+            ]
+        );
+        assert_eq!(function.value_count(), 9);
+    }
+
+    #[test]
+    fn unknown_goose_indices_are_worked_out_at_run_time() {
+        //The loop turns the goose a place each time round, so it is lost from its test on
+        let function = lower(&program(
+            2,
+            &[
+                LoopBegin { cond: 0, id: 0 },
+                Set { n: 1, value: 0 },
+                LoopEnd { id: 0 },
+                Print { n: 1 },
+                End,
+            ],
+        ))
+        .unwrap();
+
+        assert_eq!(
+            function.to_string(),
+            concat!(
+                "#2 duck(s), teacher at 3\n",
+                "start_0:\n",
+                "  %0 = duck_index 0\n",
+                "  %1 = load [%0]\n",
+                "  jump_if_zero %1, end_0\n",
+                "  %2 = duck_index 1\n",
+                "  %3 = const 0\n",
+                "  %4 = goose_index\n",
+                "  store [%4], %3\n",
+                "  set_goose %2\n",
+                "  jump start_0\n",
+                "end_0:\n",
+                "  %5 = duck_index 1\n",
+                "  %6 = load [%5]\n",
+                "  write %6\n",
+                "  exit\n",
+                "  exit\n"
+            )
+        );
+    }
+
+    #[test]
+    fn teacher_follows_the_ring() {
+        let function = lower(&program(2, &[Push { n: 1 }, Pop { n: 2 }, End])).unwrap();
+
+        assert_eq!(function.teacher(), 3);
+        let teacher_stores = function
+            .ops
+            .iter()
+            .filter(|op| matches!(op, Op::Const { value: 3, .. }))
+            .count();
+        assert_eq!(teacher_stores, 2);
+    }
+
+    //Every value is assigned once and only used after that, within its instruction
+    #[test]
+    fn values_are_assigned_before_use() {
+        let function = lower(&testing::every_instruction()).unwrap();
+
+        let mut assigned = vec![false; function.value_count()];
+        for op in &function.ops {
+            for value in op.uses() {
+                assert!(assigned[value.0], "{} used before it is assigned", value);
+            }
+            if let Some(dst) = op.dst() {
+                assert!(!assigned[dst.0], "{} assigned twice", dst);
+                assigned[dst.0] = true;
+            }
+        }
+        assert!(assigned.iter().all(|&assigned| assigned));
+    }
+
+    #[test]
+    fn taken_ops_keep_value_numbers_counting() {
+        let mut builder = Builder::new();
+        builder.prologue(&program(1, &[End])).unwrap();
+        builder.print_value(b'A' as usize).unwrap();
+        let first = builder.take_ops();
+        builder.print_value(b'B' as usize).unwrap();
+
+        assert_eq!(
+            first,
+            [
+                Op::Const {
+                    dst: Value(0),
+                    value: 65
+                },
+                Op::Write { value: Value(0) }
+            ]
+        );
+        assert_eq!(
+            builder.into_function().ops,
+            [
+                Op::Const {
+                    dst: Value(1),
+                    value: 66
+                },
+                Op::Write { value: Value(1) }
+            ]
+        );
+    }
+}
@@ -1,174 +1,358 @@
 //Hayden Coffey
-use std::fs::File;
+use std::fmt;
 use std::io::BufRead;
-use std::io::BufReader;
-
-use crate::instruction::{DuckInstruction, InstructionEnum};
-
-pub fn get_op_name(op_code: usize) -> String {
-    match op_code {
-        x if x == InstructionEnum::End as usize => String::from("End"),
-        x if x == InstructionEnum::Print as usize => String::from("Print"),
-        x if x == InstructionEnum::Add as usize => String::from("Add"),
-        x if x == InstructionEnum::Subtract as usize => String::from("Subtract"),
-        x if x == InstructionEnum::Multiply as usize => String::from("Multiply"),
-        x if x == InstructionEnum::Divide as usize => String::from("Divide"),
-        x if x == InstructionEnum::Input as usize => String::from("Input"),
-        x if x == InstructionEnum::Push as usize => String::from("Push"),
-        x if x == InstructionEnum::Pop as usize => String::from("Pop"),
-        x if x == InstructionEnum::LoopBegin as usize => String::from("LoopBegin"),
-        x if x == InstructionEnum::LoopEnd as usize => String::from("LoopEnd"),
-        x if x == InstructionEnum::Set as usize => String::from("Set"),
-        x => panic!("Unhandled instruction code {}", x),
+
+use crate::instruction::{DuckInstruction, Instruction, InstructionEnum, Span};
+use crate::program::{Metadata, Program};
+
+//Offending source line attached to a parse error, used to render diagnostics
+#[derive(Debug, Clone)]
+pub struct Snippet {
+    pub span: Span,
+    pub text: String,
+}
+
+impl Snippet {
+    //Cut the line containing span out of the full program source
+    pub fn from_source(source: &str, span: Span) -> Snippet {
+        let line = source
+            .lines()
+            .nth(span.line.saturating_sub(1))
+            .unwrap_or("");
+        snippet(line, span)
+    }
+
+    //Location and source line with a caret under the span, rustc style
+    pub fn render(&self, file_name: &str) -> String {
+        let line_no = self.span.line.to_string();
+        let pad = " ".repeat(line_no.len());
+
+        //Keep tabs so the caret lines up with the source line
+        let start = self.span.start.min(self.text.len());
+        let end = self.span.end.clamp(start, self.text.len());
+        let indent: String = self.text[..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = self.text[start..end].chars().count().max(1);
+
+        let mut out = format!(
+            "{}--> {}:{}:{}\n",
+            pad,
+            file_name,
+            self.span.line,
+            self.span.column()
+        );
+        out.push_str(&format!("{} |\n", pad));
+        out.push_str(&format!("{} | {}\n", line_no, self.text));
+        out.push_str(&format!("{} | {}{}\n", pad, indent, "^".repeat(width)));
+
+        out
+    }
+}
+
+//Everything that can go wrong while reading a .ddg file
+#[derive(Debug)]
+pub enum ParseError {
+    Io(std::io::Error),
+    MissingHeader,
+    MultipleGeese(Snippet),
+    DuckAfterGoose(Snippet),
+    MissingGoose(Snippet),
+    MissingOperand { op: InstructionEnum, at: Snippet },
+    UnknownInstruction { op_code: usize, at: Snippet },
+    MissingEnd(Option<Snippet>),
+}
+
+impl ParseError {
+    //Error message without location information
+    pub fn message(&self) -> String {
+        match self {
+            ParseError::Io(why) => why.to_string(),
+            ParseError::MissingHeader => String::from("Missing header, no goose found!"),
+            ParseError::MultipleGeese(_) => String::from("There can only be one goose!"),
+            ParseError::DuckAfterGoose(_) => String::from("Duck after goose!"),
+            ParseError::MissingGoose(_) => String::from("Missing goose!"),
+            ParseError::MissingOperand { op, .. } => {
+                format!("Mismatched argument count! Inst: {}", op.name())
+            }
+            ParseError::UnknownInstruction { op_code, .. } => {
+                format!("Unhandled instruction code {}", op_code)
+            }
+            ParseError::MissingEnd(_) => String::from("Program does not end with goose!"),
+        }
+    }
+
+    pub fn snippet(&self) -> Option<&Snippet> {
+        match self {
+            ParseError::MultipleGeese(at)
+            | ParseError::DuckAfterGoose(at)
+            | ParseError::MissingGoose(at)
+            | ParseError::MissingOperand { at, .. }
+            | ParseError::UnknownInstruction { at, .. } => Some(at),
+            ParseError::MissingEnd(at) => at.as_ref(),
+            ParseError::Io(_) | ParseError::MissingHeader => None,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.snippet().map(|at| at.span)
+    }
+
+    //Render a rustc style diagnostic with a caret under the offending duck or goose
+    pub fn render(&self, file_name: &str) -> String {
+        let location = match self.snippet() {
+            None => format!(" --> {}\n", file_name),
+            Some(at) => at.render(file_name),
+        };
+
+        format!("error: {}\n{}", self.message(), location)
     }
 }
 
-#[cfg(DEBUG)]
-fn print_parse(parse_results: &(usize, Vec<DuckInstruction>)) {
-    println!("There are {} duck(s).", parse_results.0);
-
-    for inst in &parse_results.1 {
-        let inst_name = get_op_name(inst.op_code);
-        let goose = inst.goose;
-        match inst.arg_c {
-            0 => println!("g: {} -> {}:", goose, inst_name),
-            1 => println!("g: {} -> {}: {}", goose, inst_name, inst.n),
-            2 => println!("g: {} -> {}: {},{}", goose, inst_name, inst.n, inst.y),
-            _ => panic!("Invalid argument size!"),
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.span() {
+            None => write!(f, "{}", self.message()),
+            Some(span) => write!(f, "line {}: {}", span.line, self.message()),
         }
     }
 }
 
-fn get_counts(line: &str) -> (usize, usize) {
-    let c_pos = line.find('#');
+impl std::error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ParseError::Io(why) => Some(why),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ParseError {
+    fn from(why: std::io::Error) -> Self {
+        ParseError::Io(why)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenKind {
+    Duck,
+    Goose,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let word = match self.kind {
+            TokenKind::Duck => "duck",
+            TokenKind::Goose => "goose",
+        };
+        write!(f, "{} {}", self.span, word)
+    }
+}
 
-    let line = if c_pos == None {
-        line
-    } else {
-        &line[..c_pos.unwrap()]
+//Ducks and geese on a line in source order, ignoring anything after a comment
+fn tokenize_line(line: &str, line_no: usize) -> Vec<Token> {
+    let line = match line.find('#') {
+        None => line,
+        Some(c_pos) => &line[..c_pos],
     };
 
-    let duck_count = line.matches("duck").count();
-    let goose_count = line.matches("goose").count();
+    let ducks = line.match_indices("duck").map(|m| (TokenKind::Duck, m));
+    let geese = line.match_indices("goose").map(|m| (TokenKind::Goose, m));
+
+    let mut tokens: Vec<Token> = ducks
+        .chain(geese)
+        .map(|(kind, (pos, word))| Token {
+            kind,
+            span: Span::new(line_no, pos, pos + word.len()),
+        })
+        .collect();
+    tokens.sort_by_key(|t| t.span.start);
+
+    tokens
+}
+
+//Every duck and goose in the input, without checking that they form a valid program
+pub fn tokenize<R: BufRead>(reader: &mut R) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    for (line_no, line) in reader.lines().enumerate() {
+        tokens.extend(tokenize_line(&line?, line_no + 1));
+    }
+
+    Ok(tokens)
+}
+
+fn get_counts(tokens: &[Token]) -> (usize, usize) {
+    let duck_count = tokens.iter().filter(|t| t.kind == TokenKind::Duck).count();
+    let goose_count = tokens.len() - duck_count;
 
     (duck_count, goose_count)
 }
 
-fn check_line(line: &str, goose_count: usize) {
-    if goose_count > 1 {
-        panic!("There can only be one goose!");
+//Span from the first to the last token of a line, or an empty span at column 1
+fn covering_span(tokens: &[Token], line_no: usize) -> Span {
+    match (tokens.first(), tokens.last()) {
+        (Some(first), Some(last)) => first.span.to(last.span),
+        _ => Span::new(line_no, 0, 0),
+    }
+}
+
+fn snippet(line: &str, span: Span) -> Snippet {
+    Snippet {
+        span,
+        text: String::from(line.trim_end_matches(['\n', '\r'])),
     }
+}
 
-    let goose_pos = line.find("goose").unwrap();
-    let duck_pos = match line.rfind("duck") {
-        None => 0,
-        Some(pos) => pos,
+fn check_line(line: &str, tokens: &[Token]) -> Result<(), ParseError> {
+    let mut geese = tokens.iter().filter(|t| t.kind == TokenKind::Goose);
+    let goose = match geese.next() {
+        None => return Ok(()),
+        Some(goose) => goose.span,
     };
 
-    if duck_pos > goose_pos {
-        panic!("Duck after goose!");
+    if let Some(extra) = geese.next() {
+        return Err(ParseError::MultipleGeese(snippet(line, extra.span)));
+    }
+
+    if let Some(duck) = tokens.iter().find(|t| t.span.start > goose.start) {
+        return Err(ParseError::DuckAfterGoose(snippet(line, duck.span)));
     }
+
+    Ok(())
 }
 
-fn parse_header_line(line: &str) -> (usize, usize) {
-    let counts = get_counts(line);
+fn parse_header_line(line: &str, line_no: usize) -> Result<(usize, usize), ParseError> {
+    let tokens = tokenize_line(line, line_no);
+    let counts = get_counts(&tokens);
 
     if counts.1 != 0 {
-        check_line(line, counts.1);
+        check_line(line, &tokens)?;
     } else if counts.0 != 0 {
-        panic!("Missing goose!");
+        let span = covering_span(&tokens, line_no);
+        return Err(ParseError::MissingGoose(snippet(line, span)));
     }
 
-    counts
+    Ok(counts)
 }
 
-fn parse_header(reader: &mut BufReader<File>) -> (usize, usize) {
+//Returns the duck count, along with the comments preceding the header and its location
+fn parse_header<R: BufRead>(reader: &mut R) -> Result<(usize, Metadata), ParseError> {
+    let mut metadata = Metadata::default();
     loop {
         let mut s = String::new();
-        match reader.read_line(&mut s) {
-            Err(why) => panic!("{}", why),
-            Ok(_) => (),
-        };
+        if reader.read_line(&mut s)? == 0 {
+            return Err(ParseError::MissingHeader);
+        }
+        metadata.line_count += 1;
 
-        let counts = parse_header_line(&s);
+        let counts = parse_header_line(&s, metadata.line_count)?;
         if counts.1 != 0 {
-            return counts;
+            let tokens = tokenize_line(&s, metadata.line_count);
+            metadata.header = covering_span(&tokens, metadata.line_count);
+            return Ok((counts.0, metadata));
+        }
+
+        if let Some(c_pos) = s.find('#') {
+            metadata
+                .description
+                .push(String::from(s[c_pos + 1..].trim()));
         }
     }
 }
 
-fn parse_binary_inst(inst: usize, operands: &mut Vec<usize>) -> DuckInstruction {
-    let y = match operands.pop() {
-        None => panic!("Mismatched argument count! Inst: {}", inst),
-        Some(op) => op,
-    };
-    let n = match operands.pop() {
-        None => panic!("Mismatched argument count! Inst: {}", inst),
-        Some(op) => op,
-    };
+//Instruction line currently being parsed, consumes the operand lines preceding it
+struct InstLine<'a> {
+    op: InstructionEnum,
+    text: &'a str,
+    span: Span,
+}
+
+//Operand value and the span of the ducks on its line
+type Operand = (usize, Option<Span>);
 
-    DuckInstruction {
-        op_code: inst,
-        n,
-        y,
-        arg_c: 2,
-        goose: 0,
+impl InstLine<'_> {
+    fn instruction(&self, inst: Instruction, n: Option<Span>, y: Option<Span>) -> DuckInstruction {
+        DuckInstruction {
+            inst,
+            span: self.span,
+            n_span: n,
+            y_span: y,
+        }
     }
-}
 
-fn parse_unary_inst(inst: usize, operands: &mut Vec<usize>) -> DuckInstruction {
-    let n = match operands.pop() {
-        None => panic!("Mismatched argument count! Inst: {}", inst),
-        Some(op) => op,
-    };
+    fn pop_operand(&self, operands: &mut Vec<Operand>) -> Result<Operand, ParseError> {
+        operands.pop().ok_or_else(|| ParseError::MissingOperand {
+            op: self.op,
+            at: snippet(self.text, self.span),
+        })
+    }
+
+    fn unary<F: Fn(usize) -> Instruction>(
+        &self,
+        operands: &mut Vec<Operand>,
+        build: F,
+    ) -> Result<DuckInstruction, ParseError> {
+        let n = self.pop_operand(operands)?;
 
-    DuckInstruction {
-        op_code: inst,
-        n,
-        y: 0,
-        arg_c: 1,
-        goose: 0,
+        Ok(self.instruction(build(n.0), n.1, None))
     }
-}
 
-fn parse_loop_inst(inst: usize, operands: &mut Vec<usize>) -> DuckInstruction {
-    if inst == InstructionEnum::LoopBegin as usize {
-        let y = match operands.pop() {
-            None => 0,
-            Some(op) => op,
-        };
-        let n = match operands.pop() {
-            None => panic!("Mismatched loop argument count! Inst: {}", inst),
-            Some(op) => op,
-        };
+    fn binary<F: Fn(usize, usize) -> Instruction>(
+        &self,
+        operands: &mut Vec<Operand>,
+        build: F,
+    ) -> Result<DuckInstruction, ParseError> {
+        let y = self.pop_operand(operands)?;
+        let n = self.pop_operand(operands)?;
 
-        DuckInstruction {
-            op_code: inst,
-            n,
-            y,
-            arg_c: 2,
-            goose: 0,
-        }
-    } else {
-        let n = match operands.pop() {
-            None => 0,
-            Some(op) => op,
-        };
-        DuckInstruction {
-            op_code: inst,
-            n,
-            y: 0,
-            arg_c: 2,
-            goose: 0,
+        Ok(self.instruction(build(n.0, y.0), n.1, y.1))
+    }
+
+    fn parse(&self, operands: &mut Vec<Operand>) -> Result<DuckInstruction, ParseError> {
+        match self.op {
+            InstructionEnum::End => Ok(self.instruction(Instruction::End, None, None)),
+            InstructionEnum::Print => self.unary(operands, |n| Instruction::Print { n }),
+            InstructionEnum::Add => self.binary(operands, |n, y| Instruction::Add { n, y }),
+            InstructionEnum::Subtract => {
+                self.binary(operands, |n, y| Instruction::Subtract { n, y })
+            }
+            InstructionEnum::Multiply => {
+                self.binary(operands, |n, y| Instruction::Multiply { n, y })
+            }
+            InstructionEnum::Divide => self.binary(operands, |n, y| Instruction::Divide { n, y }),
+            InstructionEnum::Input => self.unary(operands, |n| Instruction::Input { n }),
+            InstructionEnum::Push => self.unary(operands, |n| Instruction::Push { n }),
+            InstructionEnum::Pop => self.unary(operands, |n| Instruction::Pop { n }),
+            InstructionEnum::LoopBegin => {
+                //Loop id is optional and defaults to 0
+                let id = operands.pop().unwrap_or_default();
+                let cond = self.pop_operand(operands)?;
+                let inst = Instruction::LoopBegin {
+                    cond: cond.0,
+                    id: id.0,
+                };
+
+                Ok(self.instruction(inst, cond.1, id.1))
+            }
+            InstructionEnum::LoopEnd => {
+                let id = operands.pop().unwrap_or_default();
+
+                Ok(self.instruction(Instruction::LoopEnd { id: id.0 }, id.1, None))
+            }
+            InstructionEnum::Set => self.binary(operands, |n, value| Instruction::Set { n, value }),
         }
     }
 }
 
 //Old approach used to statically calculate rotated index positions
-fn _apply_goose_updates(
-    duck_count: usize,
-    inst_list: &Vec<DuckInstruction>,
-) -> Vec<DuckInstruction> {
+fn _apply_goose_updates(duck_count: usize, inst_list: &[DuckInstruction]) -> Vec<DuckInstruction> {
     //Create copy of instructions to modify with rotations
     let mut rotated_inst_list = inst_list.to_vec();
 
@@ -181,127 +365,279 @@ fn _apply_goose_updates(
     //Rotate instructions
     for inst_pos in 0..inst_list.len() {
         //Determine rotation amount
-        if inst_list[inst_pos].op_code != InstructionEnum::Print as usize
-            && inst_list[inst_pos].op_code != InstructionEnum::End as usize
-            && inst_list[inst_pos].op_code != InstructionEnum::LoopBegin as usize
-            && inst_list[inst_pos].op_code != InstructionEnum::LoopEnd as usize
-        {
-            duck_mapping.rotate_right(inst_list[inst_pos].n);
-        } else {
-            continue;
+        match inst_list[inst_pos].inst.goose_target() {
+            Some(n) => duck_mapping.rotate_right(n),
+            None => continue,
         }
 
         //Rotate subsequent instructions
         for i in inst_pos + 1..rotated_inst_list.len() {
-            let op = inst_list[i].op_code;
-            let arg_c = inst_list[i].arg_c;
-
-            if op == InstructionEnum::LoopBegin as usize {
-                rotated_inst_list[i].n = duck_mapping
-                    .iter()
-                    .position(|&r| r == inst_list[i].n)
-                    .unwrap();
-            } else if op == InstructionEnum::LoopEnd as usize {
-            } else if op == InstructionEnum::Set as usize {
-                rotated_inst_list[i].n = duck_mapping
-                    .iter()
-                    .position(|&r| r == inst_list[i].n)
-                    .unwrap();
-                rotated_inst_list[i].goose = duck_mapping
-                    .iter()
-                    .position(|&r| r == inst_list[i].goose)
-                    .unwrap();
-            } else if arg_c == 1 {
-                rotated_inst_list[i].n = duck_mapping
-                    .iter()
-                    .position(|&r| r == inst_list[i].n)
-                    .unwrap();
-                rotated_inst_list[i].goose = duck_mapping
-                    .iter()
-                    .position(|&r| r == inst_list[i].goose)
-                    .unwrap();
-            } else if arg_c == 2 {
-                rotated_inst_list[i].n = duck_mapping
-                    .iter()
-                    .position(|&r| r == inst_list[i].n)
-                    .unwrap();
-                rotated_inst_list[i].y = duck_mapping
-                    .iter()
-                    .position(|&r| r == inst_list[i].y)
-                    .unwrap();
-                rotated_inst_list[i].goose = duck_mapping
-                    .iter()
-                    .position(|&r| r == inst_list[i].goose)
-                    .unwrap();
-            }
+            rotated_inst_list[i].inst = inst_list[i]
+                .inst
+                .map_ducks(|duck| duck_mapping.iter().position(|&r| r == duck).unwrap());
         }
     }
 
     rotated_inst_list
 }
 
-pub fn parse_file(reader: &mut BufReader<File>) -> (usize, Vec<DuckInstruction>) {
+//Parse a program from any buffered reader (file, stdin, in-memory bytes)
+pub fn parse_file<R: BufRead>(reader: &mut R) -> Result<Program, ParseError> {
     //Read file header
-    let counts = parse_header(reader);
+    let (duck_count, mut metadata) = parse_header(reader)?;
+    let mut line_no = metadata.line_count;
 
     //Parse and record program body instructions
-    let mut ops = Vec::<usize>::new();
+    let mut ops = Vec::<Operand>::new();
     let mut duck_inst = Vec::<DuckInstruction>::new();
+    let mut last_line = None;
 
     for line in reader.lines() {
-        let line = line.unwrap();
+        let line = line?;
+        line_no += 1;
 
-        let counts = get_counts(&line);
+        let tokens = tokenize_line(&line, line_no);
+        let counts = get_counts(&tokens);
+        let span = covering_span(&tokens, line_no);
 
         if counts.1 == 0 {
-            ops.push(counts.0);
-        } else {
-            match counts.0 {
-                x if x == InstructionEnum::Print as usize
-                    || x == InstructionEnum::Input as usize
-                    || x == InstructionEnum::Push as usize
-                    || x == InstructionEnum::Pop as usize =>
-                {
-                    duck_inst.push(parse_unary_inst(x, &mut ops));
-                }
+            ops.push((counts.0, Some(span)));
+            continue;
+        }
 
-                x if x == InstructionEnum::Add as usize
-                    || x == InstructionEnum::Subtract as usize
-                    || x == InstructionEnum::Multiply as usize
-                    || x == InstructionEnum::Divide as usize
-                    || x == InstructionEnum::Set as usize =>
-                {
-                    duck_inst.push(parse_binary_inst(x, &mut ops));
-                }
+        let op = match InstructionEnum::from_code(counts.0) {
+            Some(op) => op,
+            None => {
+                return Err(ParseError::UnknownInstruction {
+                    op_code: counts.0,
+                    at: snippet(&line, span),
+                })
+            }
+        };
 
-                x if x == InstructionEnum::LoopBegin as usize
-                    || x == InstructionEnum::LoopEnd as usize =>
-                {
-                    duck_inst.push(parse_loop_inst(x, &mut ops));
-                }
+        let inst = InstLine {
+            op,
+            text: &line,
+            span,
+        };
+        duck_inst.push(inst.parse(&mut ops)?);
+        ops.clear();
+        last_line = Some(snippet(&line, span));
+    }
+
+    match duck_inst.last() {
+        Some(inst) if inst.inst == Instruction::End => (),
+        _ => return Err(ParseError::MissingEnd(last_line)),
+    }
+
+    metadata.line_count = line_no;
+    let program = Program::new(duck_count, duck_inst).with_metadata(metadata);
+
+    #[cfg(DEBUG)]
+    print!("{}", program);
+
+    Ok(program)
+}
 
-                x if x == InstructionEnum::End as usize => duck_inst.push(DuckInstruction {
-                    op_code: x,
-                    n: 0,
-                    y: 0,
-                    arg_c: 0,
-                    goose: 0,
-                }),
+//Parse a program held in memory
+pub fn parse_str(source: &str) -> Result<Program, ParseError> {
+    parse_file(&mut source.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction::*;
+
+    fn error(source: &str) -> ParseError {
+        match parse_str(source) {
+            Ok(program) => panic!("parsed:\n{}", program),
+            Err(why) => why,
+        }
+    }
 
-                x => panic!("Unhandled instruction code {}", x),
+    //Source for insts, each operand on a line of its own before the instruction line
+    fn source(duck_count: usize, insts: &[Instruction]) -> String {
+        let ducks = |count: usize| vec!["duck"; count].join(" ");
+        let mut out = format!("#round trip\n{} goose\n", ducks(duck_count));
+        for inst in insts {
+            let operands = match *inst {
+                End => vec![],
+                Print { n } | Input { n } | Push { n } | Pop { n } => vec![n],
+                Add { n, y } | Subtract { n, y } | Multiply { n, y } | Divide { n, y } => {
+                    vec![n, y]
+                }
+                LoopBegin { cond, id } => vec![cond, id],
+                LoopEnd { id } => vec![id],
+                Set { n, value } => vec![n, value],
+                Move { .. } | PrintValue { .. } => panic!("{} has no source", inst),
+            };
+            for operand in operands {
+                out.push_str(&format!("{}\n", ducks(operand)));
             }
-            ops.clear();
+            //Op codes count in declaration order from End
+            let op_code = (0..)
+                .find(|&code| InstructionEnum::from_code(code) == inst.op_code())
+                .unwrap();
+            out.push_str(&format!("{} goose\n", ducks(op_code)));
         }
+
+        out
     }
 
-    if duck_inst.last().unwrap().op_code != InstructionEnum::End as usize {
-        panic!("Program does not end with goose!");
+    #[test]
+    fn parse_str_round_trip() {
+        let insts = [
+            Set { n: 1, value: 3 },
+            LoopBegin { cond: 0, id: 2 },
+            Print { n: 0 },
+            Subtract { n: 0, y: 2 },
+            LoopEnd { id: 2 },
+            Input { n: 1 },
+            Push { n: 2 },
+            Pop { n: 0 },
+            Add { n: 1, y: 0 },
+            Multiply { n: 2, y: 1 },
+            Divide { n: 1, y: 1 },
+            End,
+        ];
+        let source = source(2, &insts);
+        let program = parse_str(&source).unwrap();
+
+        assert_eq!(program.duck_count(), 2);
+        assert_eq!(program.ops().collect::<Vec<_>>(), insts);
+        assert_eq!(program.metadata().description, ["round trip"]);
+        assert_eq!(program.metadata().header, Span::new(2, 0, 15));
+        assert_eq!(program.metadata().line_count, source.lines().count());
+
+        //Set 1, $3: operand lines 3 and 4, instruction line 5
+        let set = program.instructions()[0];
+        assert_eq!(set.n_span, Some(Span::new(3, 0, 4)));
+        assert_eq!(set.y_span, Some(Span::new(4, 0, 14)));
+        assert_eq!(set.span.line, 5);
+
+        //Any BufRead gives the same program
+        let mut reader = std::io::BufReader::new(source.as_bytes());
+        let from_reader = parse_file(&mut reader).unwrap();
+        assert!(program
+            .iter()
+            .zip(&from_reader)
+            .all(|(a, b)| a.inst == b.inst && a.span == b.span));
     }
 
-    let parse_results = (counts.0, duck_inst);
+    #[test]
+    fn loop_end_ids_default_to_zero() {
+        //LoopBegin 1, id 0 with a blank id line, then a LoopEnd with no operand lines
+        let program = parse_str(&format!(
+            "duck goose\nduck\n\n{}goose\n{}goose\ngoose\n",
+            "duck ".repeat(9),
+            "duck ".repeat(10)
+        ))
+        .unwrap();
+
+        assert_eq!(
+            program.ops().collect::<Vec<_>>(),
+            [LoopBegin { cond: 1, id: 0 }, LoopEnd { id: 0 }, End]
+        );
+        assert_eq!(program.instructions()[1].n_span, None);
+    }
 
-    #[cfg(DEBUG)]
-    print_parse(&parse_results);
+    #[test]
+    fn missing_header() {
+        let why = error("#no geese here\n\n");
+
+        assert!(matches!(why, ParseError::MissingHeader));
+        assert_eq!(why.span(), None);
+    }
+
+    #[test]
+    fn multiple_geese() {
+        let why = error("duck goose goose\ngoose\n");
+
+        assert!(matches!(why, ParseError::MultipleGeese(_)));
+        assert_eq!(why.span(), Some(Span::new(1, 11, 16)));
+    }
+
+    #[test]
+    fn duck_after_goose() {
+        let why = error("duck goose duck\ngoose\n");
+
+        assert!(matches!(why, ParseError::DuckAfterGoose(_)));
+        assert_eq!(why.span(), Some(Span::new(1, 11, 15)));
+        assert_eq!(why.to_string(), "line 1: Duck after goose!");
+        assert_eq!(
+            why.render("x.ddg"),
+            concat!(
+                "error: Duck after goose!\n",
+                " --> x.ddg:1:12\n",
+                "  |\n",
+                "1 | duck goose duck\n",
+                "  |            ^^^^\n"
+            )
+        );
+    }
+
+    #[test]
+    fn missing_goose() {
+        let why = error("#comment\n  duck duck\n");
 
-    parse_results
+        assert!(matches!(why, ParseError::MissingGoose(_)));
+        assert_eq!(why.span(), Some(Span::new(2, 2, 11)));
+    }
+
+    #[test]
+    fn missing_operand() {
+        //Add with a single operand line
+        let why = error("duck goose\nduck\nduck duck goose\ngoose\n");
+
+        assert!(matches!(
+            why,
+            ParseError::MissingOperand {
+                op: InstructionEnum::Add,
+                ..
+            }
+        ));
+        assert_eq!(why.span(), Some(Span::new(3, 0, 15)));
+    }
+
+    #[test]
+    fn unknown_instruction() {
+        let why = error(&format!("duck goose\n{}goose\n", "duck ".repeat(12)));
+
+        assert!(matches!(
+            why,
+            ParseError::UnknownInstruction { op_code: 12, .. }
+        ));
+        assert_eq!(why.span(), Some(Span::new(2, 0, 65)));
+    }
+
+    #[test]
+    fn missing_end() {
+        let why = error("duck goose\nduck\nduck goose\n");
+        assert!(matches!(why, ParseError::MissingEnd(Some(_))));
+        assert_eq!(why.span(), Some(Span::new(3, 0, 10)));
+
+        //Nothing after the header to point at
+        let why = error("duck goose\n");
+        assert!(matches!(why, ParseError::MissingEnd(None)));
+        assert_eq!(why.span(), None);
+    }
+
+    #[test]
+    fn invalid_utf8_is_an_io_error() {
+        let why = parse_file(&mut &b"duck goose\n\xff\n"[..]).unwrap_err();
+
+        assert!(matches!(why, ParseError::Io(_)));
+        assert_eq!(why.span(), None);
+    }
+
+    #[test]
+    fn carets_keep_tabs() {
+        let at = Snippet::from_source("duck goose\n\tduck goose duck\n", Span::new(2, 12, 16));
+
+        assert_eq!(
+            at.render("x.ddg"),
+            " --> x.ddg:2:13\n  |\n2 | \tduck goose duck\n  | \t           ^^^^\n"
+        );
+    }
 }
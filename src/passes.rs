@@ -0,0 +1,869 @@
+//Hayden Coffey
+//Optimization passes over the program, run in order by a PassManager before any backend
+//sees it. Every pass takes a checked program and must leave one behind.
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::cfg;
+use crate::check::{self, Loops};
+use crate::instruction::{DuckInstruction, Instruction};
+use crate::liveness;
+use crate::program::Program;
+use crate::rotation::{self, Rotation};
+
+pub trait Pass {
+    //Name used to select the pass on the command line, such as --print-after
+    fn name(&self) -> &'static str;
+
+    //Rewrite program in place, returning whether anything changed
+    fn run(&mut self, program: &mut Program) -> bool;
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    //No passes, one template per instruction
+    #[default]
+    O0,
+    O1,
+    //O1, plus backend choices such as keeping ducks in registers on x86_64
+    O2,
+}
+
+impl OptLevel {
+    pub fn from_name(name: &str) -> Option<OptLevel> {
+        match name {
+            "0" => Some(OptLevel::O0),
+            "1" => Some(OptLevel::O1),
+            "2" => Some(OptLevel::O2),
+            _ => None,
+        }
+    }
+
+    //Passes run at this level, in order
+    pub fn pipeline(self) -> PassManager {
+        let mut manager = PassManager::new();
+        if self >= OptLevel::O1 {
+            manager.add(Box::new(Unreachable));
+            manager.add(Box::new(Fold));
+            manager.add(Box::new(DeadStores));
+        }
+
+        manager
+    }
+}
+
+//Every pass, for looking them up by name
+pub fn all() -> Vec<Box<dyn Pass>> {
+    vec![Box::new(Unreachable), Box::new(Fold), Box::new(DeadStores)]
+}
+
+pub fn is_known(name: &str) -> bool {
+    all().iter().any(|pass| pass.name() == name)
+}
+
+//How long one pass took and whether it changed the program
+#[derive(Clone, Copy, Debug)]
+pub struct PassTiming {
+    pub name: &'static str,
+    pub elapsed: Duration,
+    pub changed: bool,
+}
+
+//Timings as a table, slowest pass first
+pub fn timing_report(timings: &[PassTiming]) -> String {
+    let mut sorted = timings.to_vec();
+    sorted.sort_by_key(|timing| std::cmp::Reverse(timing.elapsed));
+    let total: Duration = timings.iter().map(|timing| timing.elapsed).sum();
+
+    let mut out = String::from("===== Pass execution timing =====\n");
+    for timing in sorted {
+        out.push_str(&format!(
+            "{:>12.3?}  {:<16}{}\n",
+            timing.elapsed,
+            timing.name,
+            if timing.changed { "changed" } else { "" }
+        ));
+    }
+    out.push_str(&format!("{:>12.3?}  total\n", total));
+
+    out
+}
+
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    //Names of passes to dump the program after
+    print_after: Vec<String>,
+}
+
+impl PassManager {
+    pub fn new() -> PassManager {
+        PassManager::default()
+    }
+
+    pub fn add(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    //Print the program to the log after every run of the named pass
+    pub fn print_after(&mut self, name: &str) {
+        self.print_after.push(String::from(name));
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    //Run every pass in order, dumps going to log. Fails with InvalidData, naming the pass,
+    //if one leaves the program failing check::check.
+    pub fn run<W: Write>(
+        &mut self,
+        program: &mut Program,
+        log: &mut W,
+    ) -> std::io::Result<Vec<PassTiming>> {
+        let mut timings = Vec::with_capacity(self.passes.len());
+        for pass in &mut self.passes {
+            let start = Instant::now();
+            let changed = pass.run(program);
+            timings.push(PassTiming {
+                name: pass.name(),
+                elapsed: start.elapsed(),
+                changed,
+            });
+
+            if changed {
+                check::check(program).map_err(|errors| {
+                    let why = check::into_io_error(errors);
+                    std::io::Error::new(why.kind(), format!("after pass {}: {}", pass.name(), why))
+                })?;
+            }
+
+            if self.print_after.iter().any(|name| name == pass.name()) {
+                writeln!(log, "#*** IR after {} ***", pass.name())?;
+                write!(log, "{}", program)?;
+            }
+        }
+
+        Ok(timings)
+    }
+}
+
+//Remove instructions control never reaches, such as the rest of a loop body after an End.
+//A LoopEnd stays while its LoopBegin is reachable, so loops still pair up.
+pub struct Unreachable;
+
+impl Pass for Unreachable {
+    fn name(&self) -> &'static str {
+        "unreachable"
+    }
+
+    fn run(&mut self, program: &mut Program) -> bool {
+        let loops = match check::check(program) {
+            Ok(loops) => loops,
+            Err(_) => return false,
+        };
+        let cfg = cfg::build(program, &loops);
+
+        let keep: Vec<bool> = program
+            .iter()
+            .enumerate()
+            .map(|(pos, inst)| {
+                let reachable = |pos| cfg.is_reachable(cfg.block_of(pos));
+                match inst.inst {
+                    Instruction::LoopEnd { .. } => loops.partner(pos).is_some_and(reachable),
+                    _ => reachable(pos),
+                }
+            })
+            .collect();
+        if keep.iter().all(|&keep| keep) {
+            return false;
+        }
+
+        let mut keep = keep.into_iter();
+        program
+            .instructions_mut()
+            .retain(|_| keep.next().unwrap_or(true));
+
+        true
+    }
+}
+
+//Replace arithmetic on slots with known values by a Set of the result, prints of them by
+//a PrintValue, and drop loops whose condition is 0 on entry. Slot values are only tracked
+//where the rotation analysis knows the goose, and a loop forgets every slot its body may
+//write.
+pub struct Fold;
+
+impl Pass for Fold {
+    fn name(&self) -> &'static str {
+        "fold"
+    }
+
+    fn run(&mut self, program: &mut Program) -> bool {
+        //Removing a loop can make the goose known after it, which may allow more folding
+        let mut changed = false;
+        while fold(program) {
+            changed = true;
+        }
+
+        changed
+    }
+}
+
+//Known slot values, ring slots then the teacher. Everything starts at 0.
+type Slots = Vec<Option<u64>>;
+
+//Array slot of the duck n places after the goose
+fn slot(goose: usize, n: usize, ring_size: usize) -> usize {
+    (goose + n % ring_size) % ring_size
+}
+
+//Result of a binary op on two slots, if it is known at compile time. Dividing by zero is
+//left alone so it still traps at runtime.
+fn fold_binary(inst: Instruction, lhs: Option<u64>, rhs: Option<u64>, same: bool) -> Option<u64> {
+    match (inst, lhs, rhs) {
+        (Instruction::Add { .. }, Some(lhs), Some(rhs)) => Some(lhs.wrapping_add(rhs)),
+        (Instruction::Subtract { .. }, _, _) if same => Some(0),
+        (Instruction::Subtract { .. }, Some(lhs), Some(rhs)) => Some(lhs.wrapping_sub(rhs)),
+        (Instruction::Multiply { .. }, Some(0), _) | (Instruction::Multiply { .. }, _, Some(0)) => {
+            Some(0)
+        }
+        (Instruction::Multiply { .. }, Some(lhs), Some(rhs)) => Some(lhs.wrapping_mul(rhs)),
+        (Instruction::Divide { .. }, Some(lhs), Some(rhs)) if rhs != 0 => Some(lhs / rhs),
+        _ => None,
+    }
+}
+
+//Slots the body of the loop starting at begin may write
+fn loop_writes(program: &Program, loops: &Loops, rotation: &Rotation, begin: usize) -> Vec<bool> {
+    let ring_size = program.ring_size();
+    let teacher = program.teacher_slot();
+    let mut written = vec![false; ring_size + 1];
+
+    let end = loops.partner(begin).unwrap_or(begin);
+    for pos in begin + 1..end {
+        let inst = program.instructions()[pos].inst;
+        //Every instruction that moves the goose stores into its slot, except Move
+        if inst.goose_target().is_some() && !matches!(inst, Instruction::Move { .. }) {
+            match rotation.goose(pos) {
+                Some(goose) => written[goose] = true,
+                None => written[..ring_size].fill(true),
+            }
+        }
+        if let Instruction::Push { .. } | Instruction::Pop { .. } = inst {
+            written[teacher] = true;
+        }
+    }
+
+    written
+}
+
+//Goose when control reaches the LoopBegin at pos from the instruction before it rather
+//than from its LoopEnd. Known more often than the goose at the LoopBegin itself, which
+//also covers every later test.
+fn entry_goose(program: &Program, loops: &Loops, rotation: &Rotation, pos: usize) -> Option<usize> {
+    let prev = match pos.checked_sub(1) {
+        None => return Some(0),
+        Some(prev) => prev,
+    };
+
+    let inst = program.instructions()[prev].inst;
+    match inst {
+        //Left once its test read 0, with the goose where the test found it
+        Instruction::LoopEnd { .. } => rotation.goose(loops.partner(prev)?),
+        _ => {
+            let goose = rotation.goose(prev)?;
+            Some(match inst.goose_target() {
+                Some(n) => slot(goose, n, program.ring_size()),
+                None => goose,
+            })
+        }
+    }
+}
+
+//One pass of folding over program, returning whether anything changed
+fn fold(program: &mut Program) -> bool {
+    let loops = match check::check(program) {
+        Ok(loops) => loops,
+        Err(_) => return false,
+    };
+    let rotation = rotation::analyze(program, &loops);
+    let ring_size = program.ring_size();
+    let teacher = program.teacher_slot();
+
+    let mut insts = program.instructions().to_vec();
+    let mut keep = vec![true; insts.len()];
+    let mut changed = false;
+
+    let mut state: Slots = vec![Some(0); ring_size + 1];
+    //State at the test of each enclosing loop, and the slot it tests if known
+    let mut heads: Vec<(Slots, Option<usize>)> = Vec::new();
+    let mut pos = 0;
+    while pos < insts.len() {
+        let inst = insts[pos].inst;
+        let goose = rotation.goose(pos);
+        let duck = |n| goose.map(|goose| slot(goose, n, ring_size));
+
+        //Value stored in the goose slot, for the instructions that store one
+        let value = match inst {
+            Instruction::Print { n } => {
+                if let Some(value) = duck(n).and_then(|slot| state[slot]) {
+                    insts[pos].inst = Instruction::PrintValue {
+                        value: (value & 0xff) as usize,
+                    };
+                    changed = true;
+                }
+                pos += 1;
+                continue;
+            }
+            Instruction::End | Instruction::Move { .. } | Instruction::PrintValue { .. } => {
+                pos += 1;
+                continue;
+            }
+            Instruction::LoopBegin { cond, .. } => {
+                let first = entry_goose(program, &loops, &rotation, pos)
+                    .map(|goose| slot(goose, cond, ring_size));
+                let cond = duck(cond);
+                let end = loops.partner(pos).unwrap_or(pos);
+                //Never entered, so it does nothing at all
+                if first.is_some_and(|first| state[first] == Some(0)) {
+                    keep[pos..=end].fill(false);
+                    changed = true;
+                    pos = end + 1;
+                    continue;
+                }
+
+                let written = loop_writes(program, &loops, &rotation, pos);
+                for (slot, written) in state.iter_mut().zip(written) {
+                    if written {
+                        *slot = None;
+                    }
+                }
+                heads.push((state.clone(), cond));
+                pos += 1;
+                continue;
+            }
+            Instruction::LoopEnd { .. } => {
+                //Leaving the loop means its test just read 0
+                if let Some((head, cond)) = heads.pop() {
+                    state = head;
+                    if let Some(cond) = cond {
+                        state[cond] = Some(0);
+                    }
+                }
+                pos += 1;
+                continue;
+            }
+            Instruction::Add { n, y }
+            | Instruction::Subtract { n, y }
+            | Instruction::Multiply { n, y }
+            | Instruction::Divide { n, y } => match (duck(n), duck(y)) {
+                (Some(a), Some(b)) => {
+                    let value = fold_binary(inst, state[a], state[b], a == b);
+                    if let Some(folded) = value.and_then(|value| usize::try_from(value).ok()) {
+                        insts[pos].inst = Instruction::Set { n, value: folded };
+                        changed = true;
+                    }
+                    value
+                }
+                _ => None,
+            },
+            Instruction::Input { .. } => None,
+            Instruction::Push { n } => {
+                let value = duck(n).and_then(|slot| state[slot]);
+                state[teacher] = value;
+                value
+            }
+            Instruction::Pop { .. } => {
+                let value = state[teacher];
+                state[teacher] = Some(0);
+                value
+            }
+            Instruction::Set { value, .. } => Some(value as u64),
+        };
+
+        match goose {
+            Some(goose) => state[goose] = value,
+            None => state[..ring_size].fill(None),
+        }
+        pos += 1;
+    }
+
+    if !changed {
+        return false;
+    }
+    let mut keep = keep.into_iter();
+    insts.retain(|_| keep.next().unwrap_or(true));
+    *program.instructions_mut() = insts;
+
+    true
+}
+
+//Turn instructions whose stores are never read into plain goose moves, then merge the
+//moves. Divide and Input are left alone, one may trap and the other consumes input.
+pub struct DeadStores;
+
+impl Pass for DeadStores {
+    fn name(&self) -> &'static str {
+        "dead-stores"
+    }
+
+    fn run(&mut self, program: &mut Program) -> bool {
+        //A move no longer reads the operands of the instruction it replaced, which may
+        //leave the stores to those dead too
+        let mut changed = false;
+        while remove_dead_stores(program) {
+            changed = true;
+        }
+
+        merge_moves(program) || changed
+    }
+}
+
+//One sweep of dead store removal over program, returning whether anything changed
+fn remove_dead_stores(program: &mut Program) -> bool {
+    let loops = match check::check(program) {
+        Ok(loops) => loops,
+        Err(_) => return false,
+    };
+    let rotation = rotation::analyze(program, &loops);
+    let dead = liveness::dead_stores(program, &loops, &rotation);
+
+    let mut changed = false;
+    for (inst, dead) in program.instructions_mut().iter_mut().zip(dead) {
+        let n = match inst.inst {
+            Instruction::Add { n, .. }
+            | Instruction::Subtract { n, .. }
+            | Instruction::Multiply { n, .. }
+            | Instruction::Push { n }
+            | Instruction::Pop { n }
+            | Instruction::Set { n, .. }
+                if dead =>
+            {
+                n
+            }
+            _ => continue,
+        };
+        inst.inst = Instruction::Move { n };
+        inst.y_span = None;
+        changed = true;
+    }
+
+    changed
+}
+
+//Merge runs of moves into one, letting them sink past PrintValue which doesn't care where
+//the goose is. Moves by a whole turn of the ring go, and so do moves nothing follows but
+//End or the end of the program.
+fn merge_moves(program: &mut Program) -> bool {
+    let ring_size = program.ring_size();
+    let before: Vec<Instruction> = program.ops().collect();
+
+    let mut merged = Vec::with_capacity(before.len());
+    let mut pending: Option<DuckInstruction> = None;
+    for inst in program.instructions_mut().drain(..) {
+        match inst.inst {
+            Instruction::Move { n } => match &mut pending {
+                Some(DuckInstruction {
+                    inst: Instruction::Move { n: total },
+                    ..
+                }) => *total = (*total % ring_size + n % ring_size) % ring_size,
+                _ => pending = Some(inst),
+            },
+            Instruction::PrintValue { .. } => merged.push(inst),
+            Instruction::End => {
+                pending = None;
+                merged.push(inst);
+            }
+            _ => {
+                if let Some(DuckInstruction {
+                    inst: Instruction::Move { n },
+                    ..
+                }) = pending
+                {
+                    if n % ring_size != 0 {
+                        merged.extend(pending);
+                    }
+                }
+                pending = None;
+                merged.push(inst);
+            }
+        }
+    }
+
+    *program.instructions_mut() = merged;
+    program.ops().ne(before)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction::*;
+    //Tests use two ducks, so a ring of 3 with the teacher at 3
+    use crate::testing::program;
+
+    //Drops every LoopEnd, leaving the loops unmatched
+    struct BreakLoops;
+
+    impl Pass for BreakLoops {
+        fn name(&self) -> &'static str {
+            "break-loops"
+        }
+
+        fn run(&mut self, program: &mut Program) -> bool {
+            program
+                .instructions_mut()
+                .retain(|inst| !matches!(inst.inst, LoopEnd { .. }));
+            true
+        }
+    }
+
+    #[test]
+    fn pipelines_per_level() {
+        assert!(OptLevel::O0.pipeline().names().is_empty());
+        assert_eq!(
+            OptLevel::O1.pipeline().names(),
+            ["unreachable", "fold", "dead-stores"]
+        );
+        assert_eq!(
+            OptLevel::O2.pipeline().names(),
+            ["unreachable", "fold", "dead-stores"]
+        );
+        assert!(OptLevel::O1
+            .pipeline()
+            .names()
+            .iter()
+            .all(|&name| is_known(name)));
+    }
+
+    #[test]
+    fn print_after_dumps_only_the_named_pass() {
+        let mut program = program(2, &[Set { n: 1, value: 2 }, Add { n: 2, y: 2 }, End]);
+        let mut manager = OptLevel::O1.pipeline();
+        manager.print_after("fold");
+
+        let mut log = Vec::new();
+        let timings = manager.run(&mut program, &mut log).unwrap();
+        let log = String::from_utf8(log).unwrap();
+
+        assert_eq!(timings.len(), 3);
+        assert!(!timings[0].changed);
+        assert!(timings[1].changed);
+        assert!(log.starts_with("#*** IR after fold ***\n"), "{}", log);
+        assert!(log.contains("Set 2, $4"), "{}", log);
+        assert!(!log.contains("IR after dead-stores"), "{}", log);
+    }
+
+    #[test]
+    fn broken_passes_are_named() {
+        let mut program = program(
+            2,
+            &[
+                LoopBegin { cond: 0, id: 0 },
+                Print { n: 0 },
+                LoopEnd { id: 0 },
+                End,
+            ],
+        );
+        let mut manager = PassManager::new();
+        manager.add(Box::new(BreakLoops));
+
+        let why = manager.run(&mut program, &mut Vec::new()).unwrap_err();
+
+        assert_eq!(why.kind(), std::io::ErrorKind::InvalidData);
+        assert!(
+            why.to_string().starts_with("after pass break-loops: "),
+            "{}",
+            why
+        );
+    }
+
+    #[test]
+    fn unreachable_keeps_loop_ends_paired() {
+        let mut program = program(
+            2,
+            &[
+                Set { n: 1, value: 1 },
+                LoopBegin { cond: 2, id: 0 },
+                Print { n: 0 },
+                End,
+                Print { n: 1 },
+                Set { n: 1, value: 0 },
+                LoopEnd { id: 0 },
+                End,
+            ],
+        );
+
+        assert!(Unreachable.run(&mut program));
+        assert_eq!(
+            program.ops().collect::<Vec<_>>(),
+            [
+                Set { n: 1, value: 1 },
+                LoopBegin { cond: 2, id: 0 },
+                Print { n: 0 },
+                End,
+                LoopEnd { id: 0 },
+                End,
+            ]
+        );
+        assert!(check::check(&program).is_ok());
+        assert!(!Unreachable.run(&mut program));
+    }
+
+    //Interpreter output on input, and whether the program finished without an error
+    fn outcome(program: &Program, input: &[u8]) -> (Vec<u8>, bool) {
+        let mut out = Vec::new();
+        let finished = crate::interp::run(program, input, &mut out).is_ok();
+        (out, finished)
+    }
+
+    //Run Fold and then DeadStores, checking the output doesn't change after either.
+    //Returns the optimized program.
+    fn assert_same_output(insts: &[Instruction], input: &[u8], expected: (&[u8], bool)) -> Program {
+        let mut program = program(2, insts);
+        assert_eq!(outcome(&program, input), (expected.0.to_vec(), expected.1));
+
+        Fold.run(&mut program);
+        assert!(check::check(&program).is_ok(), "{}", program);
+        assert_eq!(
+            outcome(&program, input),
+            (expected.0.to_vec(), expected.1),
+            "after fold:\n{}",
+            program
+        );
+
+        DeadStores.run(&mut program);
+        assert!(check::check(&program).is_ok(), "{}", program);
+        assert_eq!(
+            outcome(&program, input),
+            (expected.0.to_vec(), expected.1),
+            "after dead-stores:\n{}",
+            program
+        );
+
+        program
+    }
+
+    #[test]
+    fn prints_of_computed_values_become_immediates() {
+        let program = assert_same_output(
+            &[
+                Set { n: 0, value: 6 },
+                Multiply { n: 0, y: 0 },
+                Add { n: 0, y: 0 },
+                Print { n: 0 },
+                Set {
+                    n: 1,
+                    value: b'i' as usize,
+                },
+                Print { n: 2 },
+                End,
+            ],
+            b"",
+            (b"Hi", true),
+        );
+
+        assert_eq!(
+            program.ops().collect::<Vec<_>>(),
+            [
+                PrintValue {
+                    value: b'H' as usize
+                },
+                PrintValue {
+                    value: b'i' as usize
+                },
+                End,
+            ]
+        );
+    }
+
+    #[test]
+    fn loop_writes_are_forgotten_after_the_loop() {
+        let program = assert_same_output(
+            &[
+                Set { n: 1, value: 1 },
+                Set { n: 2, value: 0 },
+                //Goes once round the ring, writing every slot
+                LoopBegin { cond: 0, id: 0 },
+                Add { n: 1, y: 2 },
+                Set { n: 1, value: 0 },
+                Set {
+                    n: 1,
+                    value: b'K' as usize,
+                },
+                LoopEnd { id: 0 },
+                Print { n: 2 },
+                End,
+            ],
+            b"",
+            (b"K", true),
+        );
+
+        //Read after the loop, so it stays a store
+        assert!(program.ops().any(|inst| inst
+            == Set {
+                n: 1,
+                value: b'K' as usize
+            }));
+    }
+
+    #[test]
+    fn push_and_pop_carry_values_through_the_teacher() {
+        assert_same_output(
+            &[
+                Set {
+                    n: 1,
+                    value: b'P' as usize,
+                },
+                Push { n: 2 },
+                Set { n: 1, value: 0 },
+                Pop { n: 1 },
+                Print { n: 2 },
+                Input { n: 1 },
+                Push { n: 2 },
+                Pop { n: 1 },
+                Print { n: 2 },
+                Pop { n: 0 },
+                Print { n: 0 },
+                End,
+            ],
+            b"i",
+            (b"Pi\0", true),
+        );
+    }
+
+    #[test]
+    fn unknown_values_minus_themselves_are_zero() {
+        let program = assert_same_output(
+            &[
+                Input { n: 1 },
+                Subtract { n: 2, y: 2 },
+                //Never entered once the subtraction is known to give 0
+                LoopBegin { cond: 1, id: 0 },
+                Print { n: 1 },
+                LoopEnd { id: 0 },
+                Print { n: 1 },
+                End,
+            ],
+            b"x",
+            (b"\0", true),
+        );
+
+        assert!(!program.ops().any(|inst| matches!(inst, Subtract { .. })));
+        assert!(!program.ops().any(|inst| matches!(inst, LoopBegin { .. })));
+    }
+
+    #[test]
+    fn dividing_by_a_known_zero_still_traps() {
+        let program = assert_same_output(
+            &[
+                Set {
+                    n: 1,
+                    value: b'D' as usize,
+                },
+                Print { n: 2 },
+                Divide { n: 2, y: 1 },
+                Print { n: 2 },
+                End,
+            ],
+            b"",
+            (b"D", false),
+        );
+
+        assert!(program.ops().any(|inst| matches!(inst, Divide { .. })));
+    }
+
+    #[test]
+    fn loops_never_entered_are_dropped() {
+        let program = assert_same_output(
+            &[
+                Set {
+                    n: 1,
+                    value: b'A' as usize,
+                },
+                //Tests slot 2, still 0. The body would lose the goose.
+                LoopBegin { cond: 1, id: 0 },
+                Set {
+                    n: 1,
+                    value: b'B' as usize,
+                },
+                LoopEnd { id: 0 },
+                Print { n: 2 },
+                End,
+            ],
+            b"",
+            (b"A", true),
+        );
+
+        assert_eq!(
+            program.ops().collect::<Vec<_>>(),
+            [
+                PrintValue {
+                    value: b'A' as usize
+                },
+                End
+            ]
+        );
+    }
+
+    #[test]
+    fn nested_loops_that_lose_the_goose() {
+        assert_same_output(
+            &[
+                Set {
+                    n: 1,
+                    value: b'a' as usize,
+                },
+                Set {
+                    n: 1,
+                    value: b'b' as usize,
+                },
+                LoopBegin { cond: 1, id: 0 },
+                //Zeroes slots until the next one is 0, turning the goose each time
+                LoopBegin { cond: 1, id: 1 },
+                Set { n: 1, value: 0 },
+                LoopEnd { id: 1 },
+                Print { n: 0 },
+                LoopEnd { id: 0 },
+                Set {
+                    n: 1,
+                    value: b'!' as usize,
+                },
+                Print { n: 2 },
+                End,
+            ],
+            b"",
+            (b"b!", true),
+        );
+    }
+
+    #[test]
+    fn dead_stores_keep_the_goose_moving() {
+        let program = assert_same_output(
+            &[
+                //Overwritten by the Input before anything reads it
+                Set { n: 1, value: 7 },
+                Set {
+                    n: 1,
+                    value: b'y' as usize,
+                },
+                Set {
+                    n: 1,
+                    value: b'x' as usize,
+                },
+                Input { n: 1 },
+                //Runs once and turns the goose, so what follows reads the array
+                LoopBegin { cond: 2, id: 0 },
+                Set { n: 1, value: 0 },
+                LoopEnd { id: 0 },
+                Print { n: 1 },
+                Print { n: 0 },
+                End,
+            ],
+            b"a",
+            (b"ax", true),
+        );
+
+        assert_eq!(program.ops().next(), Some(Move { n: 1 }));
+    }
+}
@@ -0,0 +1,124 @@
+//Hayden Coffey
+use std::fmt;
+use std::slice;
+
+use crate::instruction::{DuckInstruction, Instruction, Span};
+
+//Information gathered about the source while parsing
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    //Comment lines before the header, without the leading '#'
+    pub description: Vec<String>,
+    //Header line declaring the duck count
+    pub header: Span,
+    pub line_count: usize,
+}
+
+//A parsed Duck Duck Goose program
+#[derive(Clone, Debug)]
+pub struct Program {
+    duck_count: usize,
+    instructions: Vec<DuckInstruction>,
+    source_name: Option<String>,
+    metadata: Metadata,
+}
+
+impl Program {
+    pub fn new(duck_count: usize, instructions: Vec<DuckInstruction>) -> Program {
+        Program {
+            duck_count,
+            instructions,
+            source_name: None,
+            metadata: Metadata::default(),
+        }
+    }
+
+    pub fn with_source_name(mut self, name: &str) -> Program {
+        self.source_name = Some(String::from(name));
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: Metadata) -> Program {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn duck_count(&self) -> usize {
+        self.duck_count
+    }
+
+    //Number of positions the goose rotates through: every duck plus the goose itself
+    pub fn ring_size(&self) -> usize {
+        self.duck_count + 1
+    }
+
+    //Array slot holding the teacher, directly after the ring
+    pub fn teacher_slot(&self) -> usize {
+        self.ring_size()
+    }
+
+    pub fn instructions(&self) -> &[DuckInstruction] {
+        &self.instructions
+    }
+
+    //For passes rewriting the program in place, which must leave it passing check::check
+    pub fn instructions_mut(&mut self) -> &mut Vec<DuckInstruction> {
+        &mut self.instructions
+    }
+
+    pub fn into_instructions(self) -> Vec<DuckInstruction> {
+        self.instructions
+    }
+
+    pub fn source_name(&self) -> Option<&str> {
+        self.source_name.as_deref()
+    }
+
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    pub fn len(&self) -> usize {
+        self.instructions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instructions.is_empty()
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, DuckInstruction> {
+        self.instructions.iter()
+    }
+
+    //Decoded instructions without their source spans
+    pub fn ops(&self) -> impl Iterator<Item = Instruction> + '_ {
+        self.instructions.iter().map(|inst| inst.inst)
+    }
+}
+
+impl<'a> IntoIterator for &'a Program {
+    type Item = &'a DuckInstruction;
+    type IntoIter = slice::Iter<'a, DuckInstruction>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "#{}: {} duck(s), {} instruction(s)",
+            self.source_name.as_deref().unwrap_or("<memory>"),
+            self.duck_count,
+            self.instructions.len()
+        )?;
+
+        for inst in &self.instructions {
+            writeln!(f, "{:>8}  {}", inst.span.to_string(), inst.inst)?;
+        }
+
+        Ok(())
+    }
+}
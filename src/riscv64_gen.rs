@@ -0,0 +1,436 @@
+//Hayden Coffey
+//RISC-V 64 (RV64GC) Linux backend. Mirrors x86_64_gen: ducks live in a zeroed array on
+//the stack, with the teacher in the slot after the last duck.
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use crate::backend::{self, Backend, LoopLabels};
+use crate::program::Program;
+
+//Callee saved, so they survive the ecalls
+static ARRAY_BASE_REG: &str = "s1";
+static DUCK_COUNT_REG: &str = "s2";
+static GOOSE_INDEX_REG: &str = "s3";
+
+//Linux syscall numbers
+const SYS_READ: usize = 63;
+const SYS_WRITE: usize = 64;
+const SYS_EXIT: usize = 93;
+const SYS_GETPID: usize = 172;
+const SYS_KILL: usize = 129;
+const SIGFPE: usize = 8;
+
+//Routine raising SIGFPE, RISC-V division by zero doesn't trap like x86-64 does
+static DIVIDE_BY_ZERO_LABEL: &str = "divide_by_zero";
+
+//Translate given duck index to array index and store in given register
+//Uses t0, t1
+fn get_duck_index<W: Write>(register: &str, duck: usize, file: &mut W) -> std::io::Result<()> {
+    //Add duck to goose number
+    writeln!(file, "  li t0, {}", duck)?;
+    writeln!(file, "  add t0, {}, t0", GOOSE_INDEX_REG)?;
+
+    //Mod by duck count + 1
+    writeln!(file, "  addi t1, {}, 1", DUCK_COUNT_REG)?;
+    writeln!(file, "  remu {}, t0, t1", register)?;
+
+    Ok(())
+}
+
+//Store teacher index in given register
+fn get_teacher_index<W: Write>(register: &str, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "  addi {}, {}, 1", register, DUCK_COUNT_REG)
+}
+
+//Address of the slot whose index is held in index, into t2
+fn slot_address<W: Write>(index: &str, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "  slli t2, {}, 3", index)?;
+    writeln!(file, "  add t2, {}, t2", ARRAY_BASE_REG)
+}
+
+//Uses t2
+fn load_slot<W: Write>(register: &str, index: &str, file: &mut W) -> std::io::Result<()> {
+    slot_address(index, file)?;
+    writeln!(file, "  ld {}, 0(t2)", register)
+}
+
+//Uses t2
+fn store_slot<W: Write>(register: &str, index: &str, file: &mut W) -> std::io::Result<()> {
+    slot_address(index, file)?;
+    writeln!(file, "  sd {}, 0(t2)", register)
+}
+
+fn write_syscall<W: Write>(number: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "  li a7, {}", number)?;
+    writeln!(file, "  ecall")
+}
+
+//Assembly header lines, allocate stack array and initialize registers
+fn write_header<W: Write>(duck_count: usize, file: &mut W) -> std::io::Result<()> {
+    //Mark the stack non-executable so the linker doesn't warn
+    writeln!(file, ".section .note.GNU-stack,\"\",@progbits")?;
+    write!(file, ".section .text\n.global main\nmain:\n")?;
+
+    //Add slots for goose and teacher and goose pointer, sp has to stay 16 byte aligned
+    let slots = duck_count + 3;
+    writeln!(file, "#Allocate ducks on stack")?;
+    writeln!(file, "  li t0, {}", (slots * 8 + 15) & !15)?;
+    writeln!(file, "  sub sp, sp, t0")?;
+    writeln!(file, "  mv {}, sp", ARRAY_BASE_REG)?;
+
+    //Unlike push $0 the stack space isn't cleared for us
+    writeln!(file, "  li t3, {}", slots)?;
+    writeln!(file, "  li t4, 0")?;
+    writeln!(file, "1:")?;
+    writeln!(file, "  bgeu t4, t3, 2f")?;
+    store_slot("zero", "t4", file)?;
+    writeln!(file, "  addi t4, t4, 1")?;
+    writeln!(file, "  j 1b")?;
+    writeln!(file, "2:")?;
+
+    writeln!(file, "  li {}, {}", DUCK_COUNT_REG, duck_count)?;
+    writeln!(file, "  li {}, 0", GOOSE_INDEX_REG)?;
+
+    Ok(())
+}
+
+//Perform exit syscall
+fn write_exit<W: Write>(file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Exit==========")?;
+    writeln!(file, "  li a0, 0")?;
+    write_syscall(SYS_EXIT, file)
+}
+
+//Kill ourselves with SIGFPE so a division by zero ends the same way on every target
+fn write_divide_by_zero<W: Write>(file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "{}:", DIVIDE_BY_ZERO_LABEL)?;
+    write_syscall(SYS_GETPID, file)?;
+    writeln!(file, "  li a1, {}", SIGFPE)?;
+    write_syscall(SYS_KILL, file)?;
+    //Not reached unless SIGFPE is ignored
+    writeln!(file, "  li a0, {}", 128 + SIGFPE)?;
+    write_syscall(SYS_EXIT, file)
+}
+
+//Conditional branches only reach 4KB, branch around a long jump instead
+fn write_jump_if_zero<W: Write>(register: &str, label: &str, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "  bnez {}, 1f", register)?;
+    writeln!(file, "  jump {}, t0", label)?;
+    writeln!(file, "1:")
+}
+
+//Get n and y index into t5 and t6, and load their values into t3 and t4
+fn load_operands<W: Write>(name: &str, n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#{}==========", name)?;
+    writeln!(file, "#n: {}", n)?;
+    writeln!(file, "#y: {}", y)?;
+
+    get_duck_index("t5", n, file)?;
+    get_duck_index("t6", y, file)?;
+
+    load_slot("t3", "t5", file)?;
+    load_slot("t4", "t6", file)
+}
+
+//Move result -> Goose, then update goose index to n (held in t5)
+fn store_result<W: Write>(result: &str, file: &mut W) -> std::io::Result<()> {
+    store_slot(result, GOOSE_INDEX_REG, file)?;
+    writeln!(file, "  mv {}, t5", GOOSE_INDEX_REG)
+}
+
+//Lower given duck instruction to RISC-V
+fn write_add<W: Write>(n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    load_operands("Add", n, y, file)?;
+    writeln!(file, "  add t3, t3, t4")?;
+    store_result("t3", file)
+}
+
+fn write_subtract<W: Write>(n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    load_operands("Subtract", n, y, file)?;
+    //n - y
+    writeln!(file, "  sub t3, t3, t4")?;
+    store_result("t3", file)
+}
+
+fn write_multiply<W: Write>(n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    load_operands("Multiply", n, y, file)?;
+    writeln!(file, "  mul t3, t3, t4")?;
+    store_result("t3", file)
+}
+
+fn write_divide<W: Write>(n: usize, y: usize, file: &mut W) -> std::io::Result<()> {
+    load_operands("Divide", n, y, file)?;
+    //n/y, divu would quietly give all ones for y == 0
+    write_jump_if_zero("t4", DIVIDE_BY_ZERO_LABEL, file)?;
+    writeln!(file, "  divu t3, t3, t4")?;
+    store_result("t3", file)
+}
+
+//Reads a char from stdin
+fn write_input<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Input==========")?;
+    //Zeroed buffer on the stack, end of input reads as 0
+    writeln!(file, "  addi sp, sp, -16")?;
+    writeln!(file, "  sd zero, 0(sp)")?;
+
+    //read(stdin, sp, 1)
+    writeln!(file, "  li a0, 0")?;
+    writeln!(file, "  mv a1, sp")?;
+    writeln!(file, "  li a2, 1")?;
+    write_syscall(SYS_READ, file)?;
+
+    writeln!(file, "  ld t3, 0(sp)")?;
+    writeln!(file, "  addi sp, sp, 16")?;
+
+    get_duck_index("t5", n, file)?;
+    store_result("t3", file)
+}
+
+//Push value to teacher
+fn write_push<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Push==========")?;
+    get_teacher_index("t6", file)?;
+    get_duck_index("t5", n, file)?;
+    load_slot("t3", "t5", file)?;
+
+    //Move N -> Teacher, then N -> Goose and update goose index
+    store_slot("t3", "t6", file)?;
+    store_result("t3", file)
+}
+
+//Pop value from teacher to goose
+fn write_pop<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Pop==========")?;
+    //Load teacher
+    get_teacher_index("t6", file)?;
+    load_slot("t3", "t6", file)?;
+
+    //Set teacher to 0
+    store_slot("zero", "t6", file)?;
+
+    //Move teacher -> Goose and update goose index
+    get_duck_index("t5", n, file)?;
+    store_result("t3", file)
+}
+
+//The source loop id is only kept as a comment
+fn write_loop_begin<W: Write>(
+    cond: usize,
+    id: usize,
+    labels: &LoopLabels,
+    file: &mut W,
+) -> std::io::Result<()> {
+    writeln!(file, "#LoopBegin==========")?;
+    writeln!(file, "#id: {}", id)?;
+    writeln!(file, "{}:", labels.start)?;
+
+    get_duck_index("t5", cond, file)?;
+    load_slot("t3", "t5", file)?;
+    write_jump_if_zero("t3", &labels.end, file)
+}
+
+fn write_loop_end<W: Write>(id: usize, labels: &LoopLabels, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#LoopEnd==========")?;
+    writeln!(file, "#id: {}", id)?;
+    writeln!(file, "  jump {}, t0", labels.start)?;
+    writeln!(file, "{}:", labels.end)?;
+
+    Ok(())
+}
+
+fn write_set<W: Write>(n: usize, value: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Set==========")?;
+    writeln!(file, "#n: {}", n)?;
+    writeln!(file, "#value: {}", value)?;
+
+    get_duck_index("t5", n, file)?;
+    writeln!(file, "  li t3, {}", value)?;
+    store_result("t3", file)
+}
+
+//Goose to duck n, nothing stored
+fn write_move<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Move==========")?;
+    writeln!(file, "#n: {}", n)?;
+
+    get_duck_index("t5", n, file)?;
+    writeln!(file, "  mv {}, t5", GOOSE_INDEX_REG)
+}
+
+//write(stdout, sp, 1) with the low byte of register on the stack
+fn write_char<W: Write>(register: &str, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "  addi sp, sp, -16")?;
+    writeln!(file, "  sd {}, 0(sp)", register)?;
+    writeln!(file, "  li a0, 1")?;
+    writeln!(file, "  mv a1, sp")?;
+    writeln!(file, "  li a2, 1")?;
+    write_syscall(SYS_WRITE, file)?;
+    writeln!(file, "  addi sp, sp, 16")
+}
+
+fn write_print<W: Write>(n: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#Print==========")?;
+
+    get_duck_index("t5", n, file)?;
+    load_slot("t3", "t5", file)?;
+    write_char("t3", file)
+}
+
+fn write_print_value<W: Write>(value: usize, file: &mut W) -> std::io::Result<()> {
+    writeln!(file, "#PrintValue==========")?;
+
+    writeln!(file, "  li t3, {}", value)?;
+    write_char("t3", file)
+}
+
+pub struct Riscv64<W: Write> {
+    file: W,
+}
+
+impl<W: Write> Riscv64<W> {
+    pub fn new(file: W) -> Riscv64<W> {
+        Riscv64 { file }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.file
+    }
+}
+
+impl<W: Write> Backend for Riscv64<W> {
+    fn prologue(&mut self, program: &Program) -> std::io::Result<()> {
+        write_header(program.duck_count(), &mut self.file)
+    }
+
+    fn epilogue(&mut self) -> std::io::Result<()> {
+        write_exit(&mut self.file)?;
+        write_divide_by_zero(&mut self.file)
+    }
+
+    fn end(&mut self) -> std::io::Result<()> {
+        write_exit(&mut self.file)
+    }
+
+    fn print(&mut self, n: usize) -> std::io::Result<()> {
+        write_print(n, &mut self.file)
+    }
+
+    fn add(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_add(n, y, &mut self.file)
+    }
+
+    fn subtract(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_subtract(n, y, &mut self.file)
+    }
+
+    fn multiply(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_multiply(n, y, &mut self.file)
+    }
+
+    fn divide(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        write_divide(n, y, &mut self.file)
+    }
+
+    fn input(&mut self, n: usize) -> std::io::Result<()> {
+        write_input(n, &mut self.file)
+    }
+
+    fn push(&mut self, n: usize) -> std::io::Result<()> {
+        write_push(n, &mut self.file)
+    }
+
+    fn pop(&mut self, n: usize) -> std::io::Result<()> {
+        write_pop(n, &mut self.file)
+    }
+
+    fn loop_begin(&mut self, cond: usize, id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        write_loop_begin(cond, id, labels, &mut self.file)
+    }
+
+    fn loop_end(&mut self, id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        write_loop_end(id, labels, &mut self.file)
+    }
+
+    fn set(&mut self, n: usize, value: usize) -> std::io::Result<()> {
+        write_set(n, value, &mut self.file)
+    }
+
+    fn move_goose(&mut self, n: usize) -> std::io::Result<()> {
+        write_move(n, &mut self.file)
+    }
+
+    fn print_value(&mut self, value: usize) -> std::io::Result<()> {
+        write_print_value(value, &mut self.file)
+    }
+}
+
+//Write program as RV64GC assembly (GNU syntax) for Linux to out
+pub fn lower_to_writer(program: &Program, out: &mut impl Write) -> std::io::Result<()> {
+    backend::lower(program, &mut Riscv64::new(out))
+}
+
+//Program as RV64GC assembly (GNU syntax) for Linux in a string
+pub fn lower_to_string(program: &Program) -> std::io::Result<String> {
+    let mut out = Vec::new();
+    lower_to_writer(program, &mut out)?;
+
+    backend::into_text(out)
+}
+
+//Write program as RV64GC assembly (GNU syntax) for Linux
+pub fn lower_program(program: &Program, file_out_name: &str) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(file_out_name)?);
+    lower_to_writer(program, &mut file)?;
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn divide_checks_jump_to_one_shared_routine() {
+        let asm = lower_to_string(&testing::divides_by_zero()).unwrap();
+
+        assert!(
+            asm.contains("  bnez t4, 1f\n  jump divide_by_zero, t0\n1:\n  divu t3, t3, t4\n"),
+            "{}",
+            asm
+        );
+        assert_eq!(asm.matches("\ndivide_by_zero:\n").count(), 1);
+    }
+
+    #[test]
+    fn loop_tests_branch_around_a_long_jump() {
+        let asm = lower_to_string(&testing::every_instruction()).unwrap();
+
+        //No beqz reaching straight for a label that may be more than 4KB away
+        assert!(!asm.contains("beqz"), "{}", asm);
+        assert_eq!(asm.matches("  bnez t3, 1f\n  jump ").count(), 2, "{}", asm);
+    }
+
+    //The interpreter is the oracle, when there is a cross toolchain to check against
+    #[test]
+    fn programs_match_the_interpreter() {
+        let run = |test: &str, program: &Program, input: &[u8]| {
+            let asm = lower_to_string(program).unwrap();
+            testing::run_cross("riscv64-linux-gnu-gcc", "qemu-riscv64", test, &asm, input)
+        };
+
+        let (input, printed) = testing::EVERY_INSTRUCTION_IO;
+        let program = testing::every_instruction();
+        if let Some(output) = run("riscv64_every_instruction", &program, input) {
+            assert!(output.status.success());
+            assert_eq!(output.stdout, testing::interpret(&program, input));
+            assert_eq!(output.stdout, printed);
+        }
+
+        if let Some(output) = run("riscv64_divides_by_zero", &testing::divides_by_zero(), b"") {
+            assert!(!output.status.success());
+            assert_eq!(output.stdout, b"A");
+        }
+    }
+}
@@ -0,0 +1,316 @@
+//Hayden Coffey
+//Rotation analysis: where the goose is before each instruction, when that can be worked
+//out at compile time. The goose starts at 0 and every moving instruction adds a constant,
+//so it stays known through straight-line code. A loop keeps it known only if its body
+//moves the goose a multiple of the ring size, since it may run any number of times.
+use crate::check::Loops;
+use crate::instruction::Instruction;
+use crate::program::Program;
+
+//Goose position before every instruction, indexed by instruction position
+#[derive(Clone, Debug)]
+pub struct Rotation {
+    goose: Vec<Option<usize>>,
+    ring_size: usize,
+}
+
+impl Rotation {
+    //Ring index of the goose before the instruction at pos, if known
+    pub fn goose(&self, pos: usize) -> Option<usize> {
+        self.goose.get(pos).copied().flatten()
+    }
+
+    //Ring index of the duck n places after the goose, before the instruction at pos
+    pub fn duck(&self, pos: usize, n: usize) -> Option<usize> {
+        rotate(self.goose(pos), n, self.ring_size)
+    }
+
+    //Number of instructions with a known goose
+    pub fn known(&self) -> usize {
+        self.goose.iter().filter(|goose| goose.is_some()).count()
+    }
+
+    //Array slots (ring slots, then the teacher) the instruction at pos reads and writes,
+    //if the goose is known there
+    pub fn access(&self, program: &Program, pos: usize) -> Option<Access> {
+        let goose = self.goose(pos)?;
+        let teacher = self.ring_size;
+        let duck = |n: usize| (goose + n % self.ring_size) % self.ring_size;
+
+        let (reads, writes) = match program.instructions()[pos].inst {
+            Instruction::End
+            | Instruction::LoopEnd { .. }
+            | Instruction::Move { .. }
+            | Instruction::PrintValue { .. } => (vec![], vec![]),
+            Instruction::Print { n } | Instruction::LoopBegin { cond: n, .. } => {
+                (vec![duck(n)], vec![])
+            }
+            Instruction::Add { n, y }
+            | Instruction::Subtract { n, y }
+            | Instruction::Multiply { n, y }
+            | Instruction::Divide { n, y } => (vec![duck(n), duck(y)], vec![goose]),
+            Instruction::Input { .. } | Instruction::Set { .. } => (vec![], vec![goose]),
+            Instruction::Push { n } => (vec![duck(n)], vec![teacher, goose]),
+            //The teacher is cleared after being read
+            Instruction::Pop { .. } => (vec![teacher], vec![teacher, goose]),
+        };
+
+        Some(Access { reads, writes })
+    }
+
+    //How often each array slot (ring slots, then the teacher) is accessed where the goose
+    //is known, with accesses in loops weighted by nesting depth. A slot's spill cost, for
+    //deciding which ones are worth keeping in a register.
+    pub fn slot_uses(&self, program: &Program) -> Vec<usize> {
+        let mut uses = vec![0usize; self.ring_size + 1];
+
+        let mut depth = 0;
+        for (pos, inst) in program.iter().enumerate() {
+            let weight = LOOP_WEIGHT.saturating_pow(depth);
+            match inst.inst {
+                Instruction::LoopBegin { .. } => depth += 1,
+                Instruction::LoopEnd { .. } => depth = depth.saturating_sub(1),
+                _ => (),
+            }
+
+            if let Some(access) = self.access(program, pos) {
+                for slot in access.reads.into_iter().chain(access.writes) {
+                    uses[slot] = uses[slot].saturating_add(weight);
+                }
+            }
+        }
+
+        uses
+    }
+}
+
+//Slots one instruction reads and writes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Access {
+    pub reads: Vec<usize>,
+    pub writes: Vec<usize>,
+}
+
+//Loops are assumed to run this many times when weighing slot accesses
+const LOOP_WEIGHT: usize = 8;
+
+//Move a ring position n places, None stays unknown
+fn rotate(goose: Option<usize>, n: usize, ring_size: usize) -> Option<usize> {
+    goose.map(|goose| (goose + n % ring_size) % ring_size)
+}
+
+//Net rotation of every loop body, indexed by the LoopBegin position. None when a nested
+//loop rotates, the body then moves the goose by a different amount each time.
+fn loop_rotations(program: &Program, loops: &Loops) -> Vec<Option<usize>> {
+    let ring_size = program.ring_size();
+    let mut net = vec![None; program.len()];
+
+    //Rotation so far of the program and each enclosing loop body, innermost last
+    let mut open = vec![Some(0)];
+    for (pos, inst) in program.iter().enumerate() {
+        match inst.inst {
+            Instruction::LoopBegin { .. } => open.push(Some(0)),
+            Instruction::LoopEnd { .. } => {
+                let body = open.pop().unwrap_or_default();
+                if let Some(begin) = loops.partner(pos) {
+                    net[begin] = body;
+                }
+
+                //Runs an unknown number of times, so only a whole turn keeps the goose known
+                if body != Some(0) {
+                    if let Some(outer) = open.last_mut() {
+                        *outer = None;
+                    }
+                }
+            }
+            inst => {
+                if let (Some(n), Some(rotation)) = (inst.goose_target(), open.last_mut()) {
+                    *rotation = rotate(*rotation, n, ring_size);
+                }
+            }
+        }
+    }
+
+    net
+}
+
+//Work out the goose position before each instruction of a checked program
+pub fn analyze(program: &Program, loops: &Loops) -> Rotation {
+    let ring_size = program.ring_size();
+    let net = loop_rotations(program, loops);
+    let mut goose = vec![None; program.len()];
+
+    let mut current = Some(0);
+    //Goose on entry to each enclosing loop, where it is again after the loop exits
+    let mut heads = Vec::new();
+    for (pos, inst) in program.iter().enumerate() {
+        match inst.inst {
+            Instruction::LoopBegin { .. } => {
+                if net[pos] != Some(0) {
+                    current = None;
+                }
+                goose[pos] = current;
+                heads.push(current);
+            }
+            Instruction::LoopEnd { .. } => {
+                goose[pos] = current;
+                current = heads.pop().unwrap_or_default();
+            }
+            inst => {
+                goose[pos] = current;
+                if let Some(n) = inst.goose_target() {
+                    current = rotate(current, n, ring_size);
+                }
+            }
+        }
+    }
+
+    Rotation { goose, ring_size }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check;
+    use crate::instruction::Instruction::*;
+    //Tests use two ducks, so a ring of 3
+    use crate::testing::program;
+
+    fn goose_before_each(program: &Program) -> Vec<Option<usize>> {
+        let loops = check::check(program).unwrap();
+        let rotation = analyze(program, &loops);
+        (0..program.len()).map(|pos| rotation.goose(pos)).collect()
+    }
+
+    #[test]
+    fn straight_line_code_is_known() {
+        let program = program(
+            2,
+            &[
+                Set { n: 1, value: 5 },
+                Add { n: 2, y: 0 },
+                Print { n: 1 },
+                End,
+            ],
+        );
+        let loops = check::check(&program).unwrap();
+        let rotation = analyze(&program, &loops);
+
+        assert_eq!(
+            goose_before_each(&program),
+            [Some(0), Some(1), Some(0), Some(0)]
+        );
+        assert_eq!(rotation.duck(2, 1), Some(1));
+        assert_eq!(rotation.known(), 4);
+    }
+
+    #[test]
+    fn loops_turning_a_whole_ring_stay_known() {
+        let program = program(
+            2,
+            &[
+                Set { n: 1, value: 3 },
+                LoopBegin { cond: 0, id: 0 },
+                Set { n: 1, value: 0 },
+                Set { n: 2, value: 0 },
+                LoopEnd { id: 0 },
+                Print { n: 0 },
+                End,
+            ],
+        );
+        let loops = check::check(&program).unwrap();
+
+        assert_eq!(loop_rotations(&program, &loops)[1], Some(0));
+        assert_eq!(
+            goose_before_each(&program),
+            [
+                Some(0),
+                Some(1),
+                Some(1),
+                Some(2),
+                Some(1),
+                Some(1),
+                Some(1)
+            ]
+        );
+    }
+
+    #[test]
+    fn rotating_loops_lose_the_goose() {
+        let program = program(
+            2,
+            &[
+                Set { n: 1, value: 3 },
+                LoopBegin { cond: 0, id: 0 },
+                Set { n: 1, value: 0 },
+                LoopEnd { id: 0 },
+                Print { n: 0 },
+                End,
+            ],
+        );
+        let loops = check::check(&program).unwrap();
+
+        assert_eq!(loop_rotations(&program, &loops)[1], Some(1));
+        //Unknown in the body too, the test runs before and after each turn
+        assert_eq!(
+            goose_before_each(&program),
+            [Some(0), None, None, None, None, None]
+        );
+    }
+
+    #[test]
+    fn rotating_inner_loops_make_the_outer_loop_unknown() {
+        let program = program(
+            2,
+            &[
+                LoopBegin { cond: 0, id: 0 },
+                Set { n: 1, value: 0 },
+                LoopBegin { cond: 0, id: 1 },
+                Set { n: 1, value: 0 },
+                LoopEnd { id: 1 },
+                Set { n: 2, value: 0 },
+                LoopEnd { id: 0 },
+                Print { n: 0 },
+                End,
+            ],
+        );
+        let loops = check::check(&program).unwrap();
+        let net = loop_rotations(&program, &loops);
+
+        //The outer body would turn a whole ring, if not for the inner loop
+        assert_eq!(net[0], None);
+        assert_eq!(net[2], Some(1));
+        assert_eq!(goose_before_each(&program), [None; 9]);
+    }
+
+    #[test]
+    fn still_inner_loops_keep_the_outer_loop_known() {
+        let program = program(
+            2,
+            &[
+                LoopBegin { cond: 0, id: 0 },
+                Set { n: 1, value: 0 },
+                LoopBegin { cond: 2, id: 1 },
+                Set { n: 0, value: 0 },
+                LoopEnd { id: 1 },
+                Set { n: 2, value: 0 },
+                LoopEnd { id: 0 },
+                End,
+            ],
+        );
+
+        assert_eq!(
+            goose_before_each(&program),
+            [
+                Some(0),
+                Some(0),
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(1),
+                Some(0),
+                Some(0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,138 @@
+//Hayden Coffey
+//Fixtures shared by the unit tests
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use crate::instruction::Instruction::{self, *};
+use crate::program::Program;
+
+//Input every_instruction reads and what it prints for it
+pub const EVERY_INSTRUCTION_IO: (&[u8], &[u8]) = (b"ab", b"00\x002");
+
+//Program of duck_count ducks running insts, without source spans
+pub fn program(duck_count: usize, insts: &[Instruction]) -> Program {
+    Program::new(duck_count, insts.iter().map(|&inst| inst.into()).collect())
+}
+
+//Every instruction, once the goose is lost so that indices are worked out at run time
+pub fn every_instruction() -> Program {
+    program(
+        2,
+        &[
+            Set { n: 1, value: 1 },
+            //Runs once and turns the goose one place
+            LoopBegin { cond: 2, id: 0 },
+            Set { n: 1, value: 0 },
+            LoopEnd { id: 0 },
+            Input { n: 1 },
+            Set { n: 1, value: 2 },
+            Divide { n: 1, y: 2 },
+            Print { n: 2 },
+            Push { n: 2 },
+            Input { n: 2 },
+            Subtract { n: 1, y: 2 },
+            Print { n: 1 },
+            Pop { n: 1 },
+            Multiply { n: 2, y: 0 },
+            Add { n: 1, y: 1 },
+            Print { n: 0 },
+            LoopBegin { cond: 1, id: 1 },
+            Print { n: 1 },
+            Set { n: 2, value: 0 },
+            LoopEnd { id: 1 },
+            End,
+        ],
+    )
+}
+
+//Prints "A", then divides by a duck that is still 0
+pub fn divides_by_zero() -> Program {
+    program(
+        1,
+        &[
+            Set {
+                n: 1,
+                value: b'A' as usize,
+            },
+            Print { n: 1 },
+            Divide { n: 1, y: 0 },
+            End,
+        ],
+    )
+}
+
+//What the interpreter prints for program on input, which compiled code has to match
+pub fn interpret(program: &Program, input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    crate::interp::run(program, input, &mut out).unwrap();
+    out
+}
+
+//Path in the temp directory unique to this test process
+pub fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("gdd-test-{}-{}", std::process::id(), name))
+}
+
+//Run command to completion with input on stdin, capturing stdout
+pub fn run_with_input(command: &mut Command, input: &[u8]) -> Output {
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    //A program that exits without reading leaves a closed pipe behind
+    let _ = child.stdin.take().unwrap().write_all(input);
+
+    child.wait_with_output().unwrap()
+}
+
+//Run the executable at exe on input and delete it
+pub fn run_executable(exe: &Path, input: &[u8]) -> Output {
+    let output = run_with_input(&mut Command::new(exe), input);
+    std::fs::remove_file(exe).unwrap();
+
+    output
+}
+
+//Whether program can be run here. Tests needing a missing tool say so and pass, since
+//the tools are optional for gdd itself.
+pub fn have_tool(program: &str, test: &str) -> bool {
+    let found = Command::new(program)
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok();
+    if !found {
+        eprintln!("skipping {}: {} not found", test, program);
+    }
+
+    found
+}
+
+//Assemble and statically link asm with the cross compiler gcc, then run it under qemu on
+//input. None when either tool is missing.
+pub fn run_cross(gcc: &str, qemu: &str, test: &str, asm: &str, input: &[u8]) -> Option<Output> {
+    if !have_tool(gcc, test) || !have_tool(qemu, test) {
+        return None;
+    }
+
+    let asm_path = temp_path(&format!("{}.s", test));
+    let exe = temp_path(test);
+    std::fs::write(&asm_path, asm).unwrap();
+    let status = Command::new(gcc)
+        .arg("-static")
+        .arg(&asm_path)
+        .arg("-o")
+        .arg(&exe)
+        .status()
+        .unwrap();
+    std::fs::remove_file(&asm_path).unwrap();
+    assert!(status.success(), "{} failed on {}", gcc, test);
+
+    let output = run_with_input(Command::new(qemu).arg(&exe), input);
+    std::fs::remove_file(&exe).unwrap();
+
+    Some(output)
+}
@@ -0,0 +1,303 @@
+//Hayden Coffey
+//External assembler and linker invocation
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+//Program used to turn assembly into an object file, or objects into an executable
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Gcc,
+    Cc,
+    Clang,
+    //GNU as when assembling, GNU ld when linking
+    Binutils,
+}
+
+impl Tool {
+    pub fn from_name(name: &str) -> Option<Tool> {
+        match name {
+            "gcc" => Some(Tool::Gcc),
+            "cc" => Some(Tool::Cc),
+            "clang" => Some(Tool::Clang),
+            "as" | "ld" => Some(Tool::Binutils),
+            _ => None,
+        }
+    }
+
+    fn assembler_command(self) -> &'static str {
+        match self {
+            Tool::Gcc => "gcc",
+            Tool::Cc => "cc",
+            Tool::Clang => "clang",
+            Tool::Binutils => "as",
+        }
+    }
+
+    fn linker_command(self) -> &'static str {
+        match self {
+            Tool::Gcc => "gcc",
+            Tool::Cc => "cc",
+            Tool::Clang => "clang",
+            Tool::Binutils => "ld",
+        }
+    }
+}
+
+//Assembler message tied to a line of the generated assembly
+#[derive(Clone, Debug)]
+pub struct AsmDiagnostic {
+    pub line: usize,
+    pub message: String,
+    //Text of the offending line, read back from the .s file
+    pub source_line: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ToolchainError {
+    Spawn {
+        command: String,
+        why: std::io::Error,
+    },
+    Failed {
+        command: String,
+        status: ExitStatus,
+        //Assembly the diagnostics point into, None for the linker
+        input: Option<PathBuf>,
+        stderr: String,
+        diagnostics: Vec<AsmDiagnostic>,
+        //Lines of stderr that aren't diagnostics, such as notes and linker errors
+        unmatched: Vec<String>,
+    },
+}
+
+impl fmt::Display for ToolchainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ToolchainError::Spawn { command, why } => {
+                write!(f, "Couldn't run {}: {}", command, why)
+            }
+            ToolchainError::Failed {
+                command,
+                status,
+                input,
+                stderr,
+                diagnostics,
+                unmatched,
+            } => {
+                writeln!(f, "{} failed ({})", command, status)?;
+
+                let input = match input {
+                    Some(input) if !diagnostics.is_empty() => input,
+                    _ => return write!(f, "{}", stderr.trim_end()),
+                };
+
+                for diag in diagnostics {
+                    let line_no = diag.line.to_string();
+                    let pad = " ".repeat(line_no.len());
+                    writeln!(f, "{}--> {}:{}", pad, input.display(), diag.line)?;
+                    if let Some(text) = &diag.source_line {
+                        writeln!(f, "{} |", pad)?;
+                        writeln!(f, "{} | {}", line_no, text)?;
+                    }
+                    writeln!(f, "{} = {}", pad, diag.message)?;
+                }
+                for line in unmatched {
+                    writeln!(f, "{}", line)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for ToolchainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ToolchainError::Spawn { why, .. } => Some(why),
+            ToolchainError::Failed { .. } => None,
+        }
+    }
+}
+
+//Line number and message of a "<prefix><line>: message" line
+fn split_diagnostic<'a>(line: &'a str, prefix: &str) -> Option<(usize, &'a str)> {
+    let (line_no, message) = line.strip_prefix(prefix)?.split_once(':')?;
+    Some((line_no.parse().ok()?, message))
+}
+
+//Pick "<input>:<line>: message" and "<input>:<line>:<col>: message" lines out of tool
+//output, returning them along with every other line of stderr
+fn parse_diagnostics(input: &Path, stderr: &str) -> (Vec<AsmDiagnostic>, Vec<String>) {
+    let source = fs::read_to_string(input).unwrap_or_default();
+    let source_lines: Vec<&str> = source.lines().collect();
+    let prefix = format!("{}:", input.display());
+
+    let mut diagnostics = Vec::new();
+    let mut unmatched = Vec::new();
+    for line in stderr.lines() {
+        let (line_no, message) = match split_diagnostic(line, &prefix) {
+            None => {
+                unmatched.push(String::from(line));
+                continue;
+            }
+            Some(parts) => parts,
+        };
+
+        //Skip the column number clang adds
+        let message = match message.split_once(':') {
+            Some((col, message)) if col.parse::<usize>().is_ok() => message,
+            _ => message,
+        };
+
+        diagnostics.push(AsmDiagnostic {
+            line: line_no,
+            message: String::from(message.trim()),
+            source_line: line_no
+                .checked_sub(1)
+                .and_then(|i| source_lines.get(i))
+                .map(|text| String::from(*text)),
+        });
+    }
+
+    (diagnostics, unmatched)
+}
+
+//Run the command to completion, turning a non-zero exit into a ToolchainError. Messages
+//are matched against the lines of input when given.
+fn run(mut command: Command, input: Option<&Path>) -> Result<(), ToolchainError> {
+    let name = command.get_program().to_string_lossy().into_owned();
+    let output = command.output().map_err(|why| ToolchainError::Spawn {
+        command: name.clone(),
+        why,
+    })?;
+
+    if output.status.success() {
+        return Ok(());
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    let (diagnostics, unmatched) = match input {
+        Some(input) => parse_diagnostics(input, &stderr),
+        None => (Vec::new(), stderr.lines().map(String::from).collect()),
+    };
+    Err(ToolchainError::Failed {
+        command: name,
+        status: output.status,
+        input: input.map(Path::to_path_buf),
+        stderr,
+        diagnostics,
+        unmatched,
+    })
+}
+
+//Assemble asm_path into the object file obj_path
+pub fn assemble(tool: Tool, asm_path: &Path, obj_path: &Path) -> Result<(), ToolchainError> {
+    let mut command = Command::new(tool.assembler_command());
+    if tool != Tool::Binutils {
+        command.arg("-c");
+    }
+    command.arg(asm_path).arg("-g").arg("-o").arg(obj_path);
+
+    run(command, Some(asm_path))
+}
+
+//Link obj_path into an executable at exe_path
+pub fn link(tool: Tool, obj_path: &Path, exe_path: &Path) -> Result<(), ToolchainError> {
+    let mut command = Command::new(tool.linker_command());
+    if tool == Tool::Binutils {
+        //No C runtime, enter the generated code directly. It exits with a syscall and
+        //never returns.
+        command.arg("-e").arg("main");
+    }
+    command.arg(obj_path).arg("-o").arg(exe_path);
+
+    //Linker messages refer to symbols and sections, not lines of the object file
+    run(command, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{have_tool, temp_path};
+
+    #[test]
+    fn diagnostics_keep_unmatched_lines() {
+        let asm_path = temp_path("diag.s");
+        fs::write(&asm_path, "  nop\n  bogus %rax\n").unwrap();
+        let stderr = format!(
+            concat!(
+                "{0}: Assembler messages:\n",
+                "{0}:2: Error: no such instruction: `bogus %rax'\n",
+                "note: something else\n"
+            ),
+            asm_path.display()
+        );
+
+        let (diagnostics, unmatched) = parse_diagnostics(&asm_path, &stderr);
+        fs::remove_file(&asm_path).unwrap();
+
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, 2);
+        assert_eq!(diagnostics[0].source_line.as_deref(), Some("  bogus %rax"));
+        assert_eq!(
+            diagnostics[0].message,
+            "Error: no such instruction: `bogus %rax'"
+        );
+        assert_eq!(unmatched.len(), 2);
+        assert!(unmatched[1].contains("something else"));
+    }
+
+    #[test]
+    fn assembler_errors_point_at_the_line() {
+        if !have_tool("gcc", "assembler_errors_point_at_the_line") {
+            return;
+        }
+        let asm_path = temp_path("bad.s");
+        let obj_path = temp_path("bad.o");
+        fs::write(&asm_path, "  nop\n  bogus %rax\n").unwrap();
+
+        let result = assemble(Tool::Gcc, &asm_path, &obj_path);
+        fs::remove_file(&asm_path).unwrap();
+        let why = match result {
+            Err(why) => why.to_string(),
+            Ok(()) => panic!("bogus instruction assembled"),
+        };
+
+        assert!(why.contains("2 |   bogus %rax"), "{}", why);
+        assert!(why.contains("Assembler messages"), "{}", why);
+    }
+
+    #[test]
+    fn linker_errors_are_printed_as_is() {
+        if !have_tool("gcc", "linker_errors_are_printed_as_is") {
+            return;
+        }
+        let asm_path = temp_path("nomain.s");
+        let obj_path = temp_path("nomain.o");
+        let exe_path = temp_path("nomain");
+        fs::write(&asm_path, "  .text\nnot_main:\n  ret\n").unwrap();
+
+        let result = assemble(Tool::Gcc, &asm_path, &obj_path)
+            .and_then(|_| link(Tool::Gcc, &obj_path, &exe_path));
+        let _ = fs::remove_file(&asm_path);
+        let _ = fs::remove_file(&obj_path);
+        let why = match result {
+            Err(why) => why,
+            Ok(()) => panic!("linked without main"),
+        };
+
+        match &why {
+            ToolchainError::Failed {
+                input, diagnostics, ..
+            } => {
+                assert!(input.is_none());
+                assert!(diagnostics.is_empty());
+            }
+            ToolchainError::Spawn { .. } => unreachable!(),
+        }
+        assert!(why.to_string().contains("main"), "{}", why);
+    }
+}
@@ -0,0 +1,513 @@
+//Hayden Coffey
+//Binary encoder for the modules produced by wasm_gen
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use crate::program::Program;
+use crate::wasm_gen::{self, Module, Wasm};
+
+const MAGIC: [u8; 8] = [0x00, b'a', b's', b'm', 0x01, 0x00, 0x00, 0x00];
+
+const SECTION_TYPE: u8 = 1;
+const SECTION_IMPORT: u8 = 2;
+const SECTION_FUNCTION: u8 = 3;
+const SECTION_MEMORY: u8 = 5;
+const SECTION_EXPORT: u8 = 7;
+const SECTION_CODE: u8 = 10;
+
+const TYPE_I32: u8 = 0x7f;
+const TYPE_I64: u8 = 0x7e;
+const TYPE_FUNC: u8 = 0x60;
+const BLOCK_EMPTY: u8 = 0x40;
+
+const KIND_FUNC: u8 = 0x00;
+const KIND_MEMORY: u8 = 0x02;
+
+fn unsigned(mut value: u64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn signed(mut value: i64, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        //Done once the rest is only sign bits and the sign bit of this byte agrees
+        if (value == 0 && byte & 0x40 == 0) || (value == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn name(text: &str, out: &mut Vec<u8>) {
+    unsigned(text.len() as u64, out);
+    out.extend_from_slice(text.as_bytes());
+}
+
+//Section id followed by its size prefixed contents
+fn section(id: u8, contents: &[u8], out: &mut Vec<u8>) {
+    out.push(id);
+    unsigned(contents.len() as u64, out);
+    out.extend_from_slice(contents);
+}
+
+//Alignment (log2 of the access size) and offset
+fn memarg(align: u8, offset: u32, out: &mut Vec<u8>) {
+    out.push(align);
+    unsigned(offset as u64, out);
+}
+
+//Branch depth of a label, counting outwards from the innermost open block
+fn depth(labels: &[String], label: &str) -> std::io::Result<u64> {
+    labels
+        .iter()
+        .rev()
+        .position(|open| open == label)
+        .map(|depth| depth as u64)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Undefined label {}", label)))
+}
+
+//Encode the entry function body, resolving label names to branch depths
+fn encode_body(body: &[Wasm], out: &mut Vec<u8>) -> std::io::Result<()> {
+    let mut labels: Vec<String> = Vec::new();
+
+    for op in body {
+        match op {
+            Wasm::Comment(_) => (),
+            Wasm::I32Const(value) => {
+                out.push(0x41);
+                signed(*value as i64, out);
+            }
+            Wasm::I64Const(value) => {
+                out.push(0x42);
+                signed(*value, out);
+            }
+            Wasm::LocalGet(local) => {
+                out.push(0x20);
+                unsigned(*local as u64, out);
+            }
+            Wasm::LocalSet(local) => {
+                out.push(0x21);
+                unsigned(*local as u64, out);
+            }
+            Wasm::LocalTee(local) => {
+                out.push(0x22);
+                unsigned(*local as u64, out);
+            }
+            Wasm::I64Add => out.push(0x7c),
+            Wasm::I64Sub => out.push(0x7d),
+            Wasm::I64Mul => out.push(0x7e),
+            Wasm::I64DivU => out.push(0x80),
+            Wasm::I64RemU => out.push(0x82),
+            Wasm::I64Shl => out.push(0x86),
+            Wasm::I64Eqz => out.push(0x50),
+            Wasm::I32WrapI64 => out.push(0xa7),
+            Wasm::I32Store(offset) => {
+                out.push(0x36);
+                memarg(2, *offset, out);
+            }
+            Wasm::I64Load(offset) => {
+                out.push(0x29);
+                memarg(3, *offset, out);
+            }
+            Wasm::I64Store(offset) => {
+                out.push(0x37);
+                memarg(3, *offset, out);
+            }
+            Wasm::I64Store8(offset) => {
+                out.push(0x3c);
+                memarg(0, *offset, out);
+            }
+            Wasm::Call(func) => {
+                out.push(0x10);
+                unsigned(*func as u64, out);
+            }
+            Wasm::Drop => out.push(0x1a),
+            Wasm::Block(label) => {
+                out.extend_from_slice(&[0x02, BLOCK_EMPTY]);
+                labels.push(label.clone());
+            }
+            Wasm::Loop(label) => {
+                out.extend_from_slice(&[0x03, BLOCK_EMPTY]);
+                labels.push(label.clone());
+            }
+            Wasm::End => {
+                out.push(0x0b);
+                labels.pop();
+            }
+            Wasm::Br(label) => {
+                out.push(0x0c);
+                unsigned(depth(&labels, label)?, out);
+            }
+            Wasm::BrIf(label) => {
+                out.push(0x0d);
+                unsigned(depth(&labels, label)?, out);
+            }
+        }
+    }
+
+    //End of the function itself
+    out.push(0x0b);
+    Ok(())
+}
+
+//Encode a complete module: WASI imports, exported memory and the exported entry function
+pub fn encode(module: &Module) -> std::io::Result<Vec<u8>> {
+    let mut out = MAGIC.to_vec();
+
+    //Types: 0 for fd_read/fd_write, 1 for proc_exit, 2 for the entry function
+    let mut types = Vec::new();
+    unsigned(3, &mut types);
+    types.extend_from_slice(&[TYPE_FUNC, 4, TYPE_I32, TYPE_I32, TYPE_I32, TYPE_I32]);
+    types.extend_from_slice(&[1, TYPE_I32]);
+    types.extend_from_slice(&[TYPE_FUNC, 1, TYPE_I32, 0]);
+    types.extend_from_slice(&[TYPE_FUNC, 0, 0]);
+    section(SECTION_TYPE, &types, &mut out);
+
+    let mut imports = Vec::new();
+    unsigned(wasm_gen::IMPORTS.len() as u64, &mut imports);
+    for import in wasm_gen::IMPORTS {
+        name(wasm_gen::IMPORT_MODULE, &mut imports);
+        name(import, &mut imports);
+        imports.push(KIND_FUNC);
+        imports.push(if import == "proc_exit" { 1 } else { 0 });
+    }
+    section(SECTION_IMPORT, &imports, &mut out);
+
+    section(SECTION_FUNCTION, &[1, 2], &mut out);
+
+    //One memory with only a minimum size
+    let mut memory = vec![1, 0];
+    unsigned(module.memory_pages as u64, &mut memory);
+    section(SECTION_MEMORY, &memory, &mut out);
+
+    let mut exports = Vec::new();
+    unsigned(2, &mut exports);
+    name("memory", &mut exports);
+    exports.extend_from_slice(&[KIND_MEMORY, 0]);
+    name(wasm_gen::ENTRY_NAME, &mut exports);
+    exports.push(KIND_FUNC);
+    unsigned(wasm_gen::IMPORTS.len() as u64, &mut exports);
+    section(SECTION_EXPORT, &exports, &mut out);
+
+    let mut function = Vec::new();
+    //One run of i64 locals
+    function.push(1);
+    unsigned(wasm_gen::LOCAL_NAMES.len() as u64, &mut function);
+    function.push(TYPE_I64);
+    encode_body(&module.body, &mut function)?;
+
+    let mut code = Vec::new();
+    unsigned(1, &mut code);
+    unsigned(function.len() as u64, &mut code);
+    code.extend_from_slice(&function);
+    section(SECTION_CODE, &code, &mut out);
+
+    Ok(out)
+}
+
+//Lower, encode and write a binary module, runnable with any WASI runtime
+pub fn write_module(program: &Program, path: &Path) -> std::io::Result<()> {
+    let module = wasm_gen::lower_to_module(program)?;
+    fs::write(path, encode(&module)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    fn read_unsigned(bytes: &[u8], pos: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[*pos];
+            *pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return value;
+            }
+        }
+    }
+
+    fn read_signed(bytes: &[u8], pos: &mut usize) -> i64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[*pos];
+            *pos += 1;
+            value |= ((byte & 0x7f) as i64) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return value;
+            }
+        }
+    }
+
+    //Id and contents of each section
+    fn sections(module: &[u8]) -> Vec<(u8, &[u8])> {
+        assert_eq!(module[..8], MAGIC);
+        let mut sections = Vec::new();
+        let mut pos = 8;
+        while pos < module.len() {
+            let id = module[pos];
+            pos += 1;
+            let size = read_unsigned(module, &mut pos) as usize;
+            sections.push((id, &module[pos..pos + size]));
+            pos += size;
+        }
+        assert_eq!(pos, module.len());
+
+        sections
+    }
+
+    //Instructions of the entry function body, written the way wasm_gen prints them but
+    //with branch depths in place of label names
+    fn disassemble(code: &[u8]) -> Vec<String> {
+        let mut pos = 0;
+        assert_eq!(read_unsigned(code, &mut pos), 1);
+        let end = read_unsigned(code, &mut pos) as usize + pos;
+        //One run of i64 locals
+        assert_eq!(code[pos..pos + 3], [1, 2, TYPE_I64]);
+        pos += 3;
+
+        let local = |index: u64| wasm_gen::LOCAL_NAMES[index as usize];
+        let mut ops = Vec::new();
+        while pos < end {
+            let op = code[pos];
+            pos += 1;
+            let text = match op {
+                0x41 => format!("i32.const {}", read_signed(code, &mut pos)),
+                0x42 => format!("i64.const {}", read_signed(code, &mut pos)),
+                0x20 => format!("local.get ${}", local(read_unsigned(code, &mut pos))),
+                0x21 => format!("local.set ${}", local(read_unsigned(code, &mut pos))),
+                0x22 => format!("local.tee ${}", local(read_unsigned(code, &mut pos))),
+                0x36 | 0x29 | 0x37 | 0x3c => {
+                    let name = match op {
+                        0x36 => "i32.store",
+                        0x29 => "i64.load",
+                        0x37 => "i64.store",
+                        _ => "i64.store8",
+                    };
+                    pos += 1;
+                    format!("{} offset={}", name, read_unsigned(code, &mut pos))
+                }
+                0x10 => {
+                    let func = read_unsigned(code, &mut pos) as usize;
+                    format!("call ${}", wasm_gen::IMPORTS[func])
+                }
+                0x02 | 0x03 => {
+                    assert_eq!(code[pos], BLOCK_EMPTY);
+                    pos += 1;
+                    String::from(if op == 0x02 { "block" } else { "loop" })
+                }
+                0x0c => format!("br {}", read_unsigned(code, &mut pos)),
+                0x0d => format!("br_if {}", read_unsigned(code, &mut pos)),
+                0x7c => String::from("i64.add"),
+                0x7d => String::from("i64.sub"),
+                0x7e => String::from("i64.mul"),
+                0x80 => String::from("i64.div_u"),
+                0x82 => String::from("i64.rem_u"),
+                0x86 => String::from("i64.shl"),
+                0x50 => String::from("i64.eqz"),
+                0xa7 => String::from("i32.wrap_i64"),
+                0x1a => String::from("drop"),
+                0x0b => String::from("end"),
+                op => panic!("Unexpected opcode {:#x}", op),
+            };
+            ops.push(text);
+        }
+        assert_eq!(pos, end);
+        //End of the function itself
+        assert_eq!(ops.pop().as_deref(), Some("end"));
+
+        ops
+    }
+
+    //Instructions of the .wat entry function, branches resolved to depths
+    fn text_ops(wat: &str) -> Vec<String> {
+        let mut labels: Vec<&str> = Vec::new();
+        let mut ops = Vec::new();
+        let body = wat
+            .lines()
+            .map(str::trim)
+            .skip_while(|line| !line.starts_with("(local"))
+            .filter(|line| !line.starts_with('(') && !line.starts_with(";;"));
+        for line in body {
+            let (op, label) = match line.split_once(" $") {
+                Some((op, label)) if op != "call" && !op.starts_with("local.") => (op, label),
+                _ => {
+                    if line == "end" {
+                        labels.pop();
+                    }
+                    ops.push(String::from(line));
+                    continue;
+                }
+            };
+            match op {
+                "block" | "loop" => {
+                    labels.push(label);
+                    ops.push(String::from(op));
+                }
+                _ => {
+                    let depth = labels.iter().rev().position(|open| *open == label).unwrap();
+                    ops.push(format!("{} {}", op, depth));
+                }
+            }
+        }
+        //Closing parentheses of the function and module
+        assert_eq!(ops.drain(ops.len() - 2..).collect::<Vec<_>>(), [")", ")"]);
+
+        ops
+    }
+
+    #[test]
+    fn leb128() {
+        let encode = |value: u64| {
+            let mut out = Vec::new();
+            unsigned(value, &mut out);
+            out
+        };
+        let encode_signed = |value: i64| {
+            let mut out = Vec::new();
+            signed(value, &mut out);
+            out
+        };
+
+        assert_eq!(encode(0), [0]);
+        assert_eq!(encode(127), [0x7f]);
+        assert_eq!(encode(128), [0x80, 0x01]);
+        assert_eq!(encode(624485), [0xe5, 0x8e, 0x26]);
+        assert_eq!(encode_signed(63), [0x3f]);
+        //64 has the sign bit of the first byte set, so needs a second
+        assert_eq!(encode_signed(64), [0xc0, 0x00]);
+        assert_eq!(encode_signed(-64), [0x40]);
+        assert_eq!(encode_signed(-123456), [0xc0, 0xbb, 0x78]);
+        for value in [i64::MIN, -1, 0, 1, i64::MAX] {
+            let bytes = encode_signed(value);
+            assert_eq!(read_signed(&bytes, &mut 0), value);
+        }
+    }
+
+    #[test]
+    fn sections_in_order() {
+        let program = testing::every_instruction();
+        let module = encode(&wasm_gen::lower_to_module(&program).unwrap()).unwrap();
+        let sections = sections(&module);
+
+        let ids: Vec<u8> = sections.iter().map(|(id, _)| *id).collect();
+        assert_eq!(
+            ids,
+            [
+                SECTION_TYPE,
+                SECTION_IMPORT,
+                SECTION_FUNCTION,
+                SECTION_MEMORY,
+                SECTION_EXPORT,
+                SECTION_CODE
+            ]
+        );
+        //Entry function has type 2, after the imported fd_read, fd_write and proc_exit
+        assert_eq!(sections[2].1, [1, 2]);
+        assert_eq!(sections[3].1, [1, 0, 1]);
+        let exports = sections[4].1;
+        assert_eq!(
+            exports[..9],
+            [2, 6, b'm', b'e', b'm', b'o', b'r', b'y', KIND_MEMORY]
+        );
+        assert_eq!(exports[exports.len() - 2..], [KIND_FUNC, 3]);
+    }
+
+    #[test]
+    fn binary_matches_the_text_module() {
+        let program = testing::every_instruction();
+        let module = encode(&wasm_gen::lower_to_module(&program).unwrap()).unwrap();
+        let wat = wasm_gen::lower_to_string(&program).unwrap();
+
+        let (_, code) = *sections(&module).last().unwrap();
+        let ops = disassemble(code);
+        assert!(ops.len() > 100);
+        assert_eq!(ops, text_ops(&wat));
+    }
+
+    //Undefined labels are reported instead of encoding a bad depth
+    #[test]
+    fn branches_need_an_open_label() {
+        let module = Module {
+            body: vec![
+                Wasm::Block(String::from("a")),
+                Wasm::End,
+                Wasm::Br(String::from("a")),
+            ],
+            memory_pages: 1,
+        };
+
+        let why = encode(&module).unwrap_err();
+        assert_eq!(why.kind(), ErrorKind::InvalidData);
+    }
+
+    //The interpreter is the oracle, run through Node's WASI when node is installed
+    #[test]
+    fn modules_match_the_interpreter() {
+        if !testing::have_tool("node", "modules_match_the_interpreter") {
+            return;
+        }
+        let run = |name: &str, program: &Program, input: &[u8]| {
+            let path = testing::temp_path(&format!("{}.wasm", name));
+            write_module(program, &path).unwrap();
+            let output = testing::run_with_input(
+                std::process::Command::new("node")
+                    .arg("--no-warnings")
+                    .arg("-e")
+                    .arg(concat!(
+                        "const { WASI } = require('node:wasi');",
+                        "const wasi = new WASI({ version: 'preview1', returnOnExit: true });",
+                        "const bytes = require('fs').readFileSync(process.argv[1]);",
+                        "const imports = { wasi_snapshot_preview1: wasi.wasiImport };",
+                        "const instance = new WebAssembly.Instance(",
+                        "  new WebAssembly.Module(bytes), imports);",
+                        "process.exitCode = wasi.start(instance);"
+                    ))
+                    .arg(&path),
+                input,
+            );
+            fs::remove_file(&path).unwrap();
+            output
+        };
+
+        let (input, printed) = testing::EVERY_INSTRUCTION_IO;
+        let program = testing::every_instruction();
+        let output = run("every_instruction", &program, input);
+        assert!(output.status.success());
+        assert_eq!(output.stdout, testing::interpret(&program, input));
+        assert_eq!(output.stdout, printed);
+
+        let cat = crate::parse::parse_str(include_str!("../examples/cat.ddg")).unwrap();
+        let output = run("cat", &cat, b"quack");
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"quack");
+
+        //Traps, after printing what came before
+        let program = testing::divides_by_zero();
+        let mut expected = Vec::new();
+        assert!(crate::interp::run(&program, &b""[..], &mut expected).is_err());
+        let output = run("divides_by_zero", &program, b"");
+        assert!(!output.status.success());
+        assert_eq!(output.stdout, expected);
+        assert_eq!(output.stdout, b"A");
+    }
+}
@@ -0,0 +1,550 @@
+//Hayden Coffey
+//WebAssembly backend for WASI runtimes. Ducks live in linear memory, Input, Print and End
+//go through fd_read, fd_write and proc_exit. The same instruction list prints as a .wat
+//module or encodes into a binary .wasm module.
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use crate::backend::{self, Backend, LoopLabels};
+use crate::program::Program;
+
+//Linear memory layout: iovec {buf, len} at 0, byte count written by WASI at 8, one char
+//buffer at 16, then the duck array with the teacher after the last duck
+const IOVEC_ADDR: i32 = 0;
+const NBYTES_ADDR: i32 = 8;
+const BUFFER_ADDR: i32 = 16;
+const ARRAY_ADDR: u32 = 32;
+const PAGE_SIZE: usize = 65536;
+
+//Imported WASI functions come first in the function index space
+const FD_READ: u32 = 0;
+const FD_WRITE: u32 = 1;
+const PROC_EXIT: u32 = 2;
+pub static IMPORT_MODULE: &str = "wasi_snapshot_preview1";
+pub static IMPORTS: [&str; 3] = ["fd_read", "fd_write", "proc_exit"];
+
+//i64 locals of the entry function
+const GOOSE_LOCAL: u32 = 0;
+//Index the goose moves to once the instruction is done
+const TARGET_LOCAL: u32 = 1;
+pub const LOCAL_NAMES: [&str; 2] = ["goose", "target"];
+
+pub static ENTRY_NAME: &str = "_start";
+
+//WebAssembly instructions used by the generated code
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Wasm {
+    Comment(String),
+    I32Const(i32),
+    I64Const(i64),
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    I64Add,
+    I64Sub,
+    I64Mul,
+    //Traps on division by zero, like divq does
+    I64DivU,
+    I64RemU,
+    I64Shl,
+    I64Eqz,
+    I32WrapI64,
+    //Memory accesses with a constant offset added to the address
+    I32Store(u32),
+    I64Load(u32),
+    I64Store(u32),
+    I64Store8(u32),
+    Call(u32),
+    Drop,
+    Block(String),
+    Loop(String),
+    End,
+    Br(String),
+    BrIf(String),
+}
+
+impl fmt::Display for Wasm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Wasm::Comment(text) => write!(f, ";;{}", text),
+            Wasm::I32Const(value) => write!(f, "i32.const {}", value),
+            Wasm::I64Const(value) => write!(f, "i64.const {}", value),
+            Wasm::LocalGet(local) => write!(f, "local.get ${}", LOCAL_NAMES[*local as usize]),
+            Wasm::LocalSet(local) => write!(f, "local.set ${}", LOCAL_NAMES[*local as usize]),
+            Wasm::LocalTee(local) => write!(f, "local.tee ${}", LOCAL_NAMES[*local as usize]),
+            Wasm::I64Add => write!(f, "i64.add"),
+            Wasm::I64Sub => write!(f, "i64.sub"),
+            Wasm::I64Mul => write!(f, "i64.mul"),
+            Wasm::I64DivU => write!(f, "i64.div_u"),
+            Wasm::I64RemU => write!(f, "i64.rem_u"),
+            Wasm::I64Shl => write!(f, "i64.shl"),
+            Wasm::I64Eqz => write!(f, "i64.eqz"),
+            Wasm::I32WrapI64 => write!(f, "i32.wrap_i64"),
+            Wasm::I32Store(offset) => write!(f, "i32.store offset={}", offset),
+            Wasm::I64Load(offset) => write!(f, "i64.load offset={}", offset),
+            Wasm::I64Store(offset) => write!(f, "i64.store offset={}", offset),
+            Wasm::I64Store8(offset) => write!(f, "i64.store8 offset={}", offset),
+            Wasm::Call(func) => write!(f, "call ${}", IMPORTS[*func as usize]),
+            Wasm::Drop => write!(f, "drop"),
+            Wasm::Block(label) => write!(f, "block ${}", label),
+            Wasm::Loop(label) => write!(f, "loop ${}", label),
+            Wasm::End => write!(f, "end"),
+            Wasm::Br(label) => write!(f, "br ${}", label),
+            Wasm::BrIf(label) => write!(f, "br_if ${}", label),
+        }
+    }
+}
+
+//Lowered entry function along with what the module around it needs
+pub struct Module {
+    pub body: Vec<Wasm>,
+    pub memory_pages: usize,
+}
+
+fn comment(text: &str, out: &mut Vec<Wasm>) {
+    out.push(Wasm::Comment(String::from(text)));
+}
+
+//Push the array index of the given duck
+fn get_duck_index(ring_size: usize, duck: usize, out: &mut Vec<Wasm>) {
+    out.push(Wasm::LocalGet(GOOSE_LOCAL));
+    out.push(Wasm::I64Const(duck as i64));
+    out.push(Wasm::I64Add);
+    out.push(Wasm::I64Const(ring_size as i64));
+    out.push(Wasm::I64RemU);
+}
+
+//Turn the array index on top of the stack into an address, relative to ARRAY_ADDR
+fn index_to_address(out: &mut Vec<Wasm>) {
+    out.push(Wasm::I64Const(3));
+    out.push(Wasm::I64Shl);
+    out.push(Wasm::I32WrapI64);
+}
+
+//Push the value of the given duck, remembering its index in the target local
+fn load_target(ring_size: usize, duck: usize, out: &mut Vec<Wasm>) {
+    get_duck_index(ring_size, duck, out);
+    out.push(Wasm::LocalTee(TARGET_LOCAL));
+    index_to_address(out);
+    out.push(Wasm::I64Load(ARRAY_ADDR));
+}
+
+//Push the value of the given duck
+fn load_duck(ring_size: usize, duck: usize, out: &mut Vec<Wasm>) {
+    get_duck_index(ring_size, duck, out);
+    index_to_address(out);
+    out.push(Wasm::I64Load(ARRAY_ADDR));
+}
+
+//Push the address of the goose, for the store that ends most instructions
+fn goose_address(out: &mut Vec<Wasm>) {
+    out.push(Wasm::LocalGet(GOOSE_LOCAL));
+    index_to_address(out);
+}
+
+//Store the value on top of the stack in the goose, then move the goose to the target
+fn store_result(out: &mut Vec<Wasm>) {
+    out.push(Wasm::I64Store(ARRAY_ADDR));
+    out.push(Wasm::LocalGet(TARGET_LOCAL));
+    out.push(Wasm::LocalSet(GOOSE_LOCAL));
+}
+
+//The teacher sits at a fixed address
+fn teacher_address(ring_size: usize, out: &mut Vec<Wasm>) {
+    out.push(Wasm::I32Const((ring_size * 8) as i32));
+}
+
+//Point the iovec at the one char buffer, read and write share it
+fn write_header(out: &mut Vec<Wasm>) {
+    out.push(Wasm::I32Const(IOVEC_ADDR));
+    out.push(Wasm::I32Const(BUFFER_ADDR));
+    out.push(Wasm::I32Store(0));
+    out.push(Wasm::I32Const(IOVEC_ADDR + 4));
+    out.push(Wasm::I32Const(1));
+    out.push(Wasm::I32Store(0));
+}
+
+//Call fd_read/fd_write on the iovec, ignoring the result like the other backends
+fn write_io_call(fd: i32, func: u32, out: &mut Vec<Wasm>) {
+    out.push(Wasm::I32Const(fd));
+    out.push(Wasm::I32Const(IOVEC_ADDR));
+    out.push(Wasm::I32Const(1));
+    out.push(Wasm::I32Const(NBYTES_ADDR));
+    out.push(Wasm::Call(func));
+    out.push(Wasm::Drop);
+}
+
+fn write_exit(out: &mut Vec<Wasm>) {
+    comment("Exit==========", out);
+    out.push(Wasm::I32Const(0));
+    out.push(Wasm::Call(PROC_EXIT));
+}
+
+fn write_binary(name: &str, op: Wasm, ring_size: usize, n: usize, y: usize, out: &mut Vec<Wasm>) {
+    comment(&format!("{}==========", name), out);
+    comment(&format!("n: {}", n), out);
+    comment(&format!("y: {}", y), out);
+
+    goose_address(out);
+    load_target(ring_size, n, out);
+    load_duck(ring_size, y, out);
+    out.push(op);
+    store_result(out);
+}
+
+//Reads a char from stdin
+fn write_input(ring_size: usize, n: usize, out: &mut Vec<Wasm>) {
+    comment("Input==========", out);
+    //Clear the buffer, end of input reads as 0
+    out.push(Wasm::I32Const(BUFFER_ADDR));
+    out.push(Wasm::I64Const(0));
+    out.push(Wasm::I64Store(0));
+    write_io_call(0, FD_READ, out);
+
+    get_duck_index(ring_size, n, out);
+    out.push(Wasm::LocalSet(TARGET_LOCAL));
+
+    goose_address(out);
+    out.push(Wasm::I32Const(BUFFER_ADDR));
+    out.push(Wasm::I64Load(0));
+    store_result(out);
+}
+
+//Push value to teacher
+fn write_push(ring_size: usize, n: usize, out: &mut Vec<Wasm>) {
+    comment("Push==========", out);
+    //Move N -> Teacher
+    teacher_address(ring_size, out);
+    load_target(ring_size, n, out);
+    out.push(Wasm::I64Store(ARRAY_ADDR));
+
+    //Move N -> Goose and update goose index
+    goose_address(out);
+    out.push(Wasm::LocalGet(TARGET_LOCAL));
+    index_to_address(out);
+    out.push(Wasm::I64Load(ARRAY_ADDR));
+    store_result(out);
+}
+
+//Pop value from teacher to goose
+fn write_pop(ring_size: usize, n: usize, out: &mut Vec<Wasm>) {
+    comment("Pop==========", out);
+    get_duck_index(ring_size, n, out);
+    out.push(Wasm::LocalSet(TARGET_LOCAL));
+
+    //Move teacher -> Goose
+    goose_address(out);
+    teacher_address(ring_size, out);
+    out.push(Wasm::I64Load(ARRAY_ADDR));
+    out.push(Wasm::I64Store(ARRAY_ADDR));
+
+    //Set teacher to 0, then update goose index
+    teacher_address(ring_size, out);
+    out.push(Wasm::I64Const(0));
+    store_result(out);
+}
+
+//The source loop id is only kept as a comment
+fn write_loop_begin(
+    ring_size: usize,
+    cond: usize,
+    id: usize,
+    labels: &LoopLabels,
+    out: &mut Vec<Wasm>,
+) {
+    comment("LoopBegin==========", out);
+    comment(&format!("id: {}", id), out);
+    out.push(Wasm::Block(labels.end.clone()));
+    out.push(Wasm::Loop(labels.start.clone()));
+
+    load_duck(ring_size, cond, out);
+    out.push(Wasm::I64Eqz);
+    out.push(Wasm::BrIf(labels.end.clone()));
+}
+
+fn write_loop_end(id: usize, labels: &LoopLabels, out: &mut Vec<Wasm>) {
+    comment("LoopEnd==========", out);
+    comment(&format!("id: {}", id), out);
+    out.push(Wasm::Br(labels.start.clone()));
+    out.push(Wasm::End);
+    out.push(Wasm::End);
+}
+
+fn write_set(ring_size: usize, n: usize, value: usize, out: &mut Vec<Wasm>) {
+    comment("Set==========", out);
+    comment(&format!("n: {}", n), out);
+    comment(&format!("value: {}", value), out);
+
+    get_duck_index(ring_size, n, out);
+    out.push(Wasm::LocalSet(TARGET_LOCAL));
+
+    goose_address(out);
+    out.push(Wasm::I64Const(value as i64));
+    store_result(out);
+}
+
+fn write_print(ring_size: usize, n: usize, out: &mut Vec<Wasm>) {
+    comment("Print==========", out);
+    out.push(Wasm::I32Const(BUFFER_ADDR));
+    load_duck(ring_size, n, out);
+    out.push(Wasm::I64Store8(0));
+    write_io_call(1, FD_WRITE, out);
+}
+
+fn write_print_value(value: usize, out: &mut Vec<Wasm>) {
+    comment("PrintValue==========", out);
+    out.push(Wasm::I32Const(BUFFER_ADDR));
+    out.push(Wasm::I64Const(value as i64));
+    out.push(Wasm::I64Store8(0));
+    write_io_call(1, FD_WRITE, out);
+}
+
+//Goose to duck n, nothing stored
+fn write_move(ring_size: usize, n: usize, out: &mut Vec<Wasm>) {
+    comment("Move==========", out);
+    comment(&format!("n: {}", n), out);
+
+    get_duck_index(ring_size, n, out);
+    out.push(Wasm::LocalSet(GOOSE_LOCAL));
+}
+
+//Builds the entry function body, nothing is written until the module is assembled
+pub struct Wasm32 {
+    ring_size: usize,
+    module: Module,
+}
+
+impl Wasm32 {
+    pub fn new() -> Wasm32 {
+        Wasm32 {
+            ring_size: 0,
+            module: Module {
+                body: Vec::new(),
+                memory_pages: 0,
+            },
+        }
+    }
+
+    pub fn into_module(self) -> Module {
+        self.module
+    }
+}
+
+impl Default for Wasm32 {
+    fn default() -> Wasm32 {
+        Wasm32::new()
+    }
+}
+
+impl Backend for Wasm32 {
+    fn prologue(&mut self, program: &Program) -> std::io::Result<()> {
+        self.ring_size = program.ring_size();
+
+        //Goose, ducks and teacher after the fixed area, memory starts zeroed
+        let bytes = ARRAY_ADDR as usize + (program.duck_count() + 3) * 8;
+        self.module.memory_pages = bytes.div_ceil(PAGE_SIZE);
+
+        write_header(&mut self.module.body);
+        Ok(())
+    }
+
+    fn epilogue(&mut self) -> std::io::Result<()> {
+        write_exit(&mut self.module.body);
+        Ok(())
+    }
+
+    fn end(&mut self) -> std::io::Result<()> {
+        write_exit(&mut self.module.body);
+        Ok(())
+    }
+
+    fn print(&mut self, n: usize) -> std::io::Result<()> {
+        write_print(self.ring_size, n, &mut self.module.body);
+        Ok(())
+    }
+
+    fn add(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        let out = &mut self.module.body;
+        write_binary("Add", Wasm::I64Add, self.ring_size, n, y, out);
+        Ok(())
+    }
+
+    fn subtract(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        let out = &mut self.module.body;
+        write_binary("Subtract", Wasm::I64Sub, self.ring_size, n, y, out);
+        Ok(())
+    }
+
+    fn multiply(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        let out = &mut self.module.body;
+        write_binary("Multiply", Wasm::I64Mul, self.ring_size, n, y, out);
+        Ok(())
+    }
+
+    fn divide(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        let out = &mut self.module.body;
+        write_binary("Divide", Wasm::I64DivU, self.ring_size, n, y, out);
+        Ok(())
+    }
+
+    fn input(&mut self, n: usize) -> std::io::Result<()> {
+        write_input(self.ring_size, n, &mut self.module.body);
+        Ok(())
+    }
+
+    fn push(&mut self, n: usize) -> std::io::Result<()> {
+        write_push(self.ring_size, n, &mut self.module.body);
+        Ok(())
+    }
+
+    fn pop(&mut self, n: usize) -> std::io::Result<()> {
+        write_pop(self.ring_size, n, &mut self.module.body);
+        Ok(())
+    }
+
+    fn loop_begin(&mut self, cond: usize, id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        write_loop_begin(self.ring_size, cond, id, labels, &mut self.module.body);
+        Ok(())
+    }
+
+    fn loop_end(&mut self, id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        write_loop_end(id, labels, &mut self.module.body);
+        Ok(())
+    }
+
+    fn set(&mut self, n: usize, value: usize) -> std::io::Result<()> {
+        write_set(self.ring_size, n, value, &mut self.module.body);
+        Ok(())
+    }
+
+    fn move_goose(&mut self, n: usize) -> std::io::Result<()> {
+        write_move(self.ring_size, n, &mut self.module.body);
+        Ok(())
+    }
+
+    fn print_value(&mut self, value: usize) -> std::io::Result<()> {
+        write_print_value(value, &mut self.module.body);
+        Ok(())
+    }
+}
+
+//Lower program to the body of the entry function
+//Fails with InvalidData if the program doesn't pass check::check
+pub fn lower_to_module(program: &Program) -> std::io::Result<Module> {
+    let mut backend = Wasm32::new();
+    backend::lower(program, &mut backend)?;
+
+    Ok(backend.into_module())
+}
+
+//Write program as a WebAssembly text module to out
+pub fn lower_to_writer(program: &Program, out: &mut impl Write) -> std::io::Result<()> {
+    let module = lower_to_module(program)?;
+
+    writeln!(out, "(module")?;
+    for name in IMPORTS {
+        let params = if name == "proc_exit" {
+            "(param i32)"
+        } else {
+            "(param i32 i32 i32 i32) (result i32)"
+        };
+        writeln!(
+            out,
+            "  (import \"{}\" \"{}\" (func ${} {}))",
+            IMPORT_MODULE, name, name, params
+        )?;
+    }
+    writeln!(
+        out,
+        "  (memory (export \"memory\") {})",
+        module.memory_pages
+    )?;
+    writeln!(out, "  (func (export \"{}\")", ENTRY_NAME)?;
+    for name in LOCAL_NAMES {
+        writeln!(out, "    (local ${} i64)", name)?;
+    }
+
+    let mut depth = 2;
+    for op in &module.body {
+        if *op == Wasm::End {
+            depth -= 1;
+        }
+        writeln!(out, "{}{}", "  ".repeat(depth), op)?;
+        if let Wasm::Block(_) | Wasm::Loop(_) = op {
+            depth += 1;
+        }
+    }
+
+    writeln!(out, "  )")?;
+    writeln!(out, ")")
+}
+
+//Program as a WebAssembly text module in a string
+pub fn lower_to_string(program: &Program) -> std::io::Result<String> {
+    let mut out = Vec::new();
+    lower_to_writer(program, &mut out)?;
+
+    backend::into_text(out)
+}
+
+//Write program as a WebAssembly text module
+pub fn lower_program(program: &Program, file_out_name: &str) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(file_out_name)?);
+    lower_to_writer(program, &mut file)?;
+
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction::*;
+    use crate::testing::{self, program};
+
+    #[test]
+    fn memory_covers_the_teacher() {
+        //Array filling the first page exactly, then one slot more
+        let slots = (PAGE_SIZE - ARRAY_ADDR as usize) / 8;
+        let pages = |duck_count: usize| {
+            let module = lower_to_module(&program(duck_count, &[End])).unwrap();
+            module.memory_pages
+        };
+
+        assert_eq!(pages(1), 1);
+        assert_eq!(pages(slots - 3), 1);
+        assert_eq!(pages(slots - 2), 2);
+    }
+
+    #[test]
+    fn text_module_imports_wasi_and_exports_start() {
+        let wat = lower_to_string(&testing::every_instruction()).unwrap();
+
+        assert!(wat.starts_with("(module\n"), "{}", wat);
+        assert!(wat.contains(
+            "  (import \"wasi_snapshot_preview1\" \"fd_read\" \
+             (func $fd_read (param i32 i32 i32 i32) (result i32)))\n"
+        ));
+        assert!(wat.contains(
+            "  (import \"wasi_snapshot_preview1\" \"proc_exit\" (func $proc_exit (param i32)))\n"
+        ));
+        assert!(wat.contains("  (memory (export \"memory\") 1)\n"));
+        assert!(wat.contains("  (func (export \"_start\")\n    (local $goose i64)\n"));
+        //Every block and loop closed again by the end of the function
+        assert!(wat.ends_with("    call $proc_exit\n  )\n)\n"), "{}", wat);
+        assert_eq!(
+            wat.matches(" block $").count() + wat.matches(" loop $").count(),
+            wat.lines().filter(|line| line.trim() == "end").count()
+        );
+    }
+
+    //No check of its own, div_u traps on 0 like divq does
+    #[test]
+    fn divide_traps_in_div_u() {
+        let module = lower_to_module(&testing::divides_by_zero()).unwrap();
+
+        assert!(module.body.contains(&Wasm::I64DivU));
+        assert!(!module.body.contains(&Wasm::I64Eqz));
+    }
+}
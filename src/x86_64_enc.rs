@@ -0,0 +1,452 @@
+//Hayden Coffey
+//Machine code encoder for the instructions produced by x86_64_gen, so executables
+//can be written without an external assembler
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::elf;
+use crate::program::Program;
+use crate::x86_64_gen::{self, Asm, Operand, Options, Reg};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EncodeError {
+    //Operand combination the encoder has no form for, with the instruction text
+    UnsupportedOperands(String),
+    ImmediateOutOfRange(String),
+    UndefinedLabel(String),
+    DuplicateLabel(String),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::UnsupportedOperands(inst) => {
+                write!(f, "Unsupported operands: {}", inst.trim())
+            }
+            EncodeError::ImmediateOutOfRange(inst) => {
+                write!(f, "Immediate out of range: {}", inst.trim())
+            }
+            EncodeError::UndefinedLabel(label) => write!(f, "Undefined label {}", label),
+            EncodeError::DuplicateLabel(label) => write!(f, "Label {} is already defined", label),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+//Encoded instructions along with the offset of every label
+#[derive(Clone, Debug, Default)]
+pub struct Code {
+    pub bytes: Vec<u8>,
+    pub labels: HashMap<String, usize>,
+}
+
+impl Code {
+    pub fn label(&self, name: &str) -> Option<usize> {
+        self.labels.get(name).copied()
+    }
+}
+
+fn fits_i8(value: i64) -> bool {
+    i8::try_from(value).is_ok()
+}
+
+fn fits_i32(value: i64) -> bool {
+    i32::try_from(value).is_ok()
+}
+
+fn rex(w: bool, r: u8, x: u8, b: u8) -> u8 {
+    0x40 | ((w as u8) << 3) | ((r >> 3) << 2) | ((x >> 3) << 1) | (b >> 3)
+}
+
+fn modrm(mode: u8, reg: u8, rm: u8) -> u8 {
+    (mode << 6) | ((reg & 7) << 3) | (rm & 7)
+}
+
+struct Encoder {
+    code: Code,
+    //Positions of rel32 fields to patch, and the label they refer to
+    fixups: Vec<(usize, String)>,
+}
+
+impl Encoder {
+    fn byte(&mut self, byte: u8) {
+        self.code.bytes.push(byte);
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.bytes.extend_from_slice(bytes);
+    }
+
+    //REX.W prefixed instruction with a ModRM byte. reg is a register number or an
+    //opcode extension, rm a register or memory operand.
+    fn rm(&mut self, opcode: &[u8], reg: u8, rm: &Operand) -> Result<(), ()> {
+        match *rm {
+            Operand::Reg(rm) => {
+                self.byte(rex(true, reg, 0, rm.number()));
+                self.bytes(opcode);
+                self.byte(modrm(3, reg, rm.number()));
+            }
+            Operand::Mem { base, index, disp } => {
+                let base = base.number();
+                //An index of 0b100 in the SIB byte means no index
+                let (index, scale) = match index {
+                    Some(Reg::Rsp) => return Err(()),
+                    Some(index) => (index.number(), 3),
+                    None => (4, 0),
+                };
+
+                self.byte(rex(true, reg, if scale == 0 { 0 } else { index }, base));
+                self.bytes(opcode);
+
+                //rbp and r13 as a base always need a displacement
+                let mode = if disp == 0 && base & 7 != 5 {
+                    0
+                } else if fits_i8(disp as i64) {
+                    1
+                } else {
+                    2
+                };
+
+                //rsp and r12 as a base always need a SIB byte
+                if scale != 0 || base & 7 == 4 {
+                    self.byte(modrm(mode, reg, 4));
+                    self.byte((scale << 6) | ((index & 7) << 3) | (base & 7));
+                } else {
+                    self.byte(modrm(mode, reg, base));
+                }
+
+                match mode {
+                    1 => self.byte(disp as i8 as u8),
+                    2 => self.bytes(&disp.to_le_bytes()),
+                    _ => (),
+                }
+            }
+            Operand::Imm(_) => return Err(()),
+        }
+
+        Ok(())
+    }
+
+    fn imm32(&mut self, value: i64) {
+        self.bytes(&(value as i32).to_le_bytes());
+    }
+
+    //Arithmetic group: op r/m64, imm (0x83/0x81 /ext), op r/m64, r64 (store_op)
+    //and op r64, r/m64 (load_op)
+    fn arith(
+        &mut self,
+        ext: u8,
+        store_op: u8,
+        load_op: u8,
+        src: &Operand,
+        dst: Reg,
+    ) -> Result<(), EncodeError> {
+        let dst_op = Operand::Reg(dst);
+        let result = match *src {
+            Operand::Imm(value) if fits_i8(value) => {
+                let result = self.rm(&[0x83], ext, &dst_op);
+                self.byte(value as u8);
+                result
+            }
+            Operand::Imm(value) if fits_i32(value) => {
+                let result = self.rm(&[0x81], ext, &dst_op);
+                self.imm32(value);
+                result
+            }
+            Operand::Imm(_) => return Err(EncodeError::ImmediateOutOfRange(String::new())),
+            Operand::Reg(src) => self.rm(&[store_op], src.number(), &dst_op),
+            Operand::Mem { .. } => self.rm(&[load_op], dst.number(), src),
+        };
+
+        result.map_err(|_| EncodeError::UnsupportedOperands(String::new()))
+    }
+
+    fn mov(&mut self, src: &Operand, dst: &Operand) -> Result<(), EncodeError> {
+        let unsupported = || EncodeError::UnsupportedOperands(String::new());
+        match (*src, *dst) {
+            (Operand::Reg(src), dst) => self.rm(&[0x89], src.number(), &dst),
+            (Operand::Mem { .. }, Operand::Reg(dst)) => self.rm(&[0x8b], dst.number(), src),
+            (Operand::Imm(value), dst) if fits_i32(value) => {
+                let result = self.rm(&[0xc7], 0, &dst);
+                self.imm32(value);
+                result
+            }
+            //movabs, only into a register
+            (Operand::Imm(value), Operand::Reg(dst)) => {
+                self.byte(rex(true, 0, 0, dst.number()));
+                self.byte(0xb8 + (dst.number() & 7));
+                self.bytes(&value.to_le_bytes());
+                Ok(())
+            }
+            (Operand::Imm(_), _) => return Err(EncodeError::ImmediateOutOfRange(String::new())),
+            _ => return Err(unsupported()),
+        }
+        .map_err(|_| unsupported())
+    }
+
+    //Registers 8-15 need a REX.B prefix for the one byte push/pop forms
+    fn short_reg(&mut self, opcode: u8, reg: Reg) {
+        if reg.number() >= 8 {
+            self.byte(0x41);
+        }
+        self.byte(opcode + (reg.number() & 7));
+    }
+
+    fn jump(&mut self, opcode: &[u8], label: &str) {
+        self.bytes(opcode);
+        self.fixups
+            .push((self.code.bytes.len(), String::from(label)));
+        self.bytes(&[0; 4]);
+    }
+
+    fn instruction(&mut self, op: &Asm) -> Result<(), EncodeError> {
+        match op {
+            Asm::Directive(_) | Asm::Comment(_) => (),
+            Asm::Label(name) => {
+                let pos = self.code.bytes.len();
+                if self.code.labels.insert(name.clone(), pos).is_some() {
+                    return Err(EncodeError::DuplicateLabel(name.clone()));
+                }
+            }
+            Asm::Push(Operand::Imm(value)) if fits_i8(*value) => {
+                self.bytes(&[0x6a, *value as u8]);
+            }
+            Asm::Push(Operand::Imm(value)) if fits_i32(*value) => {
+                self.byte(0x68);
+                self.imm32(*value);
+            }
+            Asm::Push(Operand::Reg(reg)) => self.short_reg(0x50, *reg),
+            Asm::Push(_) => return Err(EncodeError::UnsupportedOperands(String::new())),
+            Asm::Pop(reg) => self.short_reg(0x58, *reg),
+            Asm::Mov(src, dst) => self.mov(src, dst)?,
+            Asm::Add(src, dst) => self.arith(0, 0x01, 0x03, src, *dst)?,
+            Asm::Sub(src, dst) => self.arith(5, 0x29, 0x2b, src, *dst)?,
+            Asm::Cmp(src, dst) => self.arith(7, 0x39, 0x3b, src, *dst)?,
+            Asm::Xor(src, dst) => self.arith(6, 0x31, 0x33, &Operand::Reg(*src), *dst)?,
+            Asm::Imul(src, dst) => self
+                .rm(&[0x0f, 0xaf], dst.number(), &Operand::Reg(*src))
+                .map_err(|_| EncodeError::UnsupportedOperands(String::new()))?,
+            Asm::Div(src) => self
+                .rm(&[0xf7], 6, &Operand::Reg(*src))
+                .map_err(|_| EncodeError::UnsupportedOperands(String::new()))?,
+            Asm::Jz(label) => self.jump(&[0x0f, 0x84], label),
+            Asm::Jmp(label) => self.jump(&[0xe9], label),
+            Asm::Syscall => self.bytes(&[0x0f, 0x05]),
+            Asm::Ret => self.byte(0xc3),
+        }
+
+        Ok(())
+    }
+}
+
+//Encode instructions into machine code, resolving jumps to labels
+pub fn encode(ops: &[Asm]) -> Result<Code, EncodeError> {
+    let mut encoder = Encoder {
+        code: Code::default(),
+        fixups: Vec::new(),
+    };
+
+    for op in ops {
+        //Errors are raised without context, attach the offending instruction
+        encoder.instruction(op).map_err(|why| match why {
+            EncodeError::UnsupportedOperands(_) => EncodeError::UnsupportedOperands(op.to_string()),
+            EncodeError::ImmediateOutOfRange(_) => EncodeError::ImmediateOutOfRange(op.to_string()),
+            why => why,
+        })?;
+    }
+
+    for (pos, label) in &encoder.fixups {
+        let target = encoder
+            .code
+            .label(label)
+            .ok_or_else(|| EncodeError::UndefinedLabel(label.clone()))?;
+        let rel = target as i64 - (*pos as i64 + 4);
+        encoder.code.bytes[*pos..*pos + 4].copy_from_slice(&(rel as i32).to_le_bytes());
+    }
+
+    Ok(encoder.code)
+}
+
+//Lower, encode and write a static executable for Linux x86-64, no assembler or libc needed
+pub fn write_executable(program: &Program, options: Options, path: &Path) -> std::io::Result<()> {
+    let ops = x86_64_gen::lower_to_asm(program, options)?;
+    let code =
+        encode(&ops).map_err(|why| std::io::Error::new(std::io::ErrorKind::InvalidData, why))?;
+    let entry = code.label(x86_64_gen::ENTRY_LABEL).unwrap_or_default();
+
+    elf::write_executable(path, elf::EM_X86_64, &code.bytes, entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x86_64_gen::Reg::*;
+
+    //Expected bytes were checked against GNU as
+    fn assert_encodes(op: Asm, expected: &[u8]) {
+        let code = encode(std::slice::from_ref(&op)).unwrap();
+        assert_eq!(code.bytes, expected, "{}", op);
+    }
+
+    fn mem(base: Reg, index: Option<Reg>, disp: i32) -> Operand {
+        Operand::Mem { base, index, disp }
+    }
+
+    #[test]
+    fn rex_prefixes_extend_registers() {
+        assert_encodes(
+            Asm::Mov(Operand::Reg(Rax), Operand::Reg(Rbx)),
+            &[0x48, 0x89, 0xc3],
+        );
+        //REX.R for the source, REX.B for the destination
+        assert_encodes(
+            Asm::Mov(Operand::Reg(R8), Operand::Reg(Rax)),
+            &[0x4c, 0x89, 0xc0],
+        );
+        assert_encodes(
+            Asm::Mov(Operand::Reg(Rax), Operand::Reg(R15)),
+            &[0x49, 0x89, 0xc7],
+        );
+        assert_encodes(Asm::Xor(R14, R14), &[0x4d, 0x31, 0xf6]);
+        assert_encodes(Asm::Imul(R8, Rax), &[0x49, 0x0f, 0xaf, 0xc0]);
+        assert_encodes(Asm::Imul(Rax, R9), &[0x4c, 0x0f, 0xaf, 0xc8]);
+        assert_encodes(Asm::Push(Operand::Reg(Rbx)), &[0x53]);
+        assert_encodes(Asm::Push(Operand::Reg(R15)), &[0x41, 0x57]);
+        assert_encodes(Asm::Pop(Rbp), &[0x5d]);
+        assert_encodes(Asm::Pop(R12), &[0x41, 0x5c]);
+    }
+
+    #[test]
+    fn immediates_pick_the_shortest_form() {
+        assert_encodes(Asm::Add(Operand::Imm(1), Rax), &[0x48, 0x83, 0xc0, 0x01]);
+        assert_encodes(
+            Asm::Add(Operand::Imm(1000), R9),
+            &[0x49, 0x81, 0xc1, 0xe8, 0x03, 0x00, 0x00],
+        );
+        assert_encodes(Asm::Sub(Operand::Imm(8), Rsp), &[0x48, 0x83, 0xec, 0x08]);
+        assert_encodes(Asm::Cmp(Operand::Imm(0), R8), &[0x49, 0x83, 0xf8, 0x00]);
+        assert_encodes(
+            Asm::Mov(Operand::Imm(5), Operand::Reg(Rax)),
+            &[0x48, 0xc7, 0xc0, 0x05, 0x00, 0x00, 0x00],
+        );
+        assert_encodes(
+            Asm::Mov(Operand::Imm(-1), Operand::Reg(R10)),
+            &[0x49, 0xc7, 0xc2, 0xff, 0xff, 0xff, 0xff],
+        );
+        //movabs
+        assert_encodes(
+            Asm::Mov(Operand::Imm(1 << 32), Operand::Reg(R8)),
+            &[0x49, 0xb8, 0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00],
+        );
+        assert_encodes(Asm::Push(Operand::Imm(1)), &[0x6a, 0x01]);
+        assert_encodes(
+            Asm::Push(Operand::Imm(300)),
+            &[0x68, 0x2c, 0x01, 0x00, 0x00],
+        );
+    }
+
+    #[test]
+    fn memory_operands_use_sib_and_displacements() {
+        //r12 as a base always needs a SIB byte
+        assert_encodes(
+            Asm::Mov(mem(R12, None, 0), Operand::Reg(Rax)),
+            &[0x49, 0x8b, 0x04, 0x24],
+        );
+        assert_encodes(
+            Asm::Mov(mem(R12, None, 8), Operand::Reg(Rax)),
+            &[0x49, 0x8b, 0x44, 0x24, 0x08],
+        );
+        assert_encodes(
+            Asm::Mov(Operand::Reg(Rax), mem(R12, Some(Rax), 0)),
+            &[0x49, 0x89, 0x04, 0xc4],
+        );
+        //REX.X for an extended index
+        assert_encodes(
+            Asm::Mov(mem(R12, Some(R10), 0), Operand::Reg(R8)),
+            &[0x4f, 0x8b, 0x04, 0xd4],
+        );
+        //r13 and rbp as a base always need a displacement
+        assert_encodes(
+            Asm::Mov(mem(R13, None, 0), Operand::Reg(Rax)),
+            &[0x49, 0x8b, 0x45, 0x00],
+        );
+        assert_encodes(
+            Asm::Mov(mem(R13, Some(Rcx), 0), Operand::Reg(Rax)),
+            &[0x49, 0x8b, 0x44, 0xcd, 0x00],
+        );
+        assert_encodes(
+            Asm::Mov(mem(Rbp, None, -8), Operand::Reg(Rax)),
+            &[0x48, 0x8b, 0x45, 0xf8],
+        );
+        assert_encodes(
+            Asm::Mov(mem(Rsp, None, 0x100), Operand::Reg(Rdx)),
+            &[0x48, 0x8b, 0x94, 0x24, 0x00, 0x01, 0x00, 0x00],
+        );
+        assert_encodes(
+            Asm::Mov(Operand::Imm(7), mem(R12, Some(Rax), 0)),
+            &[0x49, 0xc7, 0x04, 0xc4, 0x07, 0x00, 0x00, 0x00],
+        );
+        assert_encodes(
+            Asm::Add(mem(R12, Some(Rax), 16), R11),
+            &[0x4d, 0x03, 0x5c, 0xc4, 0x10],
+        );
+    }
+
+    #[test]
+    fn division_and_system_instructions() {
+        //Unsigned divide of rdx:rax
+        assert_encodes(Asm::Div(Rcx), &[0x48, 0xf7, 0xf1]);
+        assert_encodes(Asm::Div(R10), &[0x49, 0xf7, 0xf2]);
+        assert_encodes(Asm::Syscall, &[0x0f, 0x05]);
+        assert_encodes(Asm::Ret, &[0xc3]);
+    }
+
+    #[test]
+    fn jumps_are_relative_to_the_next_instruction() {
+        let ops = vec![
+            Asm::Label(String::from("top")),
+            Asm::Jz(String::from("bottom")),
+            Asm::Ret,
+            Asm::Jmp(String::from("top")),
+            Asm::Label(String::from("bottom")),
+        ];
+        let code = encode(&ops).unwrap();
+
+        assert_eq!(
+            code.bytes,
+            [
+                //jz +6, past the ret and jmp
+                0x0f, 0x84, 0x06, 0x00, 0x00, 0x00, //
+                0xc3, //
+                //jmp -12, back to the jz
+                0xe9, 0xf4, 0xff, 0xff, 0xff,
+            ]
+        );
+        assert_eq!(code.label("top"), Some(0));
+        assert_eq!(code.label("bottom"), Some(12));
+    }
+
+    #[test]
+    fn bad_input_is_rejected() {
+        assert_eq!(
+            encode(&[Asm::Jmp(String::from("nowhere"))]).unwrap_err(),
+            EncodeError::UndefinedLabel(String::from("nowhere"))
+        );
+        let twice = Asm::Label(String::from("here"));
+        assert_eq!(
+            encode(&[twice.clone(), twice]).unwrap_err(),
+            EncodeError::DuplicateLabel(String::from("here"))
+        );
+        //rsp can't be an index
+        assert!(matches!(
+            encode(&[Asm::Mov(mem(Rax, Some(Rsp), 0), Operand::Reg(Rax))]),
+            Err(EncodeError::UnsupportedOperands(_))
+        ));
+        assert!(matches!(
+            encode(&[Asm::Add(Operand::Imm(1 << 32), Rax)]),
+            Err(EncodeError::ImmediateOutOfRange(_))
+        ));
+    }
+}
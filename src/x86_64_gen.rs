@@ -1,341 +1,1265 @@
 //Hayden Coffey
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::BufWriter;
+
+use crate::backend::{self, Backend, LoopLabels};
+use crate::check;
+use crate::liveness::{self, LiveRange};
+use crate::mir::{self, BinOp, Op, Value};
+use crate::program::Program;
+use crate::rotation;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+    R8,
+    R9,
+    R10,
+    R11,
+    R12,
+    R13,
+    R14,
+    R15,
+}
+
+impl Reg {
+    //Register number used in instruction encodings
+    pub fn number(self) -> u8 {
+        self as u8
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Reg::Rax => "rax",
+            Reg::Rcx => "rcx",
+            Reg::Rdx => "rdx",
+            Reg::Rbx => "rbx",
+            Reg::Rsp => "rsp",
+            Reg::Rbp => "rbp",
+            Reg::Rsi => "rsi",
+            Reg::Rdi => "rdi",
+            Reg::R8 => "r8",
+            Reg::R9 => "r9",
+            Reg::R10 => "r10",
+            Reg::R11 => "r11",
+            Reg::R12 => "r12",
+            Reg::R13 => "r13",
+            Reg::R14 => "r14",
+            Reg::R15 => "r15",
+        }
+    }
+}
 
-use crate::instruction::{DuckInstruction, InstructionEnum};
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Reg(Reg),
+    Imm(i64),
+    //disp(base, index, 8)
+    Mem {
+        base: Reg,
+        index: Option<Reg>,
+        disp: i32,
+    },
+}
 
-static ARRAY_BASE_REG: &str = "r12";
-static DUCK_COUNT_REG: &str = "r13";
-static GOOSE_INDEX_REG: &str = "r14";
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write!(f, "%{}", reg.name()),
+            Operand::Imm(value) => write!(f, "${}", value),
+            Operand::Mem { base, index, disp } => {
+                if *disp != 0 {
+                    write!(f, "{}", disp)?;
+                }
+                match index {
+                    None => write!(f, "(%{})", base.name()),
+                    Some(index) => write!(f, "(%{}, %{}, 8)", base.name(), index.name()),
+                }
+            }
+        }
+    }
+}
 
-//Translate given duck index to array index and store in given register
-//Uses r8, rbx, rax, rdx
-fn get_duck_index(register: &str, duck: usize, file: &mut File) -> std::io::Result<()> {
-    get_goose_index("r8", file)?;
+//x86-64 instructions used by the generated code, operands in AT&T order (source first)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Asm {
+    Directive(String),
+    Comment(String),
+    Label(String),
+    Push(Operand),
+    Pop(Reg),
+    Mov(Operand, Operand),
+    Add(Operand, Reg),
+    Sub(Operand, Reg),
+    Imul(Reg, Reg),
+    //Unsigned divide of rdx:rax, quotient in rax and remainder in rdx
+    Div(Reg),
+    Xor(Reg, Reg),
+    Cmp(Operand, Reg),
+    Jz(String),
+    Jmp(String),
+    Syscall,
+    Ret,
+}
 
-    //Add duck to goose number
-    writeln!(file, "  add ${}, %r8", duck)?;
+impl fmt::Display for Asm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Asm::Directive(text) => write!(f, "{}", text),
+            Asm::Comment(text) => write!(f, "#{}", text),
+            Asm::Label(name) => write!(f, "{}:", name),
+            Asm::Push(src) => write!(f, "  pushq {}", src),
+            Asm::Pop(dst) => write!(f, "  popq %{}", dst.name()),
+            Asm::Mov(src, dst) => write!(f, "  movq {}, {}", src, dst),
+            Asm::Add(src, dst) => write!(f, "  addq {}, %{}", src, dst.name()),
+            Asm::Sub(src, dst) => write!(f, "  subq {}, %{}", src, dst.name()),
+            Asm::Imul(src, dst) => write!(f, "  imulq %{}, %{}", src.name(), dst.name()),
+            Asm::Div(src) => write!(f, "  divq %{}", src.name()),
+            Asm::Xor(src, dst) => write!(f, "  xorq %{}, %{}", src.name(), dst.name()),
+            Asm::Cmp(src, dst) => write!(f, "  cmpq {}, %{}", src, dst.name()),
+            Asm::Jz(label) => write!(f, "  jz {}", label),
+            Asm::Jmp(label) => write!(f, "  jmp {}", label),
+            Asm::Syscall => write!(f, "  syscall"),
+            Asm::Ret => write!(f, "  ret"),
+        }
+    }
+}
 
-    //Mod by duck count + 1
-    writeln!(file, "  mov %r8, %rax")?;
-    writeln!(file, "  mov $0, %rdx")?;
+//Symbol the generated code starts at
+pub static ENTRY_LABEL: &str = "main";
+//Epilogue End jumps to when the code is called as a function
+static RETURN_LABEL: &str = "return";
+
+//How the generated code is entered and left
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Entry {
+    //Standalone program, End exits the process
+    Process,
+    //Called with the C ABI, End returns to the caller
+    Function,
+}
 
-    writeln!(file, "  mov %{}, %rbx", DUCK_COUNT_REG)?;
-    writeln!(file, "  add $1, %rbx")?;
+//Registers the generated code uses that the C ABI expects to be preserved
+static CALLEE_SAVED: [Reg; 6] = [Reg::Rbx, Reg::Rbp, Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+//Holds the caller's stack pointer while running as a function
+static SAVED_STACK_REG: Reg = Reg::R15;
 
-    writeln!(file, "  divq %rbx")?;
+static ARRAY_BASE_REG: Reg = Reg::R12;
+static DUCK_COUNT_REG: Reg = Reg::R13;
+static GOOSE_INDEX_REG: Reg = Reg::R14;
 
-    //Move into desired register
-    writeln!(file, "  mov %rdx, %{}", register)?;
+//Registers that can hold ducks in register mode. Scratch registers and the ones
+//syscalls clobber (rax, rcx, rdx, rsi, rdi, r8, r9, r11) are left out.
+static DUCK_REGS: [Reg; 6] = [Reg::Rbx, Reg::Rbp, Reg::R10, Reg::R13, Reg::R14, Reg::R15];
 
-    Ok(())
+fn reg(reg: Reg) -> Operand {
+    Operand::Reg(reg)
 }
 
-//Store goose index in given register
-fn get_goose_index(register: &str, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "  mov %{}, %{}", GOOSE_INDEX_REG, register)?;
+fn imm(value: i64) -> Operand {
+    Operand::Imm(value)
+}
 
-    Ok(())
+//Array slot whose index is held in the given register
+fn slot(index: Reg) -> Operand {
+    Operand::Mem {
+        base: ARRAY_BASE_REG,
+        index: Some(index),
+        disp: 0,
+    }
 }
 
-//Store teacher index in given register
-fn get_teacher_index(register: &str, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "  mov %{}, %{}", DUCK_COUNT_REG, register)?;
-    writeln!(file, "  add $1, %{}", register)?;
+//Duck operand n places after the goose, with its array index if known at compile time
+#[derive(Clone, Copy, Debug)]
+struct Duck {
+    n: usize,
+    index: Option<usize>,
+}
 
-    Ok(())
+impl fmt::Display for Duck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.n)
+    }
+}
+
+fn comment(text: &str, out: &mut Vec<Asm>) {
+    out.push(Asm::Comment(String::from(text)));
 }
 
 //Assembly header lines, allocate stack array and initialize registers
-fn write_header(duck_count: usize, file: &mut File) -> std::io::Result<()> {
-    write!(file, ".section .text\n.global main\nmain:\n")?;
+fn write_header(duck_count: usize, entry: Entry, out: &mut Vec<Asm>) {
+    //Mark the stack non-executable so the linker doesn't warn
+    out.push(Asm::Directive(String::from(
+        ".section .note.GNU-stack,\"\",@progbits",
+    )));
+    out.push(Asm::Directive(String::from(".section .text")));
+    out.push(Asm::Directive(format!(".global {}", ENTRY_LABEL)));
+    out.push(Asm::Label(String::from(ENTRY_LABEL)));
+
+    if entry == Entry::Function {
+        comment("Save caller registers", out);
+        for saved in CALLEE_SAVED {
+            out.push(Asm::Push(reg(saved)));
+        }
+        out.push(Asm::Mov(reg(Reg::Rsp), reg(SAVED_STACK_REG)));
+    }
 
     //Add registers for goose and teacher and goose pointer
-    writeln!(file, "#Allocate ducks on stack")?;
+    comment("Allocate ducks on stack", out);
     for _ in 0..duck_count + 3 {
-        //write!(file, " xor %{}, %{}\n", REGISTER_MAP[i], REGISTER_MAP[i])?;
-        writeln!(file, "  push $0")?;
+        out.push(Asm::Push(imm(0)));
     }
 
-    writeln!(file, "  mov %rsp, %{}", ARRAY_BASE_REG)?;
-    writeln!(file, "  mov ${}, %{}", duck_count, DUCK_COUNT_REG)?;
-    writeln!(file, "  mov $0, %{}", GOOSE_INDEX_REG)?;
+    out.push(Asm::Mov(reg(Reg::Rsp), reg(ARRAY_BASE_REG)));
+    out.push(Asm::Mov(imm(duck_count as i64), reg(DUCK_COUNT_REG)));
+    out.push(Asm::Mov(imm(0), reg(GOOSE_INDEX_REG)));
+}
 
-    Ok(())
+//Perform exit syscall, or return to the caller
+fn write_exit(entry: Entry, out: &mut Vec<Asm>) {
+    comment("Exit==========", out);
+    if entry == Entry::Function {
+        out.push(Asm::Jmp(String::from(RETURN_LABEL)));
+        return;
+    }
+
+    out.push(Asm::Mov(imm(60), reg(Reg::Rax)));
+    out.push(Asm::Mov(imm(0), reg(Reg::Rdi)));
+    out.push(Asm::Syscall);
 }
 
-//Perform exit syscall
-fn write_exit(file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#Exit==========")?;
-    write!(file, "  mov $60, %rax\n  mov $0, %rdi\n  syscall\n")?;
-    Ok(())
+//Comment header shared by the arithmetic instructions
+fn write_binary_comment(name: &str, n: Duck, y: Duck, out: &mut Vec<Asm>) {
+    comment(&format!("{}==========", name), out);
+    comment(&format!("n: {}", n), out);
+    comment(&format!("y: {}", y), out);
 }
 
-//Lower given duck instruction to x86
-fn write_add(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#Add==========")?;
-    writeln!(file, "#n: {}", inst.n)?;
-    writeln!(file, "#y: {}", inst.y)?;
-    writeln!(file, "#goose: {}", inst.goose)?;
+//Registers MIR values live in. rax, rcx and rdx are left for index arithmetic, division
+//and scratch, rsi, rdi and r11 for syscalls, r12-r15 for the array, duck count, goose
+//and saved stack pointer.
+static VALUE_REGS: [Reg; 5] = [Reg::Rbp, Reg::Rbx, Reg::R10, Reg::R9, Reg::R8];
+
+//Where the values of one instruction's MIR live. Constants are never given a register,
+//they turn into immediates and displacements where they are used.
+struct Values {
+    consts: HashMap<Value, u64>,
+    regs: HashMap<Value, Reg>,
+    //Free registers, the next one to hand out at the end
+    free: Vec<Reg>,
+}
 
-    //Get n and y index
-    get_duck_index("r10", inst.n, file)?;
-    get_duck_index("r11", inst.y, file)?;
+impl Values {
+    fn new() -> Values {
+        Values {
+            consts: HashMap::new(),
+            regs: HashMap::new(),
+            free: VALUE_REGS.to_vec(),
+        }
+    }
 
-    //Load n and y
-    writeln!(file, "  movq (%{}, %r10, 8),%r8", ARRAY_BASE_REG)?;
-    writeln!(file, "  movq (%{}, %r11, 8),%r9", ARRAY_BASE_REG)?;
+    fn define(&mut self, value: Value) -> std::io::Result<Reg> {
+        let register = self
+            .free
+            .pop()
+            .ok_or_else(|| std::io::Error::other(format!("No register left for {}", value)))?;
+        self.regs.insert(value, register);
+        Ok(register)
+    }
+
+    fn release(&mut self, value: Value) {
+        if let Some(register) = self.regs.remove(&value) {
+            self.free.push(register);
+        }
+    }
+
+    //Register holding value, constants are moved into scratch
+    fn in_register(&self, value: Value, scratch: Reg, out: &mut Vec<Asm>) -> Reg {
+        match self.consts.get(&value) {
+            Some(&constant) => {
+                out.push(Asm::Mov(imm(constant as i64), reg(scratch)));
+                scratch
+            }
+            None => self.regs[&value],
+        }
+    }
 
-    //Add n and y
-    writeln!(file, "  add %r8, %r9")?;
+    //Constants that fit a sign extended 32 bit immediate as one, a register otherwise
+    fn operand(&self, value: Value, scratch: Reg, out: &mut Vec<Asm>) -> Operand {
+        match self.consts.get(&value) {
+            Some(&constant) if i32::try_from(constant as i64).is_ok() => imm(constant as i64),
+            _ => reg(self.in_register(value, scratch, out)),
+        }
+    }
+
+    //Array slot at the index held in value, a fixed displacement for constants
+    fn slot(&self, index: Value, scratch: Reg, out: &mut Vec<Asm>) -> Operand {
+        let fixed = self.consts.get(&index).and_then(|&constant| {
+            let disp = constant.checked_mul(8)?;
+            i32::try_from(disp)
+                .ok()
+                .map(|_| array_slot(constant as usize))
+        });
+        match fixed {
+            Some(fixed) => fixed,
+            None => slot(self.in_register(index, scratch, out)),
+        }
+    }
+}
 
-    //Move N -> Goose
-    get_goose_index("rax", file)?;
-    writeln!(file, "  movq %r9, (%{}, %rax, 8)", ARRAY_BASE_REG)?;
+//Lower the MIR of one instruction. Values don't outlive the instruction, so registers are
+//handed out as values are computed and come free after their last use.
+fn write_mir(ops: &[Op], entry: Entry, out: &mut Vec<Asm>) -> std::io::Result<()> {
+    let mut last_use = HashMap::new();
+    for (pos, op) in ops.iter().enumerate() {
+        for value in op.uses() {
+            last_use.insert(value, pos);
+        }
+    }
 
-    //Update goose index
-    writeln!(file, "  mov %r10, %{}", GOOSE_INDEX_REG)?;
+    let mut values = Values::new();
+    for (pos, op) in ops.iter().enumerate() {
+        if !matches!(op, Op::Label(_) | Op::Exit) {
+            comment(op.to_string().trim_start(), out);
+        }
+
+        match op {
+            Op::Const { dst, value } => {
+                values.consts.insert(*dst, *value);
+            }
+            //(goose + n) % (duck count + 1)
+            Op::DuckIndex { dst, n } => {
+                let dst = values.define(*dst)?;
+                out.push(Asm::Mov(reg(GOOSE_INDEX_REG), reg(Reg::Rax)));
+                out.push(Asm::Add(imm(*n as i64), Reg::Rax));
+                out.push(Asm::Mov(imm(0), reg(Reg::Rdx)));
+                out.push(Asm::Mov(reg(DUCK_COUNT_REG), reg(Reg::Rcx)));
+                out.push(Asm::Add(imm(1), Reg::Rcx));
+                out.push(Asm::Div(Reg::Rcx));
+                out.push(Asm::Mov(reg(Reg::Rdx), reg(dst)));
+            }
+            Op::GooseIndex { dst } => {
+                let dst = values.define(*dst)?;
+                out.push(Asm::Mov(reg(GOOSE_INDEX_REG), reg(dst)));
+            }
+            Op::Load { dst, index } => {
+                let dst = values.define(*dst)?;
+                let src = values.slot(*index, Reg::Rax, out);
+                out.push(Asm::Mov(src, reg(dst)));
+            }
+            Op::Store { index, value } => {
+                let dst = values.slot(*index, Reg::Rax, out);
+                let src = values.operand(*value, Reg::Rcx, out);
+                out.push(Asm::Mov(src, dst));
+            }
+            //dst is a register of its own, so writing it leaves lhs and rhs intact
+            Op::Binary { op, dst, lhs, rhs } => {
+                let dst = values.define(*dst)?;
+                match op {
+                    BinOp::Div => {
+                        let lhs = values.operand(*lhs, Reg::Rax, out);
+                        out.push(Asm::Mov(lhs, reg(Reg::Rax)));
+                        let rhs = values.in_register(*rhs, Reg::Rcx, out);
+                        out.push(Asm::Mov(imm(0), reg(Reg::Rdx)));
+                        out.push(Asm::Div(rhs));
+                        out.push(Asm::Mov(reg(Reg::Rax), reg(dst)));
+                    }
+                    BinOp::Mul => {
+                        let lhs = values.operand(*lhs, Reg::Rax, out);
+                        out.push(Asm::Mov(lhs, reg(dst)));
+                        let rhs = values.in_register(*rhs, Reg::Rcx, out);
+                        out.push(Asm::Imul(rhs, dst));
+                    }
+                    BinOp::Add | BinOp::Sub => {
+                        let lhs = values.operand(*lhs, Reg::Rax, out);
+                        out.push(Asm::Mov(lhs, reg(dst)));
+                        let rhs = values.operand(*rhs, Reg::Rcx, out);
+                        out.push(if *op == BinOp::Add {
+                            Asm::Add(rhs, dst)
+                        } else {
+                            Asm::Sub(rhs, dst)
+                        });
+                    }
+                }
+            }
+            Op::SetGoose { index } => {
+                let index = values.operand(*index, Reg::Rax, out);
+                out.push(Asm::Mov(index, reg(GOOSE_INDEX_REG)));
+            }
+            Op::Read { dst } => {
+                let dst = values.define(*dst)?;
+                //read(stdin, rsp, 1) into a zeroed buffer, end of input reads as 0
+                out.push(Asm::Push(imm(0)));
+                out.push(Asm::Xor(Reg::Rax, Reg::Rax));
+                out.push(Asm::Xor(Reg::Rdi, Reg::Rdi));
+                out.push(Asm::Mov(reg(Reg::Rsp), reg(Reg::Rsi)));
+                out.push(Asm::Mov(imm(1), reg(Reg::Rdx)));
+                out.push(Asm::Syscall);
+                out.push(Asm::Pop(dst));
+            }
+            Op::Write { value } => {
+                let value = values.operand(*value, Reg::Rax, out);
+                out.push(Asm::Push(value));
+
+                //write(stdout, rsp, 1)
+                out.push(Asm::Mov(imm(1), reg(Reg::Rax)));
+                out.push(Asm::Mov(imm(1), reg(Reg::Rdi)));
+                out.push(Asm::Mov(reg(Reg::Rsp), reg(Reg::Rsi)));
+                out.push(Asm::Mov(imm(1), reg(Reg::Rdx)));
+                out.push(Asm::Syscall);
+                out.push(Asm::Add(imm(8), Reg::Rsp));
+            }
+            Op::Exit => write_exit(entry, out),
+            Op::Label(name) => out.push(Asm::Label(name.clone())),
+            Op::Jump(label) => out.push(Asm::Jmp(label.clone())),
+            Op::JumpIfZero { value, label } => {
+                let value = values.in_register(*value, Reg::Rax, out);
+                out.push(Asm::Cmp(imm(0), value));
+                out.push(Asm::Jz(label.clone()));
+            }
+        }
+
+        for value in op.uses() {
+            if last_use[&value] == pos {
+                values.release(value);
+            }
+        }
+    }
 
     Ok(())
 }
 
-fn write_subtract(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#Subtract==========")?;
-    writeln!(file, "#n: {}", inst.n)?;
-    writeln!(file, "#y: {}", inst.y)?;
-    writeln!(file, "#goose: {}", inst.goose)?;
+//Register mode: the goose is known, so every slot is either a register or a fixed place
+//in the stack array
+struct Resident<'a> {
+    //Ring slots, then the teacher
+    slots: &'a [Operand],
+    goose: usize,
+}
 
-    //Get n and y index
-    get_duck_index("r10", inst.n, file)?;
-    get_duck_index("r11", inst.y, file)?;
+impl Resident<'_> {
+    fn duck(&self, duck: Duck) -> Operand {
+        let ring_size = self.slots.len() - 1;
+        self.slots[(self.goose + duck.n % ring_size) % ring_size]
+    }
 
-    //Load n and y
-    writeln!(file, "  movq (%{}, %r10, 8),%r8", ARRAY_BASE_REG)?;
-    writeln!(file, "  movq (%{}, %r11, 8),%r9", ARRAY_BASE_REG)?;
+    fn goose(&self) -> Operand {
+        self.slots[self.goose]
+    }
 
-    //Subtract n and y (n - y)
-    writeln!(file, "  sub %r9, %r8")?;
+    fn teacher(&self) -> Operand {
+        self.slots[self.slots.len() - 1]
+    }
+}
 
-    //Move N -> Goose
-    get_goose_index("rax", file)?;
-    writeln!(file, "  movq %r8, (%{}, %rax, 8)", ARRAY_BASE_REG)?;
+//Array slot at a fixed index
+fn array_slot(index: usize) -> Operand {
+    Operand::Mem {
+        base: ARRAY_BASE_REG,
+        index: None,
+        disp: (index * 8) as i32,
+    }
+}
 
-    //Update goose index
-    writeln!(file, "  mov %r10, %{}", GOOSE_INDEX_REG)?;
+//Register holding the operand, loading it into scratch if it lives in memory
+fn in_register(operand: Operand, scratch: Reg, out: &mut Vec<Asm>) -> Reg {
+    match operand {
+        Operand::Reg(register) => register,
+        operand => {
+            out.push(Asm::Mov(operand, reg(scratch)));
+            scratch
+        }
+    }
+}
 
-    Ok(())
+//mov can't go from memory to memory, use r8 in between
+fn copy_slot(src: Operand, dst: Operand, out: &mut Vec<Asm>) {
+    let src = reg(in_register(src, Reg::R8, out));
+    out.push(Asm::Mov(src, dst));
 }
 
-fn write_multiply(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#Multiply==========")?;
-    writeln!(file, "#n: {}", inst.n)?;
-    writeln!(file, "#y: {}", inst.y)?;
-    writeln!(file, "#goose: {}", inst.goose)?;
+//Zero the registers of ranges live from the start, the array itself was pushed as zeros
+fn write_resident_header(registers: &Registers, out: &mut Vec<Asm>) {
+    comment("Ducks in registers", out);
+    for (range, register) in &registers.ranges {
+        if range.start == 0 {
+            out.push(Asm::Xor(*register, *register));
+        }
+    }
+}
 
-    //Get n and y index
-    get_duck_index("r10", inst.n, file)?;
-    get_duck_index("r11", inst.y, file)?;
+//Leave register mode for good and set up what the stack lookup code expects. Every
+//range ended before the goose was lost, storing its register back as it did.
+fn write_spill(duck_count: usize, goose: usize, out: &mut Vec<Asm>) {
+    comment("Spill ducks to the stack==========", out);
+    out.push(Asm::Mov(imm(duck_count as i64), reg(DUCK_COUNT_REG)));
+    out.push(Asm::Mov(imm(goose as i64), reg(GOOSE_INDEX_REG)));
+}
 
-    //Load n and y
-    writeln!(file, "  movq (%{}, %r10, 8),%r8", ARRAY_BASE_REG)?;
-    writeln!(file, "  movq (%{}, %r11, 8),%r9", ARRAY_BASE_REG)?;
+//Arithmetic on n and y, result in the goose. The operand register is always r8.
+fn write_resident_binary(
+    name: &str,
+    op: fn(Operand, Reg) -> Asm,
+    n: Duck,
+    y: Duck,
+    at: &Resident,
+    out: &mut Vec<Asm>,
+) {
+    write_binary_comment(name, n, y, out);
+    out.push(Asm::Mov(at.duck(n), reg(Reg::R8)));
+    out.push(op(at.duck(y), Reg::R8));
+    out.push(Asm::Mov(reg(Reg::R8), at.goose()));
+}
 
-    //Multiply n and y
-    writeln!(file, "  imul %r8, %r9")?;
+fn write_resident_multiply(n: Duck, y: Duck, at: &Resident, out: &mut Vec<Asm>) {
+    write_binary_comment("Multiply", n, y, out);
+    out.push(Asm::Mov(at.duck(n), reg(Reg::R8)));
+    let y = in_register(at.duck(y), Reg::R9, out);
+    out.push(Asm::Imul(y, Reg::R8));
+    out.push(Asm::Mov(reg(Reg::R8), at.goose()));
+}
 
-    //Move N -> Goose
-    get_goose_index("rax", file)?;
-    writeln!(file, "  movq %r9, (%{}, %rax, 8)", ARRAY_BASE_REG)?;
+fn write_resident_divide(n: Duck, y: Duck, at: &Resident, out: &mut Vec<Asm>) {
+    write_binary_comment("Divide", n, y, out);
+    out.push(Asm::Mov(at.duck(n), reg(Reg::Rax)));
+    let y = in_register(at.duck(y), Reg::R9, out);
+    out.push(Asm::Mov(imm(0), reg(Reg::Rdx)));
+    out.push(Asm::Div(y));
+    out.push(Asm::Mov(reg(Reg::Rax), at.goose()));
+}
 
-    //Update goose index
-    writeln!(file, "  mov %r10, %{}", GOOSE_INDEX_REG)?;
+fn write_resident_input(at: &Resident, out: &mut Vec<Asm>) {
+    comment("Input==========", out);
+    //Zeroed buffer on the stack, end of input reads as 0
+    out.push(Asm::Push(imm(0)));
+    out.push(Asm::Xor(Reg::Rax, Reg::Rax));
+    out.push(Asm::Xor(Reg::Rdi, Reg::Rdi));
+    out.push(Asm::Mov(reg(Reg::Rsp), reg(Reg::Rsi)));
+    out.push(Asm::Mov(imm(1), reg(Reg::Rdx)));
+    out.push(Asm::Syscall);
+
+    out.push(Asm::Pop(Reg::R8));
+    out.push(Asm::Mov(reg(Reg::R8), at.goose()));
+}
 
-    Ok(())
+fn write_resident_push(n: Duck, at: &Resident, out: &mut Vec<Asm>) {
+    comment("Push==========", out);
+    copy_slot(at.duck(n), at.teacher(), out);
+    copy_slot(at.duck(n), at.goose(), out);
 }
-fn write_divide(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#Divide==========")?;
-    writeln!(file, "#n: {}", inst.n)?;
-    writeln!(file, "#y: {}", inst.y)?;
-    writeln!(file, "#goose: {}", inst.goose)?;
 
-    //Get n and y index
-    get_duck_index("r10", inst.n, file)?;
-    get_duck_index("r11", inst.y, file)?;
+fn write_resident_pop(at: &Resident, out: &mut Vec<Asm>) {
+    comment("Pop==========", out);
+    copy_slot(at.teacher(), at.goose(), out);
+    out.push(Asm::Mov(imm(0), at.teacher()));
+}
 
-    //Load n and y
-    writeln!(file, "  movq (%{}, %r10, 8),%r8", ARRAY_BASE_REG)?;
-    writeln!(file, "  movq (%{}, %r11, 8),%r9", ARRAY_BASE_REG)?;
+fn write_resident_loop_begin(
+    cond: Duck,
+    id: usize,
+    labels: &LoopLabels,
+    at: &Resident,
+    out: &mut Vec<Asm>,
+) {
+    comment("LoopBegin==========", out);
+    comment(&format!("id: {}", id), out);
+    out.push(Asm::Label(labels.start.clone()));
+
+    let cond = in_register(at.duck(cond), Reg::R8, out);
+    out.push(Asm::Cmp(imm(0), cond));
+    out.push(Asm::Jz(labels.end.clone()));
+}
 
-    //Divide n/y
-    writeln!(file, "  mov $0, %rdx")?;
-    writeln!(file, "  mov %r8, %rax")?;
+fn write_resident_set(n: Duck, value: usize, at: &Resident, out: &mut Vec<Asm>) {
+    comment("Set==========", out);
+    comment(&format!("n: {}", n), out);
+    comment(&format!("value: {}", value), out);
+
+    //Stores only take a sign extended 32 bit immediate, registers take any
+    let value = value as i64;
+    match at.goose() {
+        Operand::Mem { .. } if i32::try_from(value).is_err() => {
+            out.push(Asm::Mov(imm(value), reg(Reg::R8)));
+            out.push(Asm::Mov(reg(Reg::R8), at.goose()));
+        }
+        goose => out.push(Asm::Mov(imm(value), goose)),
+    }
+}
 
-    writeln!(file, "  divq %r9")?;
+fn write_resident_print(n: Duck, at: &Resident, out: &mut Vec<Asm>) {
+    comment("Print==========", out);
+    let value = in_register(at.duck(n), Reg::R8, out);
+    out.push(Asm::Push(reg(value)));
+
+    //write(stdout, rsp, 1)
+    out.push(Asm::Mov(imm(1), reg(Reg::Rax)));
+    out.push(Asm::Mov(imm(1), reg(Reg::Rdi)));
+    out.push(Asm::Mov(reg(Reg::Rsp), reg(Reg::Rsi)));
+    out.push(Asm::Mov(imm(1), reg(Reg::Rdx)));
+    out.push(Asm::Syscall);
+    out.push(Asm::Add(imm(8), Reg::Rsp));
+}
 
-    //Move N -> Goose
-    get_goose_index("rbx", file)?;
-    writeln!(file, "  movq %rax, (%{}, %rbx, 8)", ARRAY_BASE_REG)?;
+//Where each slot lives in register mode, updated as live ranges start and end
+struct Registers {
+    //Ring slots, then the teacher
+    slots: Vec<Operand>,
+    //Ranges that got a register, ordered by start
+    ranges: Vec<(LiveRange, Reg)>,
+    //Code after the known prefix reads slots from the array, so a range stores its
+    //register back when it ends
+    store_back: bool,
+}
 
-    //Update goose index
-    writeln!(file, "  mov %r10, %{}", GOOSE_INDEX_REG)?;
+impl Registers {
+    //Move on to the instruction at pos. Ranges that ended with the one before give up
+    //their register, ranges starting here take theirs.
+    fn advance(&mut self, pos: usize, out: &mut Vec<Asm>) {
+        for (range, register) in &self.ranges {
+            if range.end + 1 == pos {
+                if self.store_back && range.written {
+                    out.push(Asm::Mov(reg(*register), array_slot(range.slot)));
+                }
+                self.slots[range.slot] = array_slot(range.slot);
+            }
+        }
+        for (range, register) in &self.ranges {
+            if range.start == pos {
+                self.slots[range.slot] = reg(*register);
+            }
+        }
+    }
+}
 
-    Ok(())
+//Hand out registers to the live ranges of the known prefix in order of their start, a
+//register coming free when its range ends. When none is free, whichever range is used
+//least (weighted by loop depth) stays in the array. None if no range gets a register or
+//the array is too big to address with a displacement.
+fn allocate_slots(program: &Program, entry: Entry) -> std::io::Result<Option<Registers>> {
+    let slot_count = program.ring_size() + 1;
+    if i32::try_from(slot_count * 8).is_err() {
+        return Ok(None);
+    }
+
+    let loops = check::check(program).map_err(check::into_io_error)?;
+    let rotation = rotation::analyze(program, &loops);
+    let uses = rotation.slot_uses(program);
+    let ranges = liveness::live_ranges(program, &loops, &rotation);
+
+    //Free registers, the next one to hand out at the end
+    let mut free: Vec<Reg> = DUCK_REGS
+        .iter()
+        .rev()
+        .copied()
+        .filter(|register| entry == Entry::Process || *register != SAVED_STACK_REG)
+        .collect();
+    let mut assigned: Vec<Option<Reg>> = vec![None; ranges.len()];
+    //Ranges holding a register
+    let mut active: Vec<usize> = Vec::new();
+    for (index, range) in ranges.iter().enumerate() {
+        active.retain(|&other| {
+            let ended = ranges[other].end < range.start;
+            if ended {
+                free.extend(assigned[other]);
+            }
+            !ended
+        });
+
+        if let Some(register) = free.pop() {
+            assigned[index] = Some(register);
+            active.push(index);
+            continue;
+        }
+
+        //Out of registers, take the one of the least used range if this one is used more
+        if let Some(victim) = active
+            .iter_mut()
+            .min_by_key(|other| uses[ranges[**other].slot])
+        {
+            if uses[ranges[*victim].slot] < uses[range.slot] {
+                assigned[index] = assigned[*victim].take();
+                *victim = index;
+            }
+        }
+    }
+
+    let ranges: Vec<(LiveRange, Reg)> = ranges
+        .into_iter()
+        .zip(assigned)
+        .filter_map(|(range, register)| Some((range, register?)))
+        .collect();
+    if ranges.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(Registers {
+        slots: (0..slot_count).map(array_slot).collect(),
+        ranges,
+        store_back: liveness::known_prefix(program, &rotation) < program.len(),
+    }))
 }
 
-//Reads a char from stdin
-fn write_input(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#Input==========")?;
-    //Allocate space on the stack
-    writeln!(file, "  sub $8, %rsp")?;
+//Drop the duck array and restore the caller's registers
+fn write_return(out: &mut Vec<Asm>) {
+    out.push(Asm::Label(String::from(RETURN_LABEL)));
+    out.push(Asm::Mov(reg(SAVED_STACK_REG), reg(Reg::Rsp)));
+    for saved in CALLEE_SAVED.iter().rev() {
+        out.push(Asm::Pop(*saved));
+    }
+    out.push(Asm::Ret);
+}
 
-    //rax <- 0 (syscall number for 'read')
-    writeln!(file, "  xor %eax, %eax")?;
-    //edi <- 0 (stdin file descriptor)
-    writeln!(file, "  xor %edi, %edi")?;
-    //rsi <- address of the buffer.  lea rsi, [rel buffer]
-    writeln!(file, "  mov %rsp, %rsi")?;
-    //rdx <- size of the buffer
-    writeln!(file, "  mov $1, %edx")?;
+//Where lowered instructions go: kept for the encoder, or written out as text
+pub trait AsmOutput {
+    fn emit(&mut self, ops: Vec<Asm>) -> std::io::Result<()>;
+}
 
-    writeln!(file, "  syscall")?;
+impl AsmOutput for Vec<Asm> {
+    fn emit(&mut self, ops: Vec<Asm>) -> std::io::Result<()> {
+        self.extend(ops);
+        Ok(())
+    }
+}
 
-    get_duck_index("r10", inst.n, file)?;
-    get_goose_index("rax", file)?;
+//AT&T syntax assembly, one instruction per line
+pub struct Listing<W: Write>(pub W);
 
-    writeln!(file, "  pop %r11")?;
+impl<W: Write> AsmOutput for Listing<W> {
+    fn emit(&mut self, ops: Vec<Asm>) -> std::io::Result<()> {
+        for op in &ops {
+            writeln!(self.0, "{}", op)?;
+        }
 
-    writeln!(file, "  movq %r11, (%{}, %rax, 8)", ARRAY_BASE_REG)?;
+        Ok(())
+    }
+}
 
-    writeln!(file, "  mov %r10, %{}", GOOSE_INDEX_REG)?;
+//Code generation choices
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Options {
+    //Keep ducks and the teacher in registers for as long as the rotation analysis knows
+    //where the goose is, slots that are never live at once sharing one
+    pub duck_registers: bool,
+}
 
-    Ok(())
+pub struct X86_64<O: AsmOutput> {
+    out: O,
+    entry: Entry,
+    options: Options,
+    ring_size: usize,
+    duck_count: usize,
+    //Goose before the instruction being lowered, from the rotation analysis. In register
+    //mode it is kept up to date after each instruction as well.
+    goose: Option<usize>,
+    //Where each slot lives while in register mode
+    resident: Option<Registers>,
+    //Position of the instruction being lowered, known_goose is called once before each
+    pos: usize,
+    //Expands instructions into the MIR that stack mode is generated from
+    mir: mir::Builder,
 }
 
-//Push value to teacher
-fn write_push(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    get_teacher_index("r11", file)?;
-    get_duck_index("r10", inst.n, file)?;
-    writeln!(file, "  movq (%{}, %r10, 8),%r8", ARRAY_BASE_REG)?;
+impl<O: AsmOutput> X86_64<O> {
+    //Standalone program starting at ENTRY_LABEL, End exits the process
+    pub fn new(out: O) -> X86_64<O> {
+        X86_64 {
+            out,
+            entry: Entry::Process,
+            options: Options::default(),
+            ring_size: 0,
+            duck_count: 0,
+            goose: None,
+            resident: None,
+            pos: 0,
+            mir: mir::Builder::new(),
+        }
+    }
 
-    //Move N -> Goose
-    get_goose_index("rax", file)?;
-    writeln!(file, "  movq %r8, (%{}, %rax, 8)", ARRAY_BASE_REG)?;
+    //Function callable as extern "C" fn() at ENTRY_LABEL, End returns to the caller
+    pub fn function(out: O) -> X86_64<O> {
+        X86_64 {
+            out,
+            entry: Entry::Function,
+            options: Options::default(),
+            ring_size: 0,
+            duck_count: 0,
+            goose: None,
+            resident: None,
+            pos: 0,
+            mir: mir::Builder::new(),
+        }
+    }
 
-    //Move N -> Teacher
-    writeln!(file, "  movq %r8, (%{}, %r11, 8)", ARRAY_BASE_REG)?;
+    pub fn with_options(mut self, options: Options) -> X86_64<O> {
+        self.options = options;
+        self
+    }
 
-    //Update goose index
-    writeln!(file, "  mov %r10, %{}", GOOSE_INDEX_REG)?;
+    pub fn into_inner(self) -> O {
+        self.out
+    }
 
-    Ok(())
+    fn duck(&self, n: usize) -> Duck {
+        Duck {
+            n,
+            index: self
+                .goose
+                .map(|goose| (goose + n % self.ring_size) % self.ring_size),
+        }
+    }
+
+    //The goose moved to duck n, register mode needs to know where it is until the next
+    //instruction in case that one spills
+    fn moved(&mut self, n: Duck) {
+        self.goose = n.index;
+    }
+
+    //Run one of the write_resident_ functions if in register mode, false if not
+    fn lower_resident(
+        &mut self,
+        write: impl FnOnce(&Resident, &mut Vec<Asm>),
+    ) -> std::io::Result<bool> {
+        let registers = match &self.resident {
+            None => return Ok(false),
+            Some(registers) => registers,
+        };
+
+        let mut ops = Vec::new();
+        let at = Resident {
+            slots: &registers.slots,
+            goose: self.goose.unwrap_or_default(),
+        };
+        write(&at, &mut ops);
+        self.out.emit(ops)?;
+
+        Ok(true)
+    }
+
+    //Run one of the write_ functions and pass what it produced on
+    fn lower(&mut self, write: impl FnOnce(&mut Vec<Asm>)) -> std::io::Result<()> {
+        let mut ops = Vec::new();
+        write(&mut ops);
+        self.out.emit(ops)
+    }
+
+    //Expand the instruction into MIR with build, then lower the ops it produced
+    fn lower_mir(
+        &mut self,
+        name: &str,
+        build: impl FnOnce(&mut mir::Builder) -> std::io::Result<()>,
+    ) -> std::io::Result<()> {
+        build(&mut self.mir)?;
+        let mir = self.mir.take_ops();
+
+        let mut ops = Vec::new();
+        comment(&format!("{}==========", name), &mut ops);
+        write_mir(&mir, self.entry, &mut ops)?;
+        self.out.emit(ops)
+    }
 }
 
-//Pop value from teacher to goose
-fn write_pop(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    //Load teacher
-    get_teacher_index("r11", file)?;
-    writeln!(file, "  movq (%{}, %r11, 8),%r8", ARRAY_BASE_REG)?;
+impl<O: AsmOutput> Backend for X86_64<O> {
+    fn prologue(&mut self, program: &Program) -> std::io::Result<()> {
+        self.ring_size = program.ring_size();
+        self.duck_count = program.duck_count();
+        self.goose = Some(0);
+        self.pos = 0;
+        self.mir.prologue(program)?;
+        let (duck_count, entry) = (self.duck_count, self.entry);
+        self.lower(|out| write_header(duck_count, entry, out))?;
+
+        if self.options.duck_registers {
+            self.resident = allocate_slots(program, entry)?;
+        }
+        if let Some(registers) = &self.resident {
+            let mut ops = Vec::new();
+            write_resident_header(registers, &mut ops);
+            self.out.emit(ops)?;
+        }
+
+        Ok(())
+    }
 
-    //Move teacher -> Goose
-    get_goose_index("rax", file)?;
-    writeln!(file, "  movq %r8, (%{}, %rax, 8)", ARRAY_BASE_REG)?;
+    fn epilogue(&mut self) -> std::io::Result<()> {
+        self.lower_mir("End", Backend::epilogue)?;
+        if self.entry == Entry::Function {
+            self.lower(write_return)?;
+        }
 
-    //Set teacher to 0
-    writeln!(file, "  movq $0, (%{}, %r11, 8)", ARRAY_BASE_REG)?;
+        Ok(())
+    }
 
-    //Update goose index
-    get_duck_index("r10", inst.n, file)?;
-    writeln!(file, "  mov %r10, %{}", GOOSE_INDEX_REG)?;
+    //Once the goose is lost it stays lost, so register mode ends there
+    fn known_goose(&mut self, goose: Option<usize>) -> std::io::Result<()> {
+        if let Some(registers) = &mut self.resident {
+            let mut ops = Vec::new();
+            registers.advance(self.pos, &mut ops);
+            self.out.emit(ops)?;
+        }
+        if goose.is_none() && self.resident.take().is_some() {
+            let (duck_count, current) = (self.duck_count, self.goose.unwrap_or_default());
+            self.lower(|out| write_spill(duck_count, current, out))?;
+        }
+
+        self.goose = goose;
+        self.pos += 1;
+        self.mir.known_goose(goose)
+    }
 
-    Ok(())
+    fn end(&mut self) -> std::io::Result<()> {
+        self.lower_mir("End", Backend::end)
+    }
+
+    fn print(&mut self, n: usize) -> std::io::Result<()> {
+        let n = self.duck(n);
+        if self.lower_resident(|at, out| write_resident_print(n, at, out))? {
+            return Ok(());
+        }
+        self.lower_mir("Print", |mir| mir.print(n.n))
+    }
+
+    fn add(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        let (n, y) = (self.duck(n), self.duck(y));
+        if self.lower_resident(|at, out| write_resident_binary("Add", Asm::Add, n, y, at, out))? {
+            self.moved(n);
+            return Ok(());
+        }
+        self.lower_mir("Add", |mir| mir.add(n.n, y.n))
+    }
+
+    fn subtract(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        let (n, y) = (self.duck(n), self.duck(y));
+        if self
+            .lower_resident(|at, out| write_resident_binary("Subtract", Asm::Sub, n, y, at, out))?
+        {
+            self.moved(n);
+            return Ok(());
+        }
+        self.lower_mir("Subtract", |mir| mir.subtract(n.n, y.n))
+    }
+
+    fn multiply(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        let (n, y) = (self.duck(n), self.duck(y));
+        if self.lower_resident(|at, out| write_resident_multiply(n, y, at, out))? {
+            self.moved(n);
+            return Ok(());
+        }
+        self.lower_mir("Multiply", |mir| mir.multiply(n.n, y.n))
+    }
+
+    fn divide(&mut self, n: usize, y: usize) -> std::io::Result<()> {
+        let (n, y) = (self.duck(n), self.duck(y));
+        if self.lower_resident(|at, out| write_resident_divide(n, y, at, out))? {
+            self.moved(n);
+            return Ok(());
+        }
+        self.lower_mir("Divide", |mir| mir.divide(n.n, y.n))
+    }
+
+    fn input(&mut self, n: usize) -> std::io::Result<()> {
+        let n = self.duck(n);
+        if self.lower_resident(write_resident_input)? {
+            self.moved(n);
+            return Ok(());
+        }
+        self.lower_mir("Input", |mir| mir.input(n.n))
+    }
+
+    fn push(&mut self, n: usize) -> std::io::Result<()> {
+        let n = self.duck(n);
+        if self.lower_resident(|at, out| write_resident_push(n, at, out))? {
+            self.moved(n);
+            return Ok(());
+        }
+        self.lower_mir("Push", |mir| mir.push(n.n))
+    }
+
+    fn pop(&mut self, n: usize) -> std::io::Result<()> {
+        let n = self.duck(n);
+        if self.lower_resident(write_resident_pop)? {
+            self.moved(n);
+            return Ok(());
+        }
+        self.lower_mir("Pop", |mir| mir.pop(n.n))
+    }
+
+    fn loop_begin(&mut self, cond: usize, id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        let cond = self.duck(cond);
+        if self.lower_resident(|at, out| write_resident_loop_begin(cond, id, labels, at, out))? {
+            return Ok(());
+        }
+        self.lower_mir("LoopBegin", |mir| mir.loop_begin(cond.n, id, labels))
+    }
+
+    fn loop_end(&mut self, id: usize, labels: &LoopLabels) -> std::io::Result<()> {
+        self.lower_mir("LoopEnd", |mir| mir.loop_end(id, labels))
+    }
+
+    fn set(&mut self, n: usize, value: usize) -> std::io::Result<()> {
+        let n = self.duck(n);
+        if self.lower_resident(|at, out| write_resident_set(n, value, at, out))? {
+            self.moved(n);
+            return Ok(());
+        }
+        self.lower_mir("Set", |mir| mir.set(n.n, value))
+    }
+
+    //Nothing to do in register mode, where the goose is only tracked at compile time
+    fn move_goose(&mut self, n: usize) -> std::io::Result<()> {
+        let n = self.duck(n);
+        if self.lower_resident(|_, out| comment("Move==========", out))? {
+            self.moved(n);
+            return Ok(());
+        }
+        self.lower_mir("Move", |mir| mir.move_goose(n.n))
+    }
+
+    //Doesn't touch any slot, so the same in both modes
+    fn print_value(&mut self, value: usize) -> std::io::Result<()> {
+        self.lower_mir("PrintValue", |mir| mir.print_value(value))
+    }
 }
 
-fn write_loop_begin(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#LoopBegin==========")?;
-    writeln!(file, "  start_{}:", inst.y)?;
+//Lower program to x86-64 instructions, starting at ENTRY_LABEL
+//Fails with InvalidData if the program doesn't pass check::check
+pub fn lower_to_asm(program: &Program, options: Options) -> std::io::Result<Vec<Asm>> {
+    let mut backend = X86_64::new(Vec::new()).with_options(options);
+    backend::lower(program, &mut backend)?;
 
-    get_duck_index("r10", inst.n, file)?;
-    writeln!(file, "  movq (%{}, %r10, 8),%r8", ARRAY_BASE_REG)?;
-    writeln!(file, "  cmp $0, %r8")?;
-    writeln!(file, "  jz end_{}", inst.y)?;
-    Ok(())
+    Ok(backend.into_inner())
 }
 
-fn write_loop_end(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#LoopEnd==========")?;
-    writeln!(file, "  jmp start_{}", inst.y)?;
-    writeln!(file, "  end_{}:", inst.y)?;
-    Ok(())
+//Lower program to a function callable as extern "C" fn() at ENTRY_LABEL, where End
+//returns instead of exiting the process
+pub fn lower_to_function(program: &Program, options: Options) -> std::io::Result<Vec<Asm>> {
+    let mut backend = X86_64::function(Vec::new()).with_options(options);
+    backend::lower(program, &mut backend)?;
+
+    Ok(backend.into_inner())
 }
 
-fn write_set(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#Set==========")?;
-    writeln!(file, "#n: {}", inst.n)?;
-    writeln!(file, "#y: {}", inst.y)?;
-    writeln!(file, "#goose: {}", inst.goose)?;
+//Write program as AT&T syntax assembly to out
+pub fn lower_to_writer(program: &Program, out: &mut impl Write) -> std::io::Result<()> {
+    lower_to_writer_with(program, Options::default(), out)
+}
 
-    get_duck_index("r10", inst.n, file)?;
-    get_goose_index("rax", file)?;
+//Write program as AT&T syntax assembly to out, generated with the given options
+pub fn lower_to_writer_with(
+    program: &Program,
+    options: Options,
+    out: &mut impl Write,
+) -> std::io::Result<()> {
+    backend::lower(
+        program,
+        &mut X86_64::new(Listing(out)).with_options(options),
+    )
+}
 
-    writeln!(file, "  movq ${}, (%{}, %rax, 8)", inst.y, ARRAY_BASE_REG)?;
+//Program as AT&T syntax assembly in a string
+pub fn lower_to_string(program: &Program) -> std::io::Result<String> {
+    let mut out = Vec::new();
+    lower_to_writer(program, &mut out)?;
 
-    writeln!(file, "  mov %r10, %{}", GOOSE_INDEX_REG)?;
-    Ok(())
+    backend::into_text(out)
+}
+
+//Write program as AT&T syntax assembly
+pub fn lower_program(program: &Program, file_out_name: &str) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(file_out_name)?);
+    lower_to_writer(program, &mut file)?;
+
+    file.flush()
 }
 
-fn write_print(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    writeln!(file, "#Print==========")?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction::{self, *};
+    use crate::testing::program;
+
+    //Sets up "Hi!" while the goose is known, with a loop that never runs writing over it,
+    //then prints it from a loop that loses the goose
+    fn loses_the_goose() -> Program {
+        program(
+            3,
+            &[
+                Set {
+                    n: 1,
+                    value: b'H' as usize,
+                },
+                Set {
+                    n: 1,
+                    value: b'i' as usize,
+                },
+                Set {
+                    n: 1,
+                    value: b'!' as usize,
+                },
+                Set { n: 1, value: 0 },
+                LoopBegin { cond: 3, id: 0 },
+                Set {
+                    n: 2,
+                    value: b'X' as usize,
+                },
+                Set { n: 2, value: 0 },
+                LoopEnd { id: 0 },
+                LoopBegin { cond: 0, id: 1 },
+                Print { n: 0 },
+                Push { n: 1 },
+                LoopEnd { id: 1 },
+                Set {
+                    n: 1,
+                    value: b'\n' as usize,
+                },
+                Print { n: 3 },
+                Print { n: 1 },
+                End,
+            ],
+        )
+    }
 
-    get_duck_index("r10", inst.n, file)?;
-    writeln!(file, "  movq (%{}, %r10, 8),%r8", ARRAY_BASE_REG)?;
+    //Every slot of a ring of 9 live at once, more than there are registers, then the
+    //teacher, then a loop that loses the goose
+    fn register_pressure() -> Program {
+        let mut insts: Vec<Instruction> = (0..9)
+            .map(|i| Set {
+                n: 1,
+                value: b'a' as usize + i,
+            })
+            .collect();
+        insts.extend((0..9).map(|n| Print { n }));
+        insts.extend([
+            Push { n: 4 },
+            Pop { n: 5 },
+            LoopBegin { cond: 0, id: 0 },
+            Print { n: 0 },
+            Set { n: 1, value: 0 },
+            LoopEnd { id: 0 },
+            Set {
+                n: 1,
+                value: b'\n' as usize,
+            },
+            Print { n: 8 },
+            End,
+        ]);
+
+        program(8, &insts)
+    }
 
-    writeln!(file, "  push %r8")?;
+    //No two ranges holding the same register overlap
+    fn assert_no_conflicts(registers: &Registers) {
+        for (index, (range, register)) in registers.ranges.iter().enumerate() {
+            for (other, other_register) in &registers.ranges[index + 1..] {
+                if register == other_register {
+                    assert!(
+                        range.end < other.start || other.end < range.start,
+                        "{:?} and {:?} share {}",
+                        range,
+                        other,
+                        register.name()
+                    );
+                }
+            }
+        }
+    }
 
-    //sys_write call number
-    writeln!(file, "  mov $1, %rax")?;
-    //write to stdout (fd=1)
-    writeln!(file, "  mov $1, %rdi")?;
-    //use char on stack
-    writeln!(file, "  mov %rsp, %rsi")?;
-    //write 1 char
-    writeln!(file, "  mov $1, %rdx")?;
-    writeln!(file, "  syscall")?;
-    writeln!(file, "  add $8, %rsp")?;
+    #[test]
+    fn disjoint_ranges_share_a_register() {
+        let program = program(
+            2,
+            &[
+                Set { n: 1, value: 5 },
+                Print { n: 2 },
+                Set { n: 1, value: 7 },
+                Print { n: 2 },
+                End,
+            ],
+        );
+        let registers = allocate_slots(&program, Entry::Process).unwrap().unwrap();
+
+        assert_eq!(registers.ranges.len(), 2);
+        assert_eq!(registers.ranges[0].1, registers.ranges[1].1);
+        assert!(!registers.store_back);
+    }
 
-    Ok(())
-}
+    #[test]
+    fn least_used_ranges_stay_in_the_array() {
+        let registers = allocate_slots(&register_pressure(), Entry::Process)
+            .unwrap()
+            .unwrap();
 
-fn write_instruction(inst: &DuckInstruction, file: &mut File) -> std::io::Result<()> {
-    match inst.op_code {
-        x if x == InstructionEnum::End as usize => write_exit(file)?,
-        x if x == InstructionEnum::Print as usize => write_print(inst, file)?,
-        x if x == InstructionEnum::Add as usize => write_add(inst, file)?,
-        x if x == InstructionEnum::Subtract as usize => write_subtract(inst, file)?,
-        x if x == InstructionEnum::Multiply as usize => write_multiply(inst, file)?,
-        x if x == InstructionEnum::Divide as usize => write_divide(inst, file)?,
-        x if x == InstructionEnum::Input as usize => write_input(inst, file)?,
-        x if x == InstructionEnum::Push as usize => write_push(inst, file)?,
-        x if x == InstructionEnum::Pop as usize => write_pop(inst, file)?,
-        x if x == InstructionEnum::LoopBegin as usize => write_loop_begin(inst, file)?,
-        x if x == InstructionEnum::LoopEnd as usize => write_loop_end(inst, file)?,
-        x if x == InstructionEnum::Set as usize => write_set(inst, file)?,
-        x => panic!("Unhandled instruction code {}", x),
-    };
+        //Ten ranges, nine of them live at once
+        assert!(registers.ranges.len() < 10);
+        assert!(registers.store_back);
+        assert_no_conflicts(&registers);
+    }
 
-    Ok(())
-}
+    #[test]
+    fn functions_keep_the_saved_stack_pointer() {
+        let registers = allocate_slots(&register_pressure(), Entry::Function)
+            .unwrap()
+            .unwrap();
+
+        assert!(registers
+            .ranges
+            .iter()
+            .all(|(_, register)| *register != SAVED_STACK_REG));
+        assert_no_conflicts(&registers);
+    }
 
-pub fn lower_program(
-    parse_results: &(usize, Vec<DuckInstruction>),
-    file_out_name: &str,
-) -> std::io::Result<()> {
-    let mut file = File::create(file_out_name)?;
-    write_header(parse_results.0, &mut file)?;
+    //Build program as an executable, run it on input and return what it printed
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    fn run_executable(name: &str, program: &Program, options: Options, input: &[u8]) -> Vec<u8> {
+        let exe = crate::testing::temp_path(&format!("{}-{}", name, options.duck_registers));
+        crate::x86_64_enc::write_executable(program, options, &exe).unwrap();
+        let result = crate::testing::run_executable(&exe, input);
 
-    for inst in &parse_results.1 {
-        write_instruction(inst, &mut file)?;
+        assert!(result.status.success(), "{} {:?}", name, options);
+        result.stdout
     }
 
-    write_exit(&mut file)?;
-    Ok(())
+    //The interpreter is the oracle, with and without ducks in registers
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn duck_registers_print_what_the_stack_array_prints() {
+        let programs = [
+            ("spill", loses_the_goose(), "Hi!\n!"),
+            ("pressure", register_pressure(), "abcdefghiebcdefghi\n"),
+        ];
+        for (name, program, printed) in programs {
+            let expected = crate::testing::interpret(&program, b"");
+            assert_eq!(expected, printed.as_bytes(), "{}", name);
+
+            for duck_registers in [false, true] {
+                let stdout = run_executable(name, &program, Options { duck_registers }, b"");
+                assert_eq!(stdout, expected, "{} {}", name, duck_registers);
+            }
+        }
+    }
+
+    //Every instruction once the goose is lost, so all of it goes through DuckIndex
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn stack_lookups_match_the_interpreter() {
+        let program = crate::testing::every_instruction();
+        let mir = crate::mir::lower(&program).unwrap().to_string();
+        assert!(mir.contains("duck_index"), "{}", mir);
+
+        let (input, printed) = crate::testing::EVERY_INSTRUCTION_IO;
+        let expected = crate::testing::interpret(&program, input);
+        assert_eq!(expected, printed);
+
+        let stdout = run_executable("lookups", &program, Options::default(), input);
+        assert_eq!(stdout, expected);
+    }
 }